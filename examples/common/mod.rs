@@ -37,52 +37,6 @@ impl Camera {
     }
 }
 
-pub struct UserSphere {
-    pub center: Point3<f32>,
-    pub radius: f32,
-}
-
-impl UserPrimitive for UserSphere {
-    fn intersect(&self, ray: &Ray) -> UserPrimHit {
-        let v = ray.origin - self.center;
-
-        let a = ray.dir.magnitude2();
-        let b = 2.0 * dot(v, ray.dir);
-        let c = v.magnitude2() - self.radius * self.radius;
-        let d = b*b - 4.0 * a * c;
-        if d < 0.0 {
-            return UserPrimHit::miss()
-        }
-
-        let q = d.sqrt();
-        let rcp_a = 1.0 / a;
-
-        let t0 = 0.5 * rcp_a * (-b - q);
-        if ray.in_range(t0) {
-            return UserPrimHit {
-                t: t0,
-                Ng: ray.point_at_dist(t0) - self.center,
-                uv: Vector2::zero(),
-            }
-        }
-        let t1 = 0.5 * rcp_a * (-b + q);
-        if ray.in_range(t1) {
-            return UserPrimHit {
-                t: t1,
-                Ng: ray.point_at_dist(t1) - self.center,
-                uv: Vector2::zero(),
-            }
-        }
-        UserPrimHit::miss()
-    }
-
-    fn bounds(&self) -> Bounds {
-        Bounds::new(
-            self.center - Vector3::new(self.radius, self.radius, self.radius),
-            self.center + Vector3::new(self.radius, self.radius, self.radius))
-    }
-}
-
 #[derive(Debug, Copy, Clone, Constructor, Add, Mul, AddAssign)]
 pub struct Colour {
     pub r: f32,
@@ -9,8 +9,6 @@ extern crate embree;
 
 mod common;
 
-use std::f32;
-
 use common::*;
 use cgmath::*;
 use minifb::*;
@@ -88,7 +86,7 @@ pub fn build_scene(device: &Device) -> Scene {
     let cube = TriangleMesh::new(device, Vec::from(CUBE_INDICES.as_ref()), Vec::from(CUBE_VERTICES.as_ref()));
     scene.attach(cube);
 
-    let sphere = UserGeometry::new(device, vec![UserSphere { center: Point3::new(-3.0, 0.0, 0.0), radius: 1.0 }]);
+    let sphere = UserGeometry::new(device, vec![SpherePrimitive { center: Point3::new(-3.0, 0.0, 0.0), radius: 1.0 }]);
     scene.attach(sphere);
 
     scene.set_build_quality(BuildQuality::Medium);
@@ -111,23 +109,13 @@ pub fn render_scene(buffer: &mut Vec<u32>, scene: &Scene, camera: &Camera) {
             hit: Hit::empty(),
         };
         scene.intersect(&mut rayhit);
-        let ray = rayhit.ray;
-        let hit = rayhit.hit;
-        if hit.is_hit() {
-            let hit_pos = ray.point_at_dist(ray.tfar);
-
-            let mut shadow_rayhit = RayHit {
-                ray: Ray::new(hit_pos, sun_dir, 1e-4, f32::MAX),
-                hit: Hit::empty(),
-            };
-            scene.intersect(&mut shadow_rayhit);
-            let shadow_hit = shadow_rayhit.hit;
-
-            let shadowing = if shadow_hit.is_hit() { 0.0 } else { 1.0 };
-            let lighting: f32 = AMBIENT + shadowing * (1.0 - AMBIENT) * clamp(dot(hit.Ng, sun_dir), 0.0, 1.0);
+        if let Some(si) = SurfaceInteraction::from_hit(scene, &rayhit) {
+            let mut shadow_ray = si.spawn_ray(sun_dir);
+            let shadowing = if scene.occluded(&mut shadow_ray) { 0.0 } else { 1.0 };
+            let lighting: f32 = AMBIENT + shadowing * (1.0 - AMBIENT) * clamp(dot(si.n, sun_dir), 0.0, 1.0);
             debug_assert!(lighting <= 1.0);
 
-            let colour = COLOURS[hit.geom_id.unwrap() as usize] * lighting;
+            let colour = COLOURS[si.geom_id.unwrap() as usize] * lighting;
             *value = colour.to_rgba8();
         }
     })
@@ -0,0 +1,359 @@
+use std::f32;
+use std::f32::consts::PI;
+
+use cgmath::*;
+
+use common::*;
+use csg_geometry::*;
+use interaction::coordinate_system;
+use ray::*;
+use user_geometry::*;
+
+// Analytic primitives for `UserGeometry`. Normals point out of the solid and are normalized.
+// Each primitive's `hit` can also be used on its own, outside of a scene
+
+/// Embree needs finite bounds, so unbounded primitives use these
+const UNBOUNDED_EXTENT: f32 = 1e18;
+
+#[derive(Debug, Copy, Clone)]
+pub struct SpherePrimitive {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct BoxPrimitive {
+    pub lower: Point3<f32>,
+    pub upper: Point3<f32>,
+}
+
+/// A box spanning `center ± axes[i]` along each of its three orthogonal `axes`
+#[derive(Debug, Copy, Clone)]
+pub struct OrientedBoxPrimitive {
+    pub center: Point3<f32>,
+    pub axes: [Vector3<f32>; 3],
+}
+
+/// Capped cylinder running from `base` to `base + axis`
+#[derive(Debug, Copy, Clone)]
+pub struct CylinderPrimitive {
+    pub base: Point3<f32>,
+    pub axis: Vector3<f32>,
+    pub radius: f32,
+}
+
+/// Cone with a disc of `radius` at `base` and its apex at `base + axis`
+#[derive(Debug, Copy, Clone)]
+pub struct ConePrimitive {
+    pub base: Point3<f32>,
+    pub axis: Vector3<f32>,
+    pub radius: f32,
+}
+
+/// All points within `radius` of the segment from `a` to `b`
+#[derive(Debug, Copy, Clone)]
+pub struct CapsulePrimitive {
+    pub a: Point3<f32>,
+    pub b: Point3<f32>,
+    pub radius: f32,
+}
+
+/// Torus in the plane through `center` perpendicular to `axis`
+#[derive(Debug, Copy, Clone)]
+pub struct TorusPrimitive {
+    pub center: Point3<f32>,
+    pub axis: Vector3<f32>,
+    pub major_radius: f32,
+    pub minor_radius: f32,
+}
+
+/// Infinite plane, hit from either side. Hits always report `normal`
+#[derive(Debug, Copy, Clone)]
+pub struct PlanePrimitive {
+    pub point: Point3<f32>,
+    pub normal: Vector3<f32>,
+}
+
+/// The first surface crossing within the ray's range
+fn first_boundary(spans: &[Span], ray: &Ray) -> Option<Boundary> {
+    spans.iter()
+        .flat_map(|span| vec![span.enter, span.exit])
+        .find(|b| ray.in_range(b.t))
+}
+
+/// `v` in the frame of `axis` with the angle around it in [0, 1)
+fn cylindrical(v: Vector3<f32>, axis: Vector3<f32>) -> (f32, f32) {
+    let n = axis.normalize();
+    let (t1, t2) = coordinate_system(n);
+    let angle = dot(v, t2).atan2(dot(v, t1)) / (2.0 * PI);
+    (if angle < 0.0 { angle + 1.0 } else { angle }, dot(v, n))
+}
+
+fn clamp01(x: f32) -> f32 {
+    x.clamp(0.0, 1.0)
+}
+
+impl SpherePrimitive {
+    /// u is the angle around z and v the angle down from +z
+    pub fn hit(&self, ray: &Ray) -> Option<UserPrimHit> {
+        let span = sphere_span(self.center, self.radius, ray)?;
+        let b = first_boundary(&[span], ray)?;
+        let (u, z) = cylindrical(b.normal, Vector3::unit_z());
+        Some(UserPrimHit::new(b.t, b.normal.normalize(), Vector2::new(u, z.clamp(-1.0, 1.0).acos() / PI)))
+    }
+
+    fn bounding_box(&self) -> Bounds {
+        let r = Vector3::new(self.radius, self.radius, self.radius);
+        Bounds::new(self.center - r, self.center + r)
+    }
+}
+
+/// uv across the face of a unit cube [0, 1]^3 with the given normal
+fn box_face_uv(p: Point3<f32>, normal: Vector3<f32>) -> Vector2<f32> {
+    let axis = if normal.x.abs() > 0.5 { 0 } else if normal.y.abs() > 0.5 { 1 } else { 2 };
+    let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+    Vector2::new(clamp01(p[a]), clamp01(p[b]))
+}
+
+impl BoxPrimitive {
+    /// uv spans each face, along the next two axes after the face's normal
+    pub fn hit(&self, ray: &Ray) -> Option<UserPrimHit> {
+        let span = box_span(self.lower, self.upper, ray)?;
+        let b = first_boundary(&[span], ray)?;
+        let size = self.upper - self.lower;
+        let p = ray.point_at_dist(b.t) - self.lower;
+        let local = Point3::new(p.x / size.x, p.y / size.y, p.z / size.z);
+        Some(UserPrimHit::new(b.t, b.normal, box_face_uv(local, b.normal)))
+    }
+
+    fn bounding_box(&self) -> Bounds {
+        Bounds::new(self.lower, self.upper)
+    }
+}
+
+impl OrientedBoxPrimitive {
+    /// uv spans each face like `BoxPrimitive` in the frame of the box's axes
+    pub fn hit(&self, ray: &Ray) -> Option<UserPrimHit> {
+        // The box is [-1, 1]^3 in its own frame and distances along the ray are unchanged
+        let to_local = |v: Vector3<f32>| Vector3::new(
+            dot(v, self.axes[0]) / self.axes[0].magnitude2(),
+            dot(v, self.axes[1]) / self.axes[1].magnitude2(),
+            dot(v, self.axes[2]) / self.axes[2].magnitude2());
+        let mut local_ray = *ray;
+        local_ray.origin = Point3::from_vec(to_local(ray.origin - self.center));
+        local_ray.dir = to_local(ray.dir);
+        let span = box_span(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0), &local_ray)?;
+        let b = first_boundary(&[span], &local_ray)?;
+        let p = local_ray.point_at_dist(b.t);
+        let uv = box_face_uv(Point3::new(0.5 * p.x + 0.5, 0.5 * p.y + 0.5, 0.5 * p.z + 0.5), b.normal);
+        let normal = self.axes[0].normalize() * b.normal.x
+            + self.axes[1].normalize() * b.normal.y
+            + self.axes[2].normalize() * b.normal.z;
+        Some(UserPrimHit::new(b.t, normal, uv))
+    }
+
+    fn bounding_box(&self) -> Bounds {
+        let abs = |v: Vector3<f32>| Vector3::new(v.x.abs(), v.y.abs(), v.z.abs());
+        let extent = abs(self.axes[0]) + abs(self.axes[1]) + abs(self.axes[2]);
+        Bounds::new(self.center - extent, self.center + extent)
+    }
+}
+
+impl CylinderPrimitive {
+    /// u is the angle around the axis and v the distance along it. The caps are mapped
+    /// to their disc's square
+    pub fn hit(&self, ray: &Ray) -> Option<UserPrimHit> {
+        let span = cylinder_span(self.base, self.axis, self.radius, ray)?;
+        let b = first_boundary(&[span], ray)?;
+        let p = ray.point_at_dist(b.t) - self.base;
+        let (u, v) = cylindrical(p, self.axis);
+        let normal = b.normal.normalize();
+        let uv = if dot(normal, self.axis).abs() > 0.5 {
+            let (t1, t2) = coordinate_system(self.axis.normalize());
+            let disc = |t: Vector3<f32>| clamp01(0.5 * dot(p, t) / self.radius + 0.5);
+            Vector2::new(disc(t1), disc(t2))
+        } else {
+            Vector2::new(u, clamp01(v / self.axis.magnitude()))
+        };
+        Some(UserPrimHit::new(b.t, normal, uv))
+    }
+
+    fn bounding_box(&self) -> Bounds {
+        CsgSolid::Cylinder { base: self.base, axis: self.axis, radius: self.radius }.bounds()
+    }
+}
+
+impl ConePrimitive {
+    /// u is the angle around the axis and v the distance along it, from the base to the apex
+    pub fn hit(&self, ray: &Ray) -> Option<UserPrimHit> {
+        let span = cone_span(self.base, self.axis, self.radius, ray)?;
+        let b = first_boundary(&[span], ray)?;
+        let (u, v) = cylindrical(ray.point_at_dist(b.t) - self.base, self.axis);
+        let normal = if b.normal.magnitude2() > 0.0 { b.normal.normalize() } else { self.axis.normalize() };
+        Some(UserPrimHit::new(b.t, normal, Vector2::new(u, clamp01(v / self.axis.magnitude()))))
+    }
+
+    fn bounding_box(&self) -> Bounds {
+        CsgSolid::Cone { base: self.base, axis: self.axis, radius: self.radius }.bounds()
+    }
+}
+
+impl CapsulePrimitive {
+    /// u is the angle around the axis and v the distance along it from the tip beyond `a`
+    /// to the tip beyond `b`
+    pub fn hit(&self, ray: &Ray) -> Option<UserPrimHit> {
+        let axis = self.b - self.a;
+        let ends: Vec<Span> = sphere_span(self.a, self.radius, ray).into_iter().collect();
+        let other: Vec<Span> = sphere_span(self.b, self.radius, ray).into_iter().collect();
+        let ends = combine(CsgOp::Union, &ends, &other);
+        let side: Vec<Span> = cylinder_span(self.a, axis, self.radius, ray).into_iter().collect();
+        let b = first_boundary(&combine(CsgOp::Union, &ends, &side), ray)?;
+        let (u, v) = cylindrical(ray.point_at_dist(b.t) - self.a, axis);
+        let length = axis.magnitude();
+        let v = (v + self.radius) / (length + 2.0 * self.radius);
+        Some(UserPrimHit::new(b.t, b.normal.normalize(), Vector2::new(u, clamp01(v))))
+    }
+
+    fn bounding_box(&self) -> Bounds {
+        let r = Vector3::new(self.radius, self.radius, self.radius);
+        Bounds::new(self.a - r, self.a + r).union(&Bounds::new(self.b - r, self.b + r))
+    }
+}
+
+impl TorusPrimitive {
+    /// u is the angle around the axis and v the angle around the tube
+    pub fn hit(&self, ray: &Ray) -> Option<UserPrimHit> {
+        let spans = torus_spans(self.center, self.axis, self.major_radius, self.minor_radius, ray);
+        let b = first_boundary(&spans, ray)?;
+        let p = ray.point_at_dist(b.t) - self.center;
+        let (u, _) = cylindrical(p, self.axis);
+        let n = self.axis.normalize();
+        let radial = p - n * dot(p, n);
+        let ring = if radial.magnitude2() > 0.0 { radial.normalize() } else { coordinate_system(n).0 };
+        let tube = p - ring * self.major_radius;
+        let v = dot(tube, n).atan2(dot(tube, ring)) / (2.0 * PI);
+        Some(UserPrimHit::new(b.t, b.normal, Vector2::new(u, if v < 0.0 { v + 1.0 } else { v })))
+    }
+
+    fn bounding_box(&self) -> Bounds {
+        CsgSolid::Torus {
+            center: self.center,
+            axis: self.axis,
+            major_radius: self.major_radius,
+            minor_radius: self.minor_radius,
+        }.bounds()
+    }
+}
+
+impl PlanePrimitive {
+    /// uv are world space distances from `point` along a frame in the plane
+    pub fn hit(&self, ray: &Ray) -> Option<UserPrimHit> {
+        let n = self.normal.normalize();
+        let denom = dot(ray.dir, n);
+        if denom == 0.0 {
+            return None;
+        }
+        let t = dot(self.point - ray.origin, n) / denom;
+        if !ray.in_range(t) {
+            return None;
+        }
+        let p = ray.point_at_dist(t) - self.point;
+        let (t1, t2) = coordinate_system(n);
+        Some(UserPrimHit::new(t, n, Vector2::new(dot(p, t1), dot(p, t2))))
+    }
+
+    fn bounding_box(&self) -> Bounds {
+        let e = Vector3::new(UNBOUNDED_EXTENT, UNBOUNDED_EXTENT, UNBOUNDED_EXTENT);
+        Bounds::new(Point3::new(0.0, 0.0, 0.0) - e, Point3::new(0.0, 0.0, 0.0) + e)
+    }
+}
+
+macro_rules! impl_user_primitive {
+    ($($t:ty),*) => {
+        $(
+            impl UserPrimitive for $t {
+                fn intersect(&self, ray: &Ray) -> UserPrimHit {
+                    self.hit(ray).unwrap_or_else(UserPrimHit::miss)
+                }
+
+                fn bounds(&self) -> Bounds {
+                    self.bounding_box()
+                }
+            }
+        )*
+    }
+}
+
+impl_user_primitive!(SpherePrimitive, BoxPrimitive, OrientedBoxPrimitive, CylinderPrimitive,
+    ConePrimitive, CapsulePrimitive, TorusPrimitive, PlanePrimitive);
+
+#[cfg(test)]
+fn test_rays() -> Vec<Ray> {
+    // Deterministic rays from a shell around the origin aimed near it
+    let mut seed = 12345u32;
+    let mut rand = move || {
+        seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+        (seed >> 8) as f32 / (1 << 24) as f32
+    };
+    (0..256).map(|_| {
+        let origin = Point3::new(rand() - 0.5, rand() - 0.5, rand() - 0.5) * 20.0;
+        let target = Point3::new(rand() - 0.5, rand() - 0.5, rand() - 0.5) * 2.0;
+        Ray::new(origin, (target - origin).normalize(), 0.0, f32::INFINITY)
+    }).collect()
+}
+
+#[test]
+fn test_primitive_hits() {
+    let down = Vector3::new(0.0, 0.0, -1.0);
+    let ray = Ray::new(Point3::new(0.25, 0.5, 5.0), down, 0.0, f32::INFINITY);
+
+    let hit = BoxPrimitive { lower: Point3::new(0.0, 0.0, 0.0), upper: Point3::new(1.0, 1.0, 1.0) }.hit(&ray).unwrap();
+    assert_eq!((hit.t, hit.Ng, hit.uv), (4.0, Vector3::unit_z(), Vector2::new(0.25, 0.5)));
+
+    let obb = OrientedBoxPrimitive {
+        center: Point3::new(0.5, 0.5, 0.5),
+        axes: [Vector3::new(0.0, 0.5, 0.0), Vector3::new(-0.5, 0.0, 0.0), Vector3::new(0.0, 0.0, 0.5)],
+    };
+    let hit = obb.hit(&ray).unwrap();
+    assert!((hit.t - 4.0).abs() < 1e-6 && (hit.Ng - Vector3::unit_z()).magnitude() < 1e-6);
+
+    let capsule = CapsulePrimitive { a: Point3::new(0.0, 0.0, 0.0), b: Point3::new(0.0, 0.0, 2.0), radius: 0.5 };
+    let hit = capsule.hit(&Ray::new(Point3::new(0.0, 0.0, 5.0), down, 0.0, f32::INFINITY)).unwrap();
+    assert!((hit.t - 2.5).abs() < 1e-6 && (hit.uv.y - 1.0).abs() < 1e-6);
+    let hit = capsule.hit(&Ray::new(Point3::new(5.0, 0.0, 1.0), -Vector3::unit_x(), 0.0, f32::INFINITY)).unwrap();
+    assert!((hit.t - 4.5).abs() < 1e-6 && (hit.Ng - Vector3::unit_x()).magnitude() < 1e-6);
+
+    let plane = PlanePrimitive { point: Point3::new(0.0, 0.0, -1.0), normal: Vector3::unit_z() };
+    assert!((plane.hit(&ray).unwrap().t - 6.0).abs() < 1e-6);
+    assert!(plane.hit(&Ray::new(ray.origin, down, 0.0, 2.0)).is_none());
+
+    let torus = TorusPrimitive { center: Point3::origin(), axis: Vector3::unit_z(), major_radius: 2.0, minor_radius: 0.5 };
+    let hit = torus.hit(&Ray::new(Point3::new(2.0, 0.0, 5.0), down, 0.0, f32::INFINITY)).unwrap();
+    assert!((hit.t - 4.5).abs() < 1e-4 && (hit.uv.y - 0.25).abs() < 1e-3);
+}
+
+#[test]
+fn test_sphere_primitive_against_embree_sphere() {
+    use device::Device;
+    use point_geometry::*;
+    use scene::SceneBuilder;
+
+    let device = Device::new();
+    let sphere = SpherePrimitive { center: Point3::new(0.1, -0.2, 0.3), radius: 1.5 };
+    let mut builder = SceneBuilder::new(&device);
+    builder.attach(SphereGeometry::new(&device, vec![Sphere { center: sphere.center, radius: sphere.radius }]));
+    let scene = builder.build();
+
+    for ray in test_rays() {
+        let mut rayhit = RayHit { ray, hit: Hit::empty() };
+        scene.intersect(&mut rayhit);
+        match sphere.hit(&ray) {
+            Some(hit) => {
+                assert!(rayhit.hit.is_hit());
+                assert!((hit.t - rayhit.ray.tfar).abs() <= 1e-4 * hit.t);
+                assert!((hit.Ng - rayhit.hit.Ng.normalize()).magnitude() < 1e-3);
+            }
+            None => assert!(!rayhit.hit.is_hit()),
+        }
+    }
+}
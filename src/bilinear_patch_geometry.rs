@@ -0,0 +1,174 @@
+use std::f32;
+
+use cgmath::*;
+
+use common::*;
+use device::*;
+use geometry::*;
+use polygon_geometry::Quad;
+use ray::*;
+use user_geometry::*;
+
+/// The surface P(u, v) = (1-u)(1-v) p00 + u(1-v) p10 + u v p11 + (1-u) v p01,
+/// which needn't be planar
+#[derive(Debug, Copy, Clone)]
+pub struct BilinearPatch {
+    pub p00: Point3<f32>,
+    pub p10: Point3<f32>,
+    pub p11: Point3<f32>,
+    pub p01: Point3<f32>,
+}
+
+impl BilinearPatch {
+    pub fn new(p00: Point3<f32>, p10: Point3<f32>, p11: Point3<f32>, p01: Point3<f32>) -> Self {
+        BilinearPatch { p00, p10, p11, p01 }
+    }
+
+    pub fn point(&self, u: f32, v: f32) -> Point3<f32> {
+        let bottom = self.p00 + (self.p10 - self.p00) * u;
+        let top = self.p01 + (self.p11 - self.p01) * u;
+        bottom + (top - bottom) * v
+    }
+
+    /// dP/du x dP/dv, which matches the winding of a `Quad` with the same vertices
+    pub fn normal(&self, u: f32, v: f32) -> Vector3<f32> {
+        let dpdu = (self.p10 - self.p00) * (1.0 - v) + (self.p11 - self.p01) * v;
+        let dpdv = (self.p01 - self.p00) * (1.0 - u) + (self.p11 - self.p10) * u;
+        dpdu.cross(dpdv)
+    }
+
+    /// The closest intersection within the ray's range, with uv over the whole patch.
+    /// See "Cool Patches: A Geometric Approach to Ray/Bilinear Patch Intersections", Ray Tracing Gems
+    pub fn hit(&self, ray: &Ray) -> Option<UserPrimHit> {
+        let d = ray.dir;
+        let q00 = self.p00 - ray.origin;
+        let q10 = self.p10 - ray.origin;
+        let e10 = self.p10 - self.p00;
+        let e11 = self.p11 - self.p10;
+        let e00 = self.p01 - self.p00;
+        let qn = e10.cross(self.p01 - self.p11);
+
+        // u is a root of a + b u + c u^2
+        let a = dot(q00.cross(d), e00);
+        let c = dot(qn, d);
+        let b = dot(q10.cross(d), e11) - (a + c);
+        let det = b * b - 4.0 * a * c;
+        if det < 0.0 {
+            return None;
+        }
+        let det = det.sqrt();
+        let (u1, u2) = if c == 0.0 {
+            (-a / b, f32::NAN)
+        } else {
+            // Avoids cancellation between -b and the root of the discriminant
+            let u1 = 0.5 * (-b - det.copysign(b));
+            (u1 / c, a / u1)
+        };
+
+        let mut best: Option<(f32, f32, f32)> = None;
+        for &u in [u1, u2].iter() {
+            if !(0.0..=1.0).contains(&u) {
+                continue;
+            }
+            // Intersect the ray with the line across the patch at u
+            let pa = q00 + e10 * u;
+            let pb = e00 + (e11 - e00) * u;
+            let n = d.cross(pb);
+            let len2 = n.magnitude2();
+            if len2 == 0.0 {
+                continue;
+            }
+            let n = n.cross(pa);
+            let t = dot(n, pb) / len2;
+            let v = dot(n, d) / len2;
+            if (0.0..=1.0).contains(&v) && ray.in_range(t) && best.map_or(true, |(best_t, _, _)| t < best_t) {
+                best = Some((t, u, v));
+            }
+        }
+        best.map(|(t, u, v)| UserPrimHit::new(t, self.normal(u, v), Vector2::new(u, v)))
+    }
+}
+
+impl UserPrimitive for BilinearPatch {
+    fn intersect(&self, ray: &Ray) -> UserPrimHit {
+        self.hit(ray).unwrap_or_else(UserPrimHit::miss)
+    }
+
+    fn bounds(&self) -> Bounds {
+        // A bilinear patch lies within the convex hull of its corners
+        Bounds::from_points([self.p00, self.p10, self.p11, self.p01].iter().cloned())
+    }
+}
+
+/// Quads intersected exactly as bilinear patches rather than as two triangles, for
+/// quads whose vertices aren't co-planar. Takes the same buffers as a `QuadMesh`
+pub struct BilinearPatchGeometry {
+    handle: GeometryHandle,
+    id: u32,
+    pub indices: Vec<Quad>,
+    pub vertices: Vec<Point3<f32>>,
+}
+
+impl BilinearPatchGeometry {
+    pub fn new(device: &Device, index_buffer: Vec<Quad>, vertex_buffer: Vec<Point3<f32>>) -> Self {
+        BilinearPatchGeometry {
+            handle: GeometryHandle::new(device, GeometryType::User),
+            id: 0,
+            indices: index_buffer,
+            vertices: vertex_buffer,
+        }
+    }
+
+    pub fn patch(&self, prim_id: u32) -> BilinearPatch {
+        let q = self.indices[prim_id as usize];
+        let v = |i: u32| self.vertices[i as usize];
+        BilinearPatch::new(v(q.v0), v(q.v1), v(q.v2), v(q.v3))
+    }
+}
+
+impl Geometry for BilinearPatchGeometry {
+    fn handle(&self) -> &GeometryHandle {
+        &self.handle
+    }
+
+    fn handle_mut(&mut self) -> &mut GeometryHandle {
+        &mut self.handle
+    }
+
+    fn set_geom_id(&mut self, id: u32) {
+        self.id = id;
+    }
+
+    fn bind_buffers(&mut self) {
+        let count = self.indices.len();
+        unsafe { bind_user_primitives(self, count); }
+    }
+}
+
+impl UserPrimitiveSource for BilinearPatchGeometry {
+    type Primitive<'a> = BilinearPatch;
+
+    fn geom_id(&self) -> u32 {
+        self.id
+    }
+
+    fn with_primitive<'a, R, F: FnOnce(&Self::Primitive<'a>) -> R>(&'a self, prim_id: u32, f: F) -> R {
+        f(&self.patch(prim_id))
+    }
+}
+
+#[test]
+fn test_bilinear_patch_hit() {
+    // A saddle, z = (x - 0.5)(y - 0.5) scaled, which two triangles can't represent
+    let patch = BilinearPatch::new(Point3::new(0.0, 0.0, 1.0), Point3::new(1.0, 0.0, -1.0),
+        Point3::new(1.0, 1.0, 1.0), Point3::new(0.0, 1.0, -1.0));
+    for &(x, y) in [(0.5, 0.5), (0.25, 0.75), (0.9, 0.1), (0.0, 0.0)].iter() {
+        let ray = Ray::new(Point3::new(x, y, 5.0), Vector3::new(0.0, 0.0, -2.0), 0.0, f32::INFINITY);
+        let hit = patch.hit(&ray).unwrap();
+        let expected = patch.point(x, y);
+        assert!((ray.point_at_dist(hit.t) - expected).magnitude() < 1e-5);
+        assert!((hit.uv - Vector2::new(x, y)).magnitude() < 1e-5);
+        assert!(hit.Ng.z > 0.0);
+    }
+    assert!(patch.hit(&Ray::new(Point3::new(1.5, 0.5, 5.0), -Vector3::unit_z(), 0.0, f32::INFINITY)).is_none());
+}
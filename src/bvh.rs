@@ -0,0 +1,354 @@
+use std::ffi::c_void;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr::{self, NonNull};
+use std::slice;
+use std::sync::Mutex;
+
+use cgmath::*;
+
+use sys::*;
+
+use common::*;
+use device::Device;
+use error::*;
+
+/// A primitive given to `BvhBuilder` by its bounds. The ids are passed back to leaves
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct BuildPrimitive {
+    pub lower: Point3<f32>,
+    pub geom_id: u32,
+    pub upper: Point3<f32>,
+    pub prim_id: u32,
+}
+
+impl BuildPrimitive {
+    pub fn new(bounds: &Bounds, geom_id: u32, prim_id: u32) -> Self {
+        BuildPrimitive {
+            lower: bounds.lower,
+            geom_id,
+            upper: bounds.upper,
+            prim_id,
+        }
+    }
+
+    pub fn bounds(&self) -> Bounds {
+        Bounds::new(self.lower, self.upper)
+    }
+}
+
+/// A node of a `Bvh`, as an index into the tree's nodes which `Bvh::node` looks up
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+/// A node in Embree's thread local arena, which records its index so Embree's pointers
+/// to it can be given to `set_children` as `NodeId`s
+struct Slot<N> {
+    id: NodeId,
+    node: N,
+}
+
+/// A tree built by `BvhBuilder`. Nodes are dropped and their memory freed along with it
+pub struct Bvh<N> {
+    handle: RTCBVH,
+    root: Option<NodeId>,
+    nodes: Vec<NonNull<Slot<N>>>,
+}
+
+unsafe impl<N: Send + Sync> Send for Bvh<N> {}
+unsafe impl<N: Send + Sync> Sync for Bvh<N> {}
+
+impl<N> Bvh<N> {
+    /// `None` if there were no primitives
+    pub fn root(&self) -> Option<&N> {
+        self.root.map(|id| self.node(id))
+    }
+
+    /// Panics if the node isn't in this tree
+    pub fn node(&self, id: NodeId) -> &N {
+        unsafe { &self.nodes[id.0].as_ref().node }
+    }
+
+    /// Number of inner nodes and leaves
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+impl<N> Drop for Bvh<N> {
+    fn drop(&mut self) {
+        for slot in self.nodes.iter() {
+            unsafe { ptr::drop_in_place(slot.as_ptr()); }
+        }
+        unsafe { rtcReleaseBVH(self.handle) }
+    }
+}
+
+/// Settings for building a `Bvh` over user primitives with Embree's builders
+#[derive(Debug, Copy, Clone)]
+pub struct BvhBuilder {
+    pub quality: BuildQuality,
+    pub max_branching_factor: u32,
+    pub max_depth: u32,
+    /// Leaf sizes the SAH heuristic is computed for are multiples of this
+    pub sah_block_size: u32,
+    pub min_leaf_size: u32,
+    pub max_leaf_size: u32,
+    /// Estimated cost of stepping into a node relative to intersecting a primitive
+    pub traversal_cost: f32,
+    pub intersection_cost: f32,
+}
+
+/// The closures given to `BvhBuilder::build`, shared by Embree's build threads
+struct BuildCallbacks<'a, N> {
+    create_node: &'a (dyn Fn(usize) -> N + Sync),
+    set_children: &'a (dyn Fn(&mut N, Vec<NodeId>) + Sync),
+    set_bounds: &'a (dyn Fn(&mut N, &[Bounds]) + Sync),
+    create_leaf: &'a (dyn Fn(&[BuildPrimitive]) -> N + Sync),
+    nodes: Mutex<Vec<NonNull<Slot<N>>>>,
+    /// Set when a closure panics or a node can't be allocated, which cancels the build
+    error: Mutex<Option<ErrorKind>>,
+}
+
+impl<'a, N> BuildCallbacks<'a, N> {
+    fn failed(&self) -> bool {
+        self.error.lock().unwrap().is_some()
+    }
+
+    fn fail(&self, error: ErrorKind) {
+        self.error.lock().unwrap().get_or_insert(error);
+    }
+
+    /// Runs a user closure unless the build already failed. Panics can't unwind into
+    /// Embree, so they're caught and fail the build instead
+    fn run<R, F: FnOnce() -> R>(&self, f: F) -> Option<R> {
+        if self.failed() {
+            return None;
+        }
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(r) => Some(r),
+            Err(_) => {
+                self.fail(ErrorKind::Cancelled);
+                None
+            }
+        }
+    }
+
+    unsafe fn alloc(&self, allocator: RTCThreadLocalAllocator, node: Option<N>) -> *mut c_void {
+        let node = match node {
+            Some(node) => node,
+            None => return ptr::null_mut(),
+        };
+        let ptr = rtcThreadLocalAlloc(allocator, mem::size_of::<Slot<N>>(), mem::align_of::<Slot<N>>()) as *mut Slot<N>;
+        match NonNull::new(ptr) {
+            Some(ptr) => {
+                let mut nodes = self.nodes.lock().unwrap();
+                ptr::write(ptr.as_ptr(), Slot { id: NodeId(nodes.len()), node });
+                nodes.push(ptr);
+                ptr.as_ptr() as *mut c_void
+            }
+            None => {
+                self.fail(ErrorKind::OutOfMemory);
+                ptr::null_mut()
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn create_node_func<N>(allocator: RTCThreadLocalAllocator, child_count: u32, user_ptr: *mut c_void) -> *mut c_void {
+    let callbacks = &*(user_ptr as *const BuildCallbacks<N>);
+    callbacks.alloc(allocator, callbacks.run(|| (callbacks.create_node)(child_count as usize)))
+}
+
+unsafe extern "C" fn set_node_children_func<N>(node: *mut c_void, children: *mut *mut c_void, child_count: u32, user_ptr: *mut c_void) {
+    let callbacks = &*(user_ptr as *const BuildCallbacks<N>);
+    // Nodes are only null after the build failed, and then run skips the closure
+    callbacks.run(|| {
+        let children = slice::from_raw_parts(children, child_count as usize).iter()
+            .map(|&c| (*(c as *const Slot<N>)).id)
+            .collect();
+        (callbacks.set_children)(&mut (*(node as *mut Slot<N>)).node, children);
+    });
+}
+
+unsafe extern "C" fn set_node_bounds_func<N>(node: *mut c_void, bounds: *mut *const RTCBounds, child_count: u32, user_ptr: *mut c_void) {
+    let callbacks = &*(user_ptr as *const BuildCallbacks<N>);
+    callbacks.run(|| {
+        // Bounds has the same layout as RTCBounds
+        let bounds: Vec<Bounds> = slice::from_raw_parts(bounds, child_count as usize).iter()
+            .map(|&b| *(b as *const Bounds))
+            .collect();
+        (callbacks.set_bounds)(&mut (*(node as *mut Slot<N>)).node, &bounds);
+    });
+}
+
+unsafe extern "C" fn create_leaf_func<N>(allocator: RTCThreadLocalAllocator, primitives: *const RTCBuildPrimitive,
+                                         primitive_count: usize, user_ptr: *mut c_void) -> *mut c_void {
+    let callbacks = &*(user_ptr as *const BuildCallbacks<N>);
+    let primitives = if primitive_count == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(primitives as *const BuildPrimitive, primitive_count)
+    };
+    callbacks.alloc(allocator, callbacks.run(|| (callbacks.create_leaf)(primitives)))
+}
+
+/// Embree cancels the build once this returns false
+unsafe extern "C" fn build_progress_func<N>(user_ptr: *mut c_void, _progress: f64) -> bool {
+    let callbacks = &*(user_ptr as *const BuildCallbacks<N>);
+    !callbacks.failed()
+}
+
+impl Default for BvhBuilder {
+    fn default() -> Self {
+        BvhBuilder::new()
+    }
+}
+
+impl BvhBuilder {
+    pub fn new() -> Self {
+        BvhBuilder {
+            quality: BuildQuality::Medium,
+            max_branching_factor: 2,
+            max_depth: 32,
+            sah_block_size: 1,
+            min_leaf_size: 1,
+            max_leaf_size: 32,
+            traversal_cost: 1.0,
+            intersection_cost: 1.0,
+        }
+    }
+
+    /// Builds a tree over `primitives`. Embree calls the closures from several threads:
+    /// `create_node` makes an inner node with a number of children, which are then given
+    /// to `set_children` followed by their bounds to `set_bounds`, and `create_leaf` makes
+    /// a leaf holding some of the primitives.
+    ///
+    /// Children are given as `NodeId`s of the returned tree. If a closure panics the build
+    /// is cancelled and returns `Err(ErrorKind::Cancelled)`
+    pub fn build<N, C, S, B, L>(&self, device: &Device, primitives: &[BuildPrimitive],
+                                create_node: C, set_children: S, set_bounds: B, create_leaf: L) -> Result<Bvh<N>, ErrorKind>
+        where N: Send + Sync,
+              C: Fn(usize) -> N + Sync,
+              S: Fn(&mut N, Vec<NodeId>) + Sync,
+              B: Fn(&mut N, &[Bounds]) + Sync,
+              L: Fn(&[BuildPrimitive]) -> N + Sync {
+        let callbacks = BuildCallbacks {
+            create_node: &create_node,
+            set_children: &set_children,
+            set_bounds: &set_bounds,
+            create_leaf: &create_leaf,
+            nodes: Mutex::new(Vec::new()),
+            error: Mutex::new(None),
+        };
+        // Embree reorders the primitives while building
+        let mut primitives = primitives.to_vec();
+
+        let handle = unsafe { rtcNewBVH(device.ptr) };
+        let args = RTCBuildArguments {
+            byteSize: mem::size_of::<RTCBuildArguments>(),
+            buildQuality: self.quality.into(),
+            buildFlags: RTC_BUILD_FLAG_NONE,
+            maxBranchingFactor: self.max_branching_factor,
+            maxDepth: self.max_depth,
+            sahBlockSize: self.sah_block_size,
+            minLeafSize: self.min_leaf_size,
+            maxLeafSize: self.max_leaf_size,
+            traversalCost: self.traversal_cost,
+            intersectionCost: self.intersection_cost,
+            bvh: handle,
+            primitives: primitives.as_mut_ptr() as *mut RTCBuildPrimitive,
+            primitiveCount: primitives.len(),
+            primitiveArrayCapacity: primitives.len(),
+            createNode: Some(create_node_func::<N>),
+            setNodeChildren: Some(set_node_children_func::<N>),
+            setNodeBounds: Some(set_node_bounds_func::<N>),
+            createLeaf: Some(create_leaf_func::<N>),
+            splitPrimitive: None,
+            buildProgress: Some(build_progress_func::<N>),
+            userPtr: &callbacks as *const BuildCallbacks<N> as *mut c_void,
+        };
+        let root = unsafe { rtcBuildBVH(&args) as *const Slot<N> };
+
+        // Dropping the tree frees any nodes made before a failure
+        let bvh = Bvh {
+            handle,
+            root: unsafe { root.as_ref() }.map(|slot| slot.id),
+            nodes: callbacks.nodes.into_inner().unwrap(),
+        };
+        if let Some(error) = callbacks.error.into_inner().unwrap() {
+            return Err(error);
+        }
+        if bvh.root.is_none() && !primitives.is_empty() {
+            device.last_error()?;
+            return Err(ErrorKind::Unknown);
+        }
+        Ok(bvh)
+    }
+}
+
+#[test]
+fn test_build_primitive_layout() {
+    assert_eq!(mem::size_of::<BuildPrimitive>(), mem::size_of::<RTCBuildPrimitive>());
+    assert_eq!(offset_of!(BuildPrimitive, lower), offset_of!(RTCBuildPrimitive, lower_x));
+    assert_eq!(offset_of!(BuildPrimitive, geom_id), offset_of!(RTCBuildPrimitive, geomID));
+    assert_eq!(offset_of!(BuildPrimitive, upper), offset_of!(RTCBuildPrimitive, upper_x));
+    assert_eq!(offset_of!(BuildPrimitive, prim_id), offset_of!(RTCBuildPrimitive, primID));
+    assert_eq!(mem::size_of::<Bounds>(), mem::size_of::<RTCBounds>());
+}
+
+#[test]
+fn test_bvh_builder_against_embree() {
+    enum Node {
+        Inner { children: Vec<NodeId>, bounds: Vec<Bounds> },
+        Leaf(Vec<u32>),
+    }
+
+    fn check(bvh: &Bvh<Node>, node: &Node, primitives: &[BuildPrimitive], seen: &mut Vec<u32>) {
+        match *node {
+            Node::Inner { ref children, ref bounds } => {
+                for (&child, b) in children.iter().zip(bounds.iter()) {
+                    let before = seen.len();
+                    check(bvh, bvh.node(child), primitives, seen);
+                    for &id in seen[before..].iter() {
+                        let p = primitives[id as usize];
+                        assert!(b.lower.x <= p.lower.x && b.upper.x >= p.upper.x);
+                    }
+                }
+            }
+            Node::Leaf(ref ids) => seen.extend_from_slice(ids),
+        }
+    }
+
+    let primitives: Vec<BuildPrimitive> = (0..1000).map(|i| {
+        let p = Point3::new((i % 10) as f32, (i / 10 % 10) as f32, (i / 100) as f32);
+        BuildPrimitive::new(&Bounds::new(p, p + Vector3::new(0.5, 0.5, 0.5)), 0, i)
+    }).collect();
+    let device = Device::new();
+    let mut builder = BvhBuilder::new();
+    builder.max_leaf_size = 4;
+    let bvh = builder.build(&device, &primitives,
+        |n| Node::Inner { children: Vec::with_capacity(n), bounds: Vec::with_capacity(n) },
+        |node, c| if let Node::Inner { ref mut children, .. } = *node { *children = c },
+        |node, b| if let Node::Inner { ref mut bounds, .. } = *node { *bounds = b.to_vec() },
+        |prims| Node::Leaf(prims.iter().map(|p| p.prim_id).collect())).unwrap();
+
+    let mut seen = Vec::new();
+    check(&bvh, bvh.root().unwrap(), &primitives, &mut seen);
+    seen.sort();
+    assert_eq!(seen, (0..1000).collect::<Vec<u32>>());
+}
+
+#[test]
+fn test_bvh_builder_panicking_closure() {
+    let primitives: Vec<BuildPrimitive> = (0..100).map(|i| {
+        let p = Point3::new(i as f32, 0.0, 0.0);
+        BuildPrimitive::new(&Bounds::new(p, p + Vector3::new(0.5, 0.5, 0.5)), 0, i)
+    }).collect();
+    let device = Device::new();
+    let builder = BvhBuilder { max_leaf_size: 4, ..BvhBuilder::default() };
+    let result = builder.build(&device, &primitives, |_| 0u32, |_, _| {}, |_, _| {},
+        |prims| if prims.iter().any(|p| p.prim_id == 42) { panic!("leaf with 42") } else { 1u32 });
+    assert_eq!(result.err(), Some(ErrorKind::Cancelled));
+}
@@ -1,9 +1,12 @@
 use std::u32;
+use std::f32;
 
 use cgmath::*;
 
 use sys::*;
 
+use ray::Ray;
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct GeomID {
@@ -68,6 +71,42 @@ impl Bounds {
         }
     }
 
+    /// The smallest bounds holding all the points, with `lower` above `upper` if there are none
+    pub fn from_points<I: IntoIterator<Item = Point3<f32>>>(points: I) -> Self {
+        let empty = Bounds::new(Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY));
+        points.into_iter().fold(empty, |b, p| b.union(&Bounds::new(p, p)))
+    }
+
+    /// The smallest bounds holding both
+    pub fn union(&self, other: &Bounds) -> Self {
+        Bounds::new(
+            Point3::new(self.lower.x.min(other.lower.x), self.lower.y.min(other.lower.y), self.lower.z.min(other.lower.z)),
+            Point3::new(self.upper.x.max(other.upper.x), self.upper.y.max(other.upper.y), self.upper.z.max(other.upper.z)))
+    }
+
+    /// Whether the ray passes through the bounds within its range. A ray along
+    /// one of the faces counts as passing through
+    pub fn hits(&self, ray: &Ray) -> bool {
+        let (mut t0, mut t1) = (ray.tnear, ray.tfar);
+        for axis in 0..3 {
+            let (lower, upper, o) = (self.lower[axis], self.upper[axis], ray.origin[axis]);
+            if ray.dir[axis] == 0.0 {
+                // Neighbouring bounds share faces so rays along them must still be inside
+                if o < lower || o > upper {
+                    return false;
+                }
+                continue;
+            }
+            let inv_dir = 1.0 / ray.dir[axis];
+            let near = (lower - o) * inv_dir;
+            let far = (upper - o) * inv_dir;
+            t0 = t0.max(near.min(far));
+            t1 = t1.min(near.max(far));
+        }
+        t0 <= t1
+    }
+
     pub fn as_raw_ptr(&mut self) -> *mut RTCBounds {
         self as *mut Bounds as *mut RTCBounds
     }
@@ -79,6 +118,61 @@ fn test_bounds_layout() {
     assert_eq!(offset_of!(Bounds, upper.x), offset_of!(RTCBounds, upper_x));
 }
 
+#[test]
+fn test_bounds_helpers() {
+    let b = Bounds::from_points(vec![Point3::new(1.0, 0.0, 2.0), Point3::new(0.0, 3.0, 1.0)]);
+    assert_eq!((b.lower, b.upper), (Point3::new(0.0, 0.0, 1.0), Point3::new(1.0, 3.0, 2.0)));
+    let u = b.union(&Bounds::new(Point3::new(-1.0, 1.0, 1.0), Point3::new(0.0, 1.0, 5.0)));
+    assert_eq!((u.lower, u.upper), (Point3::new(-1.0, 0.0, 1.0), Point3::new(1.0, 3.0, 5.0)));
+    assert!(Bounds::from_points(Vec::new()).lower.x > 0.0);
+
+    let ray = |origin: Point3<f32>, tfar: f32| Ray::new(origin, Vector3::unit_x(), 0.0, tfar);
+    assert!(b.hits(&ray(Point3::new(-5.0, 1.0, 1.5), f32::INFINITY)));
+    assert!(!b.hits(&ray(Point3::new(-5.0, 1.0, 1.5), 4.0)));
+    assert!(!b.hits(&ray(Point3::new(-5.0, 4.0, 1.5), f32::INFINITY)));
+    // Along a face
+    assert!(b.hits(&ray(Point3::new(-5.0, 3.0, 2.0), f32::INFINITY)));
+}
+
+/// Bound on the relative error of `n` consecutive floating point operations
+/// (see Physically Based Rendering, 3rd ed. section 3.9)
+pub(crate) fn gamma(n: i32) -> f32 {
+    let e = f32::EPSILON * 0.5;
+    (n as f32 * e) / (1.0 - n as f32 * e)
+}
+
+/// The next representable float greater than `v`
+pub(crate) fn next_float_up(v: f32) -> f32 {
+    if v.is_infinite() && v > 0.0 {
+        return v;
+    }
+    let v = if v == -0.0 { 0.0 } else { v };
+    let bits = v.to_bits();
+    let bits = if v >= 0.0 { bits + 1 } else { bits - 1 };
+    f32::from_bits(bits)
+}
+
+/// The next representable float less than `v`
+pub(crate) fn next_float_down(v: f32) -> f32 {
+    if v.is_infinite() && v < 0.0 {
+        return v;
+    }
+    let v = if v == 0.0 { -0.0 } else { v };
+    let bits = v.to_bits();
+    let bits = if v > 0.0 { bits - 1 } else { bits + 1 };
+    f32::from_bits(bits)
+}
+
+#[test]
+fn test_next_float() {
+    assert!(next_float_up(1.0) > 1.0);
+    assert!(next_float_down(1.0) < 1.0);
+    assert!(next_float_up(0.0) > 0.0);
+    assert!(next_float_down(0.0) < 0.0);
+    assert!(next_float_up(-1.0) > -1.0);
+    assert_eq!(next_float_down(next_float_up(3.5)), 3.5);
+}
+
 macro_rules! into_primitive {
     ($enum_name:ty, $prim:ty) => (
         impl Into<$prim> for $enum_name {
@@ -103,11 +197,13 @@ pub(crate) trait TypeFormat {
     const FORMAT: Format;
 }
 
+/// The layout of a single element in a geometry buffer
 #[repr(i32)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 #[allow(non_camel_case_types)]
 #[allow(dead_code)]
-pub(crate) enum Format {
+pub enum Format {
+    u8x1   = RTC_FORMAT_UCHAR,
     u32x1  = RTC_FORMAT_UINT,
     u32x2  = RTC_FORMAT_UINT2,
     u32x3  = RTC_FORMAT_UINT3,
@@ -128,10 +224,48 @@ pub(crate) enum Format {
     f32x14 = RTC_FORMAT_FLOAT14,
     f32x15 = RTC_FORMAT_FLOAT15,
     f32x16 = RTC_FORMAT_FLOAT16,
+    grid = RTC_FORMAT_GRID,
 }
 
 into_primitive!(Format, i32);
 
+impl Format {
+    /// Number of components in an element of this format. Grids are structs rather than
+    /// vectors, so `Format::grid` has none
+    pub fn component_count(&self) -> u32 {
+        match self {
+            Format::u8x1 => 1,
+            Format::u32x1 => 1,
+            Format::u32x2 => 2,
+            Format::u32x3 => 3,
+            Format::u32x4 => 4,
+            Format::grid => 0,
+            // The float formats are numbered consecutively
+            _ => (*self as i32 - RTC_FORMAT_FLOAT + 1) as u32,
+        }
+    }
+
+    /// The float format with `count` components. Panics unless `1 <= count <= 16`
+    pub fn float(count: u32) -> Format {
+        assert!((1..=16).contains(&count), "Float formats have between 1 and 16 components");
+        unsafe { ::std::mem::transmute::<i32, Format>(RTC_FORMAT_FLOAT + count as i32 - 1) }
+    }
+
+    pub(crate) fn is_float(&self) -> bool {
+        !matches!(self, Format::u8x1 | Format::u32x1 | Format::u32x2 | Format::u32x3 | Format::u32x4 | Format::grid)
+    }
+}
+
+#[test]
+fn test_float_format_components() {
+    assert_eq!(Format::float(1), Format::f32x1);
+    assert_eq!(Format::float(16), Format::f32x16);
+    for n in 1..17 {
+        assert_eq!(Format::float(n).component_count(), n);
+    }
+    assert_eq!(Format::u32x3.component_count(), 3);
+}
+
 #[repr(i32)]
 #[derive(Debug, Copy, Clone)]
 #[allow(non_camel_case_types)]
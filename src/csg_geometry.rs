@@ -0,0 +1,540 @@
+use std::f32;
+
+use cgmath::*;
+
+use common::*;
+use interaction::coordinate_system;
+use ray::*;
+use user_geometry::*;
+
+/// Constructive solid geometry trees of analytic solids. Hits report the depth first index
+/// of the leaf they're on as their `prim_id`, so use one geometry per tree when the tree
+/// itself needs to be identified
+pub type CsgGeometry = UserGeometry<Csg>;
+
+#[derive(Debug, Copy, Clone)]
+pub enum CsgSolid {
+    Sphere { center: Point3<f32>, radius: f32 },
+    Box { lower: Point3<f32>, upper: Point3<f32> },
+    /// Capped cylinder running from `base` to `base + axis`
+    Cylinder { base: Point3<f32>, axis: Vector3<f32>, radius: f32 },
+    /// Cone with a disc of `radius` at `base` and its apex at `base + axis`
+    Cone { base: Point3<f32>, axis: Vector3<f32>, radius: f32 },
+    /// Torus in the plane through `center` perpendicular to `axis`
+    Torus { center: Point3<f32>, axis: Vector3<f32>, major_radius: f32, minor_radius: f32 },
+}
+
+#[derive(Debug, Clone)]
+pub enum CsgNode {
+    Solid(CsgSolid),
+    Union(Box<CsgNode>, Box<CsgNode>),
+    Intersection(Box<CsgNode>, Box<CsgNode>),
+    Difference(Box<CsgNode>, Box<CsgNode>),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum CsgOp {
+    Union,
+    Intersection,
+    Difference,
+}
+
+/// Where a ray crosses the surface of a solid. `normal` points out of the solid
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Boundary {
+    pub(crate) t: f32,
+    pub(crate) normal: Vector3<f32>,
+    leaf: u32,
+}
+
+/// A range of the ray inside a solid
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Span {
+    pub(crate) enter: Boundary,
+    pub(crate) exit: Boundary,
+}
+
+impl Boundary {
+    fn new(t: f32, normal: Vector3<f32>) -> Self {
+        Boundary { t, normal, leaf: 0 }
+    }
+
+    fn unbounded(t: f32) -> Self {
+        Boundary::new(t, Vector3::zero())
+    }
+}
+
+impl Span {
+    fn new(enter: Boundary, exit: Boundary) -> Self {
+        Span { enter, exit }
+    }
+
+    fn everywhere() -> Self {
+        Span::new(Boundary::unbounded(f32::NEG_INFINITY), Boundary::unbounded(f32::INFINITY))
+    }
+
+    /// The overlap of two spans of convex solids
+    fn clip(&self, other: &Span) -> Option<Span> {
+        let enter = if other.enter.t > self.enter.t { other.enter } else { self.enter };
+        let exit = if other.exit.t < self.exit.t { other.exit } else { self.exit };
+        if enter.t <= exit.t { Some(Span::new(enter, exit)) } else { None }
+    }
+}
+
+impl CsgSolid {
+    pub fn bounds(&self) -> Bounds {
+        match *self {
+            CsgSolid::Sphere { center, radius } => {
+                let r = Vector3::new(radius, radius, radius);
+                Bounds::new(center - r, center + r)
+            }
+            CsgSolid::Box { lower, upper } => Bounds::new(lower, upper),
+            CsgSolid::Cylinder { base, axis, radius } => {
+                let extent = disc_extent(axis, radius);
+                let top = base + axis;
+                Bounds::new(base - extent, base + extent).union(&Bounds::new(top - extent, top + extent))
+            }
+            CsgSolid::Cone { base, axis, radius } => {
+                let extent = disc_extent(axis, radius);
+                let apex = base + axis;
+                Bounds::new(base - extent, base + extent).union(&Bounds::new(apex, apex))
+            }
+            CsgSolid::Torus { center, axis, major_radius, minor_radius } => {
+                let extent = disc_extent(axis, major_radius)
+                    + Vector3::new(minor_radius, minor_radius, minor_radius);
+                Bounds::new(center - extent, center + extent)
+            }
+        }
+    }
+
+    /// Ranges of the whole line through `ray` inside the solid in increasing order
+    fn spans(&self, ray: &Ray) -> Vec<Span> {
+        match *self {
+            CsgSolid::Sphere { center, radius } => sphere_span(center, radius, ray).into_iter().collect(),
+            CsgSolid::Box { lower, upper } => box_span(lower, upper, ray).into_iter().collect(),
+            CsgSolid::Cylinder { base, axis, radius } => cylinder_span(base, axis, radius, ray).into_iter().collect(),
+            CsgSolid::Cone { base, axis, radius } => cone_span(base, axis, radius, ray).into_iter().collect(),
+            CsgSolid::Torus { center, axis, major_radius, minor_radius } =>
+                torus_spans(center, axis, major_radius, minor_radius, ray),
+        }
+    }
+}
+
+impl CsgNode {
+    pub fn solid(solid: CsgSolid) -> Self {
+        CsgNode::Solid(solid)
+    }
+
+    pub fn union(self, other: CsgNode) -> Self {
+        CsgNode::Union(Box::new(self), Box::new(other))
+    }
+
+    pub fn intersection(self, other: CsgNode) -> Self {
+        CsgNode::Intersection(Box::new(self), Box::new(other))
+    }
+
+    pub fn difference(self, other: CsgNode) -> Self {
+        CsgNode::Difference(Box::new(self), Box::new(other))
+    }
+
+    pub fn leaf_count(&self) -> u32 {
+        match *self {
+            CsgNode::Solid(_) => 1,
+            CsgNode::Union(ref a, ref b) | CsgNode::Intersection(ref a, ref b)
+                | CsgNode::Difference(ref a, ref b) => a.leaf_count() + b.leaf_count(),
+        }
+    }
+
+    pub fn bounds(&self) -> Bounds {
+        match *self {
+            CsgNode::Solid(ref solid) => solid.bounds(),
+            CsgNode::Union(ref a, ref b) => a.bounds().union(&b.bounds()),
+            CsgNode::Intersection(ref a, ref b) => {
+                let (a, b) = (a.bounds(), b.bounds());
+                let lower = Point3::new(a.lower.x.max(b.lower.x), a.lower.y.max(b.lower.y), a.lower.z.max(b.lower.z));
+                let upper = Point3::new(a.upper.x.min(b.upper.x), a.upper.y.min(b.upper.y), a.upper.z.min(b.upper.z));
+                if lower.x <= upper.x && lower.y <= upper.y && lower.z <= upper.z {
+                    Bounds::new(lower, upper)
+                } else {
+                    Bounds::new(lower, lower)
+                }
+            }
+            CsgNode::Difference(ref a, _) => a.bounds(),
+        }
+    }
+
+    /// `next_leaf` is the id given to the first leaf of this node
+    fn spans(&self, ray: &Ray, next_leaf: &mut u32) -> Vec<Span> {
+        let (op, a, b) = match *self {
+            CsgNode::Solid(ref solid) => {
+                let leaf = *next_leaf;
+                *next_leaf += 1;
+                let mut spans = solid.spans(ray);
+                for span in spans.iter_mut() {
+                    span.enter.leaf = leaf;
+                    span.exit.leaf = leaf;
+                }
+                return spans;
+            }
+            CsgNode::Union(ref a, ref b) => (CsgOp::Union, a, b),
+            CsgNode::Intersection(ref a, ref b) => (CsgOp::Intersection, a, b),
+            CsgNode::Difference(ref a, ref b) => (CsgOp::Difference, a, b),
+        };
+        let a = a.spans(ray, next_leaf);
+        let b = b.spans(ray, next_leaf);
+        combine(op, &a, &b)
+    }
+}
+
+/// Merges the spans of two solids by sweeping along the ray
+pub(crate) fn combine(op: CsgOp, a: &[Span], b: &[Span]) -> Vec<Span> {
+    // (boundary, from b, entering)
+    let mut events = Vec::with_capacity(2 * (a.len() + b.len()));
+    // Degenerate solids and zero length directions give NaN distances, which bound nothing
+    let is_valid = |span: &&Span| !span.enter.t.is_nan() && !span.exit.t.is_nan();
+    for span in a.iter().filter(is_valid) {
+        events.push((span.enter, false, true));
+        events.push((span.exit, false, false));
+    }
+    for span in b.iter().filter(is_valid) {
+        events.push((span.enter, true, true));
+        events.push((span.exit, true, false));
+    }
+    events.sort_by(|x, y| x.0.t.total_cmp(&y.0.t));
+
+    let mut spans = Vec::new();
+    let (mut in_a, mut in_b, mut inside) = (false, false, false);
+    let mut enter = Boundary::unbounded(0.0);
+    for &(mut boundary, from_b, entering) in events.iter() {
+        if from_b {
+            in_b = entering;
+            if op == CsgOp::Difference {
+                // Surfaces carved out by b face into it
+                boundary.normal = -boundary.normal;
+            }
+        } else {
+            in_a = entering;
+        }
+        let now_inside = match op {
+            CsgOp::Union => in_a || in_b,
+            CsgOp::Intersection => in_a && in_b,
+            CsgOp::Difference => in_a && !in_b,
+        };
+        if now_inside && !inside {
+            enter = boundary;
+        } else if !now_inside && inside {
+            spans.push(Span::new(enter, boundary));
+        }
+        inside = now_inside;
+    }
+    spans
+}
+
+pub struct Csg {
+    root: CsgNode,
+    bounds: Bounds,
+}
+
+impl Csg {
+    pub fn new(root: CsgNode) -> Self {
+        let bounds = root.bounds();
+        Csg { root, bounds }
+    }
+
+    pub fn root(&self) -> &CsgNode {
+        &self.root
+    }
+}
+
+impl UserPrimitive for Csg {
+    fn intersect(&self, ray: &Ray) -> UserPrimHit {
+        let spans = self.root.spans(ray, &mut 0);
+        for span in spans.iter() {
+            for boundary in [span.enter, span.exit].iter() {
+                if ray.in_range(boundary.t) {
+                    return UserPrimHit::new(boundary.t, boundary.normal, Vector2::zero())
+                        .with_prim_id(boundary.leaf);
+                }
+            }
+        }
+        UserPrimHit::miss()
+    }
+
+    fn bounds(&self) -> Bounds {
+        self.bounds
+    }
+}
+
+/// Half extents of a disc of `radius` perpendicular to `axis`
+pub(crate) fn disc_extent(axis: Vector3<f32>, radius: f32) -> Vector3<f32> {
+    let n = axis.normalize();
+    let e = |c: f32| radius * (1.0 - c * c).max(0.0).sqrt();
+    Vector3::new(e(n.x), e(n.y), e(n.z))
+}
+
+/// Sorted roots of `a t^2 + 2 b t + c`
+fn solve_quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+    let discriminant = b * b - a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    // Avoids cancellation between -b and the root of the discriminant
+    let q = -(b + discriminant.sqrt().copysign(b));
+    if q == 0.0 {
+        return Some((0.0, 0.0));
+    }
+    let (t0, t1) = (q / a, c / q);
+    Some(if t0 <= t1 { (t0, t1) } else { (t1, t0) })
+}
+
+pub(crate) fn sphere_span(center: Point3<f32>, radius: f32, ray: &Ray) -> Option<Span> {
+    let v = ray.origin - center;
+    let (t0, t1) = solve_quadratic(ray.dir.magnitude2(), dot(v, ray.dir), v.magnitude2() - radius * radius)?;
+    let normal = |t: f32| (ray.point_at_dist(t) - center) / radius;
+    Some(Span::new(Boundary::new(t0, normal(t0)), Boundary::new(t1, normal(t1))))
+}
+
+pub(crate) fn box_span(lower: Point3<f32>, upper: Point3<f32>, ray: &Ray) -> Option<Span> {
+    let mut span = Span::everywhere();
+    for axis in 0..3 {
+        let d = ray.dir[axis];
+        let o = ray.origin[axis];
+        if d == 0.0 {
+            if o < lower[axis] || o > upper[axis] {
+                return None;
+            }
+            continue;
+        }
+        let mut normal = Vector3::zero();
+        normal[axis] = 1.0;
+        let t_lower = Boundary::new((lower[axis] - o) / d, -normal);
+        let t_upper = Boundary::new((upper[axis] - o) / d, normal);
+        let slab = if d > 0.0 { Span::new(t_lower, t_upper) } else { Span::new(t_upper, t_lower) };
+        span = span.clip(&slab)?;
+    }
+    Some(span)
+}
+
+/// The range of the ray between the planes through `base` and `base + axis`
+fn slab_span(base: Point3<f32>, axis: Vector3<f32>, ray: &Ray) -> Option<Span> {
+    let height = axis.magnitude();
+    let a = axis / height;
+    let s = dot(ray.origin - base, a);
+    let ds = dot(ray.dir, a);
+    if ds == 0.0 {
+        return if s >= 0.0 && s <= height { Some(Span::everywhere()) } else { None };
+    }
+    let bottom = Boundary::new(-s / ds, -a);
+    let top = Boundary::new((height - s) / ds, a);
+    Some(if ds > 0.0 { Span::new(bottom, top) } else { Span::new(top, bottom) })
+}
+
+pub(crate) fn cylinder_span(base: Point3<f32>, axis: Vector3<f32>, radius: f32, ray: &Ray) -> Option<Span> {
+    let a = axis.normalize();
+    let q = ray.origin - base;
+    let q_perp = q - a * dot(q, a);
+    let d_perp = ray.dir - a * dot(ray.dir, a);
+    let c = q_perp.magnitude2() - radius * radius;
+    let side = if d_perp.magnitude2() == 0.0 {
+        if c > 0.0 {
+            return None;
+        }
+        Span::everywhere()
+    } else {
+        let (t0, t1) = solve_quadratic(d_perp.magnitude2(), dot(q_perp, d_perp), c)?;
+        let normal = |t: f32| (q_perp + d_perp * t) / radius;
+        Span::new(Boundary::new(t0, normal(t0)), Boundary::new(t1, normal(t1)))
+    };
+    side.clip(&slab_span(base, axis, ray)?)
+}
+
+pub(crate) fn cone_span(base: Point3<f32>, axis: Vector3<f32>, radius: f32, ray: &Ray) -> Option<Span> {
+    let apex = base + axis;
+    let a = -axis.normalize();
+    let k2 = (radius / axis.magnitude()).powi(2);
+    let q = ray.origin - apex;
+    let (qa, da) = (dot(q, a), dot(ray.dir, a));
+    let q_perp = q - a * qa;
+    let d_perp = ray.dir - a * da;
+    // f(t) = |perpendicular|^2 - k^2 (distance along axis)^2 is negative inside the double cone
+    let fa = d_perp.magnitude2() - k2 * da * da;
+    let fb = dot(q_perp, d_perp) - k2 * qa * da;
+    let fc = q_perp.magnitude2() - k2 * qa * qa;
+    let normal = |t: f32| (q_perp + d_perp * t) - a * (k2 * (qa + da * t));
+    let boundary = |t: f32| if t.is_infinite() { Boundary::unbounded(t) } else { Boundary::new(t, normal(t)) };
+    let range = |t0: f32, t1: f32| Span::new(boundary(t0), boundary(t1));
+
+    let candidates = if fa.abs() < 1e-12 {
+        if fb.abs() < 1e-12 {
+            if fc > 0.0 { vec![] } else { vec![Span::everywhere()] }
+        } else {
+            let t = -fc / (2.0 * fb);
+            if fb > 0.0 { vec![range(f32::NEG_INFINITY, t)] } else { vec![range(t, f32::INFINITY)] }
+        }
+    } else {
+        match solve_quadratic(fa, fb, fc) {
+            Some((t0, t1)) if fa > 0.0 => vec![range(t0, t1)],
+            Some((t0, t1)) => vec![range(f32::NEG_INFINITY, t0), range(t1, f32::INFINITY)],
+            None if fa > 0.0 => vec![],
+            None => vec![Span::everywhere()],
+        }
+    };
+
+    // Only one nappe lies between the apex and base planes and it's convex
+    let slab = slab_span(base, axis, ray)?;
+    candidates.iter().filter_map(|span| span.clip(&slab)).next()
+}
+
+pub(crate) fn torus_spans(center: Point3<f32>, axis: Vector3<f32>, major_radius: f32, minor_radius: f32, ray: &Ray) -> Vec<Span> {
+    let outer = match sphere_span(center, major_radius + minor_radius, ray) {
+        Some(span) => span,
+        None => return vec![],
+    };
+    let n = axis.normalize();
+    let (t1, t2) = coordinate_system(n);
+    let to_local = |v: Vector3<f32>| Vector3::new(dot(v, t1) as f64, dot(v, t2) as f64, dot(v, n) as f64);
+    let o = to_local(ray.origin - center);
+    let d = to_local(ray.dir);
+    let (big_r2, small_r2) = ((major_radius as f64).powi(2), (minor_radius as f64).powi(2));
+
+    // (|p|^2 + R^2 - r^2)^2 - 4 R^2 (x^2 + y^2) along the ray, negative inside the torus
+    let s = d.magnitude2();
+    let e = dot(o, d);
+    let f = o.magnitude2() + big_r2 - small_r2;
+    let coeffs = [
+        f * f - 4.0 * big_r2 * (o.x * o.x + o.y * o.y),
+        4.0 * e * f - 8.0 * big_r2 * (o.x * d.x + o.y * d.y),
+        4.0 * e * e + 2.0 * s * f - 4.0 * big_r2 * (d.x * d.x + d.y * d.y),
+        4.0 * s * e,
+        s * s,
+    ];
+
+    let (lo, hi) = (outer.enter.t as f64, outer.exit.t as f64);
+    let mut ts = vec![lo];
+    ts.extend(poly_roots(&coeffs, lo, hi));
+    ts.push(hi);
+
+    let normal = |t: f64| {
+        let p = o + d * t;
+        let g = p * (p.magnitude2() + big_r2 - small_r2) - Vector3::new(p.x, p.y, 0.0) * (2.0 * big_r2);
+        (t1 * g.x as f32 + t2 * g.y as f32 + n * g.z as f32).normalize()
+    };
+
+    let mut spans: Vec<Span> = Vec::new();
+    for w in ts.windows(2) {
+        if eval_poly(&coeffs, 0.5 * (w[0] + w[1])) >= 0.0 {
+            continue;
+        }
+        let exit = Boundary::new(w[1] as f32, normal(w[1]));
+        match spans.last_mut() {
+            // Merge ranges split at a tangent root
+            Some(last) if last.exit.t == w[0] as f32 => last.exit = exit,
+            _ => spans.push(Span::new(Boundary::new(w[0] as f32, normal(w[0])), exit)),
+        }
+    }
+    spans
+}
+
+/// `coeffs` are in increasing order of degree
+fn eval_poly(coeffs: &[f64], x: f64) -> f64 {
+    coeffs.iter().rev().fold(0.0, |acc, &c| acc * x + c)
+}
+
+/// The real roots of a polynomial within `[lo, hi]` in increasing order. Roots of the
+/// derivative split the range into monotonic pieces which are then bisected
+fn poly_roots(coeffs: &[f64], lo: f64, hi: f64) -> Vec<f64> {
+    let mut degree = coeffs.len() - 1;
+    while degree > 0 && coeffs[degree] == 0.0 {
+        degree -= 1;
+    }
+    if degree == 0 {
+        return vec![];
+    }
+    if degree == 1 {
+        let root = -coeffs[0] / coeffs[1];
+        return if root >= lo && root <= hi { vec![root] } else { vec![] };
+    }
+
+    let derivative: Vec<f64> = (1..=degree).map(|i| coeffs[i] * i as f64).collect();
+    let mut ends = vec![lo];
+    ends.extend(poly_roots(&derivative, lo, hi));
+    ends.push(hi);
+
+    let mut roots: Vec<f64> = Vec::new();
+    for w in ends.windows(2) {
+        let (mut a, mut b) = (w[0], w[1]);
+        let (fa, fb) = (eval_poly(&coeffs[..=degree], a), eval_poly(&coeffs[..=degree], b));
+        let root = if fa == 0.0 {
+            a
+        } else if fb == 0.0 {
+            b
+        } else if fa.signum() == fb.signum() {
+            continue;
+        } else {
+            for _ in 0..100 {
+                let mid = 0.5 * (a + b);
+                if mid <= a || mid >= b {
+                    break;
+                }
+                if eval_poly(&coeffs[..=degree], mid).signum() == fa.signum() { a = mid } else { b = mid }
+            }
+            0.5 * (a + b)
+        };
+        if roots.last().map_or(true, |&last| last < root) {
+            roots.push(root);
+        }
+    }
+    roots
+}
+
+#[test]
+fn test_csg_difference() {
+    // A unit box with a sphere carved out of the top
+    let tree = CsgNode::solid(CsgSolid::Box { lower: Point3::new(-1.0, -1.0, -1.0), upper: Point3::new(1.0, 1.0, 1.0) })
+        .difference(CsgNode::solid(CsgSolid::Sphere { center: Point3::new(0.0, 1.0, 0.0), radius: 0.5 }));
+    let csg = Csg::new(tree);
+
+    let down = Vector3::new(0.0, -1.0, 0.0);
+    let hit = csg.intersect(&Ray::new(Point3::new(0.0, 5.0, 0.0), down, 0.0, f32::INFINITY));
+    assert!((hit.t - 4.5).abs() < 1e-5);
+    assert_eq!(hit.prim_id, Some(1));
+    // The carved surface faces into the sphere
+    assert!((hit.Ng - Vector3::unit_y()).magnitude() < 1e-5);
+
+    let hit = csg.intersect(&Ray::new(Point3::new(0.9, 5.0, 0.0), down, 0.0, f32::INFINITY));
+    assert!((hit.t - 4.0).abs() < 1e-5);
+    assert_eq!(hit.prim_id, Some(0));
+}
+
+#[test]
+fn test_csg_solids() {
+    let ray = Ray::new(Point3::new(0.0, 0.0, -5.0), Vector3::unit_z(), 0.0, f32::INFINITY);
+    let cylinder = CsgSolid::Cylinder { base: Point3::new(0.0, 0.0, -1.0), axis: Vector3::new(0.0, 0.0, 2.0), radius: 0.5 };
+    let spans = cylinder.spans(&ray);
+    assert_eq!(spans.len(), 1);
+    assert!((spans[0].enter.t - 4.0).abs() < 1e-5 && (spans[0].exit.t - 6.0).abs() < 1e-5);
+
+    let cone = CsgSolid::Cone { base: Point3::origin(), axis: Vector3::new(0.0, 0.0, 2.0), radius: 1.0 };
+    let spans = cone.spans(&ray);
+    assert_eq!(spans.len(), 1);
+    assert!((spans[0].enter.t - 5.0).abs() < 1e-5 && (spans[0].exit.t - 7.0).abs() < 1e-5);
+
+    let torus = CsgSolid::Torus { center: Point3::origin(), axis: Vector3::unit_y(), major_radius: 2.0, minor_radius: 0.5 };
+    let spans = torus.spans(&Ray::new(Point3::new(-5.0, 0.0, 0.0), Vector3::unit_x(), 0.0, f32::INFINITY));
+    assert_eq!(spans.len(), 2);
+    assert!((spans[0].enter.t - 2.5).abs() < 1e-4 && (spans[0].exit.t - 3.5).abs() < 1e-4);
+    assert!((spans[0].enter.normal + Vector3::unit_x()).magnitude() < 1e-3);
+}
+
+#[test]
+fn test_combine_nan_spans() {
+    let span = |enter: f32, exit: f32| Span {
+        enter: Boundary::new(enter, -Vector3::unit_z()),
+        exit: Boundary::new(exit, Vector3::unit_z()),
+    };
+    let a = [span(f32::NAN, 2.0), span(3.0, 4.0)];
+    let b = [span(1.0, f32::NAN), span(3.5, 5.0)];
+    let spans = combine(CsgOp::Union, &a, &b);
+    assert_eq!(spans.len(), 1);
+    assert_eq!((spans[0].enter.t, spans[0].exit.t), (3.0, 5.0));
+}
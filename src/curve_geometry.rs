@@ -0,0 +1,368 @@
+use cgmath::*;
+
+use sys::*;
+
+use common::*;
+use device::*;
+use geometry::*;
+use polygon_geometry::TriangleMesh;
+
+/// The spline basis of a curve's control points
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CurveBasis {
+    /// Cubic Bezier segments of 4 control points. Consecutive segments of a strand share an end point
+    Bezier,
+    /// Uniform cubic B-spline where every 4 consecutive control points form a segment
+    BSpline,
+    /// Cubic Hermite segments between 2 control points with a tangent at each
+    Hermite,
+}
+
+/// How the cross section of a curve is shaped
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CurveKind {
+    /// A swept circle
+    Round,
+    /// A flat ribbon which always faces the ray, for distant thin hair
+    Flat,
+    /// A flat ribbon oriented by the normal buffer
+    NormalOriented,
+}
+
+impl CurveBasis {
+    /// The index of the first control point of each segment of a strand with `count` control points
+    pub fn segment_starts(&self, count: u32) -> Vec<u32> {
+        match self {
+            CurveBasis::Bezier => (0..count.saturating_sub(1) / 3).map(|i| i * 3).collect(),
+            CurveBasis::BSpline => (0..count.saturating_sub(3)).collect(),
+            CurveBasis::Hermite => (0..count.saturating_sub(1)).collect(),
+        }
+    }
+
+    fn geometry_type(&self, kind: CurveKind) -> GeometryType {
+        match (self, kind) {
+            (CurveBasis::Bezier, CurveKind::Round) => GeometryType::RoundBezierCurve,
+            (CurveBasis::Bezier, CurveKind::Flat) => GeometryType::FlatBezierCurve,
+            (CurveBasis::Bezier, CurveKind::NormalOriented) => GeometryType::NormalOrientedBezierCurve,
+            (CurveBasis::BSpline, CurveKind::Round) => GeometryType::RoundBSplineCurve,
+            (CurveBasis::BSpline, CurveKind::Flat) => GeometryType::FlatBSplineCurve,
+            (CurveBasis::BSpline, CurveKind::NormalOriented) => GeometryType::NormalOrientedBSplineCurve,
+            (CurveBasis::Hermite, CurveKind::Round) => GeometryType::RoundHermiteCurve,
+            (CurveBasis::Hermite, CurveKind::Flat) => GeometryType::FlatHermiteCurve,
+            (CurveBasis::Hermite, CurveKind::NormalOriented) => GeometryType::NormalOrientedHermiteCurve,
+        }
+    }
+}
+
+#[test]
+fn test_curve_basis() {
+    assert_eq!(CurveBasis::Bezier.segment_starts(7), vec![0, 3]);
+    assert_eq!(CurveBasis::BSpline.segment_starts(6), vec![0, 1, 2]);
+    assert_eq!(CurveBasis::Hermite.segment_starts(3), vec![0, 1]);
+    for basis in [CurveBasis::Bezier, CurveBasis::BSpline, CurveBasis::Hermite].iter() {
+        assert!(basis.segment_starts(1).is_empty());
+    }
+
+    let geometry_type = |basis: CurveBasis, kind| -> i32 { basis.geometry_type(kind).into() };
+    assert_eq!(geometry_type(CurveBasis::Bezier, CurveKind::Round), RTC_GEOMETRY_TYPE_ROUND_BEZIER_CURVE);
+    assert_eq!(geometry_type(CurveBasis::Bezier, CurveKind::Flat), RTC_GEOMETRY_TYPE_FLAT_BEZIER_CURVE);
+    assert_eq!(geometry_type(CurveBasis::BSpline, CurveKind::NormalOriented), RTC_GEOMETRY_TYPE_NORMAL_ORIENTED_BSPLINE_CURVE);
+    assert_eq!(geometry_type(CurveBasis::Hermite, CurveKind::Flat), RTC_GEOMETRY_TYPE_FLAT_HERMITE_CURVE);
+    assert_eq!(geometry_type(CurveBasis::Hermite, CurveKind::Round), RTC_GEOMETRY_TYPE_ROUND_HERMITE_CURVE);
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct CurveVertex {
+    pub position: Point3<f32>,
+    pub radius: f32,
+}
+
+impl CurveVertex {
+    pub fn new(position: Point3<f32>, radius: f32) -> Self {
+        CurveVertex {
+            position,
+            radius,
+        }
+    }
+}
+
+/// Tangent of a Hermite curve at a control point, including the derivative of the radius
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct CurveTangent {
+    pub dir: Vector3<f32>,
+    pub radius: f32,
+}
+
+impl CurveTangent {
+    pub fn new(dir: Vector3<f32>, radius: f32) -> Self {
+        CurveTangent {
+            dir,
+            radius,
+        }
+    }
+}
+
+#[test]
+fn test_curve_buffer_formats() {
+    // Vertices and tangents are bound as Format::f32x4 and flags as Format::u8x1
+    assert_eq!(std::mem::size_of::<CurveVertex>(), 4 * std::mem::size_of::<f32>());
+    assert_eq!(offset_of!(CurveVertex, radius), 3 * std::mem::size_of::<f32>());
+    assert_eq!(std::mem::size_of::<CurveTangent>(), 4 * std::mem::size_of::<f32>());
+    assert_eq!(offset_of!(CurveTangent, radius), 3 * std::mem::size_of::<f32>());
+    assert_eq!(std::mem::size_of::<CurveFlags>(), 1);
+}
+
+bitflags! {
+    /// Per segment flags marking whether the segment connects to its neighbours in the strand
+    #[repr(C)]
+    pub struct CurveFlags: u8 {
+        const NEIGHBOR_LEFT = RTC_CURVE_FLAG_NEIGHBOR_LEFT as u8;
+        const NEIGHBOR_RIGHT = RTC_CURVE_FLAG_NEIGHBOR_RIGHT as u8;
+    }
+}
+
+/// Curves for hair and fur. Each index is the first control point of a segment and hits
+/// report the segment as `prim_id` and the curve parameter as `uv.x`
+pub struct CurveGeometry {
+    pub(crate) handle: GeometryHandle,
+    basis: CurveBasis,
+    kind: CurveKind,
+    pub indices: Vec<u32>,
+    pub vertices: Vec<CurveVertex>,
+    /// Required for `CurveKind::NormalOriented`, one per control point
+    pub normals: Option<Vec<Vector3<f32>>>,
+    /// Required for `CurveBasis::Hermite`, one per control point
+    pub tangents: Option<Vec<CurveTangent>>,
+    /// Optional flags for each segment, parallel to `indices`
+    pub flags: Option<Vec<CurveFlags>>,
+    /// Vertex attributes, bound to slots in the order they were added
+    pub attributes: Vec<VertexAttribute>,
+}
+
+impl CurveGeometry {
+    pub fn new(device: &Device, basis: CurveBasis, kind: CurveKind, indices: Vec<u32>, vertices: Vec<CurveVertex>) -> Self {
+        let handle = GeometryHandle::new(device, basis.geometry_type(kind));
+        CurveGeometry {
+            handle,
+            basis,
+            kind,
+            indices,
+            vertices,
+            normals: None,
+            tangents: None,
+            flags: None,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Builds the segments from a list of strands, each given as consecutive control points
+    pub fn from_strands(device: &Device, basis: CurveBasis, kind: CurveKind, strands: &[Vec<CurveVertex>]) -> Self {
+        let mut indices = Vec::new();
+        let mut vertices = Vec::new();
+        for strand in strands {
+            let start = vertices.len() as u32;
+            indices.extend(basis.segment_starts(strand.len() as u32).iter().map(|i| start + i));
+            vertices.extend_from_slice(strand);
+        }
+        CurveGeometry::new(device, basis, kind, indices, vertices)
+    }
+
+    pub fn basis(&self) -> CurveBasis {
+        self.basis
+    }
+
+    pub fn kind(&self) -> CurveKind {
+        self.kind
+    }
+
+    pub fn set_normal_buffer(&mut self, buf: Vec<Vector3<f32>>) {
+        self.normals = Some(buf);
+    }
+
+    pub fn set_tangent_buffer(&mut self, buf: Vec<CurveTangent>) {
+        self.tangents = Some(buf);
+    }
+
+    pub fn set_flags_buffer(&mut self, buf: Vec<CurveFlags>) {
+        self.flags = Some(buf);
+    }
+
+    /// Adds a named vertex attribute and returns the slot it will be bound to.
+    /// An existing attribute with the same name is replaced and keeps its slot
+    pub fn add_vertex_attribute(&mut self, attribute: VertexAttribute) -> u32 {
+        assert!(attribute.vertex_count() == self.vertices.len(), "Attribute must have a value for every vertex");
+        insert_vertex_attribute(&mut self.attributes, attribute)
+    }
+
+    pub fn get_vertex_attribute(&self, name: &str) -> Option<&VertexAttribute> {
+        self.attributes.iter().find(|a| a.name == name)
+    }
+}
+
+impl Geometry for CurveGeometry {
+    fn handle(&self) -> &GeometryHandle {
+        &self.handle
+    }
+
+    fn handle_mut(&mut self) -> &mut GeometryHandle {
+        &mut self.handle
+    }
+
+    fn bind_buffers(&mut self) {
+        assert!(self.kind != CurveKind::NormalOriented || self.normals.is_some(), "Normal oriented curves require normals");
+        assert!(self.basis != CurveBasis::Hermite || self.tangents.is_some(), "Hermite curves require tangents");
+
+        self.vertices.reserve(1);
+
+        unsafe {
+            self.handle.bind_shared_geometry_buffer(&self.indices, BufferType::Index, Format::u32x1, 0, 0);
+            self.handle.bind_shared_geometry_buffer(&self.vertices, BufferType::Vertex, Format::f32x4, 0, 0);
+
+            if let Some(ref mut normals) = self.normals {
+                debug_assert!(normals.len() == self.vertices.len());
+                normals.reserve(1);
+                self.handle.bind_shared_geometry_buffer(normals, BufferType::Normal, Format::f32x3, 0, 0);
+            }
+            if let Some(ref mut tangents) = self.tangents {
+                debug_assert!(tangents.len() == self.vertices.len());
+                self.handle.bind_shared_geometry_buffer(tangents, BufferType::Tangent, Format::f32x4, 0, 0);
+            }
+            if let Some(ref mut flags) = self.flags {
+                debug_assert!(flags.len() == self.indices.len());
+                self.handle.bind_shared_geometry_buffer(flags, BufferType::Flags, Format::u8x1, 0, 0);
+            }
+
+            bind_vertex_attributes(&mut self.handle, &mut self.attributes);
+        }
+    }
+
+    fn vertex_attribute(&self, name: &str) -> Option<AttributeSlot> {
+        find_vertex_attribute(&self.attributes, name)
+    }
+}
+
+/// Straight line segments rendered as flat ribbons facing the ray. Each index is the
+/// first of two consecutive vertices and hits report the segment as `prim_id`
+pub struct LineSegments {
+    pub(crate) handle: GeometryHandle,
+    pub indices: Vec<u32>,
+    pub vertices: Vec<CurveVertex>,
+    /// Optional flags for each segment, parallel to `indices`
+    pub flags: Option<Vec<CurveFlags>>,
+    /// Vertex attributes, bound to slots in the order they were added
+    pub attributes: Vec<VertexAttribute>,
+}
+
+impl LineSegments {
+    pub fn new(device: &Device, indices: Vec<u32>, vertices: Vec<CurveVertex>) -> Self {
+        let handle = GeometryHandle::new(device, GeometryType::FlatLinearCurve);
+        LineSegments {
+            handle,
+            indices,
+            vertices,
+            flags: None,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Creates a segment between every pair of consecutive points of each polyline
+    pub fn from_polylines(device: &Device, polylines: &[Vec<Point3<f32>>], radius: f32) -> Self {
+        let mut indices = Vec::new();
+        let mut vertices = Vec::new();
+        for line in polylines {
+            let start = vertices.len() as u32;
+            indices.extend((0..line.len().saturating_sub(1) as u32).map(|i| start + i));
+            vertices.extend(line.iter().map(|&p| CurveVertex::new(p, radius)));
+        }
+        LineSegments::new(device, indices, vertices)
+    }
+
+    /// Creates a segment for every unique edge of a triangle mesh, e.g. for a wireframe overlay
+    pub fn from_mesh_edges(device: &Device, mesh: &TriangleMesh, radius: f32) -> Self {
+        let mut edges: Vec<(u32, u32)> = mesh.indices.iter()
+            .flat_map(|t| [(t.v0, t.v1), (t.v1, t.v2), (t.v2, t.v0)])
+            .map(|(a, b)| if a < b { (a, b) } else { (b, a) })
+            .collect();
+        edges.sort();
+        edges.dedup();
+
+        // Segments use consecutive vertices so each edge needs its own pair
+        let mut vertices = Vec::with_capacity(edges.len() * 2);
+        for &(a, b) in edges.iter() {
+            vertices.push(CurveVertex::new(mesh.vertices[a as usize], radius));
+            vertices.push(CurveVertex::new(mesh.vertices[b as usize], radius));
+        }
+        let indices = (0..edges.len() as u32).map(|i| i * 2).collect();
+        LineSegments::new(device, indices, vertices)
+    }
+
+    pub fn set_flags_buffer(&mut self, buf: Vec<CurveFlags>) {
+        self.flags = Some(buf);
+    }
+
+    /// Adds a named vertex attribute and returns the slot it will be bound to.
+    /// An existing attribute with the same name is replaced and keeps its slot
+    pub fn add_vertex_attribute(&mut self, attribute: VertexAttribute) -> u32 {
+        assert!(attribute.vertex_count() == self.vertices.len(), "Attribute must have a value for every vertex");
+        insert_vertex_attribute(&mut self.attributes, attribute)
+    }
+
+    pub fn get_vertex_attribute(&self, name: &str) -> Option<&VertexAttribute> {
+        self.attributes.iter().find(|a| a.name == name)
+    }
+}
+
+#[test]
+fn test_line_segments_from_mesh_edges() {
+    use polygon_geometry::Triangle;
+    let device = Device::new();
+    let vertices = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(1.0, 1.0, 0.0),
+        Point3::new(0.0, 1.0, 0.0)];
+    // Two triangles sharing the diagonal from vertex 0 to 2
+    let mesh = TriangleMesh::new(&device, vec![Triangle::new(0, 1, 2), Triangle::new(0, 2, 3)], vertices);
+    let lines = LineSegments::from_mesh_edges(&device, &mesh, 0.01);
+    assert_eq!(lines.indices, vec![0, 2, 4, 6, 8]);
+    assert_eq!(lines.vertices.len(), 10);
+    // Edges are sorted, so the shared diagonal comes second
+    assert_eq!(lines.vertices[2].position, mesh.vertices[0]);
+    assert_eq!(lines.vertices[3].position, mesh.vertices[2]);
+
+    let polylines = vec![vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(2.0, 0.0, 0.0)],
+        vec![Point3::new(0.0, 1.0, 0.0)], vec![Point3::new(0.0, 2.0, 0.0), Point3::new(1.0, 2.0, 0.0)]];
+    let lines = LineSegments::from_polylines(&device, &polylines, 0.01);
+    // The single point polyline has no segments but its vertex is kept
+    assert_eq!(lines.indices, vec![0, 1, 4]);
+    assert_eq!(lines.vertices.len(), 6);
+}
+
+impl Geometry for LineSegments {
+    fn handle(&self) -> &GeometryHandle {
+        &self.handle
+    }
+
+    fn handle_mut(&mut self) -> &mut GeometryHandle {
+        &mut self.handle
+    }
+
+    fn bind_buffers(&mut self) {
+        self.vertices.reserve(1);
+
+        unsafe {
+            self.handle.bind_shared_geometry_buffer(&self.indices, BufferType::Index, Format::u32x1, 0, 0);
+            self.handle.bind_shared_geometry_buffer(&self.vertices, BufferType::Vertex, Format::f32x4, 0, 0);
+
+            if let Some(ref mut flags) = self.flags {
+                debug_assert!(flags.len() == self.indices.len());
+                self.handle.bind_shared_geometry_buffer(flags, BufferType::Flags, Format::u8x1, 0, 0);
+            }
+
+            bind_vertex_attributes(&mut self.handle, &mut self.attributes);
+        }
+    }
+
+    fn vertex_attribute(&self, name: &str) -> Option<AttributeSlot> {
+        find_vertex_attribute(&self.attributes, name)
+    }
+}
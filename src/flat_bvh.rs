@@ -0,0 +1,440 @@
+use std::f32;
+use std::io::{self, Read, Write};
+
+use cgmath::*;
+
+use bvh::*;
+use common::*;
+use device::Device;
+use error::ErrorKind;
+use polygon_geometry::*;
+use ray::*;
+
+/// First bytes of a serialized flat BVH
+const FLAT_BVH_MAGIC: &[u8; 4] = b"EBVH";
+
+/// A BVH built by Embree, copied out of its arena before flattening
+enum TreeNode {
+    Inner(Vec<(Bounds, TreeNode)>),
+    Leaf(Vec<u32>),
+}
+
+enum BuildNode {
+    Inner { children: Vec<NodeId>, bounds: Vec<Bounds> },
+    Leaf(Vec<u32>),
+}
+
+fn copy_tree(bvh: &Bvh<BuildNode>, node: &BuildNode) -> TreeNode {
+    match *node {
+        BuildNode::Inner { ref children, ref bounds } => {
+            TreeNode::Inner(bounds.iter().cloned().zip(children.iter().map(|&c| copy_tree(bvh, bvh.node(c)))).collect())
+        }
+        BuildNode::Leaf(ref prims) => TreeNode::Leaf(prims.clone()),
+    }
+}
+
+fn triangle_bounds(mesh: &TriangleMesh, prim: u32) -> Bounds {
+    let t = mesh.indices[prim as usize];
+    Bounds::from_points([t.v0, t.v1, t.v2].iter().map(|&v| mesh.vertices[v as usize]))
+}
+
+/// Builds a tree over the triangles of a mesh, always with an inner node at the root
+fn build_tree(device: &Device, builder: &BvhBuilder, width: u32, mesh: &TriangleMesh) -> Result<TreeNode, ErrorKind> {
+    let primitives: Vec<BuildPrimitive> = (0..mesh.indices.len() as u32).map(|i| {
+        BuildPrimitive::new(&triangle_bounds(mesh, i), 0, i)
+    }).collect();
+
+    let mut builder = *builder;
+    builder.max_branching_factor = width;
+    let bvh = builder.build(device, &primitives,
+        |n| BuildNode::Inner { children: Vec::with_capacity(n), bounds: Vec::with_capacity(n) },
+        |node, c| if let BuildNode::Inner { ref mut children, .. } = *node { *children = c },
+        |node, b| if let BuildNode::Inner { ref mut bounds, .. } = *node { *bounds = b.to_vec() },
+        |prims| BuildNode::Leaf(prims.iter().map(|p| p.prim_id).collect()))?;
+
+    Ok(match bvh.root().map(|root| copy_tree(&bvh, root)) {
+        Some(TreeNode::Inner(children)) => TreeNode::Inner(children),
+        Some(leaf) => {
+            let bounds = primitives.iter().map(|p| p.bounds()).reduce(|a, b| a.union(&b)).unwrap();
+            TreeNode::Inner(vec![(bounds, leaf)])
+        }
+        None => TreeNode::Inner(Vec::new()),
+    })
+}
+
+fn write_u32s<W: Write>(w: &mut W, values: &[u32]) -> io::Result<()> {
+    for v in values.iter() {
+        w.write_all(&v.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn write_f32s<W: Write>(w: &mut W, values: &[f32]) -> io::Result<()> {
+    for v in values.iter() {
+        w.write_all(&v.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u32s<R: Read>(r: &mut R, values: &mut [u32]) -> io::Result<()> {
+    let mut bytes = [0; 4];
+    for v in values.iter_mut() {
+        r.read_exact(&mut bytes)?;
+        *v = u32::from_le_bytes(bytes);
+    }
+    Ok(())
+}
+
+/// Reads `count` values, growing the vector only as they arrive so a corrupt count can't
+/// allocate more than the data holds
+fn read_u32_vec<R: Read>(r: &mut R, count: u32) -> io::Result<Vec<u32>> {
+    const CHUNK: usize = 4096;
+    let count = count as usize;
+    let mut values = Vec::new();
+    while values.len() < count {
+        let start = values.len();
+        values.resize(start + (count - start).min(CHUNK), 0);
+        read_u32s(r, &mut values[start..])?;
+    }
+    Ok(values)
+}
+
+fn read_f32s<R: Read>(r: &mut R, values: &mut [f32]) -> io::Result<()> {
+    let mut bytes = [0; 4];
+    for v in values.iter_mut() {
+        r.read_exact(&mut bytes)?;
+        *v = f32::from_le_bytes(bytes);
+    }
+    Ok(())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+macro_rules! flat_bvh_def {
+    ($bvhname:ident, $nodename:ident, $width:expr) => (
+/// A node holding the bounds of up to its width of children, as x, y and z rows.
+/// Unused slots have a child of `INVALID_ID` and empty bounds
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct $nodename {
+    pub lower: [[f32; $width]; 3],
+    pub upper: [[f32; $width]; 3],
+    /// The index of inner children in `nodes`, or of the first primitive of leaf children in `prim_order`
+    pub children: [u32; $width],
+    /// Number of primitives in leaf children, 0 for inner children
+    pub counts: [u32; $width],
+}
+
+impl $nodename {
+    fn empty() -> Self {
+        $nodename {
+            lower: [[f32::INFINITY; $width]; 3],
+            upper: [[f32::NEG_INFINITY; $width]; 3],
+            children: [INVALID_ID; $width],
+            counts: [0; $width],
+        }
+    }
+
+    pub fn child_bounds(&self, slot: usize) -> Bounds {
+        Bounds::new(Point3::new(self.lower[0][slot], self.lower[1][slot], self.lower[2][slot]),
+            Point3::new(self.upper[0][slot], self.upper[1][slot], self.upper[2][slot]))
+    }
+}
+
+/// A BVH stored as an array of nodes in depth first order, with the root first, for
+/// uploading to other tracers. Leaves are ranges of `prim_order`, which holds triangle indices.
+/// Serialized as little endian: the bytes "EBVH", the width, node count and primitive
+/// count as u32, then each node's fields in order, then `prim_order`
+#[derive(Debug, Clone, PartialEq)]
+pub struct $bvhname {
+    pub nodes: Vec<$nodename>,
+    pub prim_order: Vec<u32>,
+}
+
+impl $bvhname {
+    pub const WIDTH: usize = $width;
+
+    /// Builds over the triangles of a mesh. The builder's branching factor is replaced by the width
+    pub fn build(device: &Device, builder: &BvhBuilder, mesh: &TriangleMesh) -> Result<Self, ErrorKind> {
+        let tree = build_tree(device, builder, $width, mesh)?;
+        Ok(Self::from_tree(&tree))
+    }
+
+    fn from_tree(root: &TreeNode) -> Self {
+        let mut bvh = $bvhname { nodes: Vec::new(), prim_order: Vec::new() };
+        match *root {
+            TreeNode::Inner(ref children) => bvh.push_node(children),
+            TreeNode::Leaf(_) => unreachable!("The root is always an inner node"),
+        };
+        bvh
+    }
+
+    fn push_node(&mut self, children: &[(Bounds, TreeNode)]) -> u32 {
+        assert!(children.len() <= $width, "Node has more children than the BVH's width");
+        let index = self.nodes.len();
+        self.nodes.push($nodename::empty());
+        for (slot, &(ref bounds, ref child)) in children.iter().enumerate() {
+            let (child, count) = match *child {
+                TreeNode::Inner(ref grandchildren) => (self.push_node(grandchildren), 0),
+                TreeNode::Leaf(ref prims) if prims.is_empty() => continue,
+                TreeNode::Leaf(ref prims) => {
+                    let first = self.prim_order.len() as u32;
+                    self.prim_order.extend_from_slice(prims);
+                    (first, prims.len() as u32)
+                }
+            };
+            let node = &mut self.nodes[index];
+            for axis in 0..3 {
+                node.lower[axis][slot] = bounds.lower[axis];
+                node.upper[axis][slot] = bounds.upper[axis];
+            }
+            node.children[slot] = child;
+            node.counts[slot] = count;
+        }
+        index as u32
+    }
+
+    /// Reference traversal, which calls `intersect` with every primitive whose leaf the
+    /// ray reaches. Hits it returns within the ray's range shorten the ray.
+    /// Returns whether anything was hit
+    pub fn traverse<F>(&self, ray: &mut Ray, mut intersect: F) -> bool
+        where F: FnMut(u32, &Ray) -> Option<f32> {
+        let mut hit = false;
+        let mut stack = if self.nodes.is_empty() { Vec::new() } else { vec![0] };
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index as usize];
+            for slot in 0..$width {
+                if node.children[slot] == INVALID_ID || !node.child_bounds(slot).hits(ray) {
+                    continue;
+                }
+                if node.counts[slot] == 0 {
+                    stack.push(node.children[slot]);
+                    continue;
+                }
+                let first = node.children[slot] as usize;
+                for &prim in self.prim_order[first..first + node.counts[slot] as usize].iter() {
+                    if let Some(t) = intersect(prim, ray) {
+                        if ray.in_range(t) {
+                            ray.tfar = t;
+                            hit = true;
+                        }
+                    }
+                }
+            }
+        }
+        hit
+    }
+
+    /// Intersects the mesh the BVH was built over, filling in the hit as Embree would
+    /// for a scene holding only the mesh. Fails with `InvalidArgument` if the BVH was
+    /// built over a different number of triangles
+    pub fn intersect_mesh(&self, mesh: &TriangleMesh, rayhit: &mut RayHit) -> Result<(), ErrorKind> {
+        if self.prim_order.len() != mesh.indices.len() {
+            return Err(ErrorKind::InvalidArgument);
+        }
+        let mut closest = None;
+        self.traverse(&mut rayhit.ray, |prim, ray| {
+            let t = mesh.indices[prim as usize];
+            let points = [mesh.vertices[t.v0 as usize], mesh.vertices[t.v1 as usize], mesh.vertices[t.v2 as usize]];
+            triangle_hit(&points, ray).map(|hit| {
+                closest = Some((prim, hit.Ng, hit.uv));
+                hit.t
+            })
+        });
+        if let Some((prim, normal, uv)) = closest {
+            rayhit.hit.Ng = normal;
+            rayhit.hit.uv = uv;
+            rayhit.hit.prim_id = GeomID::new(prim);
+            rayhit.hit.geom_id = GeomID::new(0);
+        }
+        Ok(())
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(FLAT_BVH_MAGIC)?;
+        write_u32s(w, &[$width, self.nodes.len() as u32, self.prim_order.len() as u32])?;
+        for node in self.nodes.iter() {
+            for row in node.lower.iter().chain(node.upper.iter()) {
+                write_f32s(w, row)?;
+            }
+            write_u32s(w, &node.children)?;
+            write_u32s(w, &node.counts)?;
+        }
+        write_u32s(w, &self.prim_order)
+    }
+
+    pub fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != FLAT_BVH_MAGIC {
+            return Err(invalid_data("Not a serialized BVH"));
+        }
+        let mut header = [0; 3];
+        read_u32s(r, &mut header)?;
+        if header[0] != $width {
+            return Err(invalid_data("Serialized BVH has a different width"));
+        }
+
+        let mut nodes = Vec::new();
+        for _ in 0..header[1] {
+            let mut node = $nodename::empty();
+            for row in node.lower.iter_mut().chain(node.upper.iter_mut()) {
+                read_f32s(r, row)?;
+            }
+            read_u32s(r, &mut node.children)?;
+            read_u32s(r, &mut node.counts)?;
+            nodes.push(node);
+        }
+        let prim_order = read_u32_vec(r, header[2])?;
+        if prim_order.iter().any(|&p| p as usize >= prim_order.len()) {
+            return Err(invalid_data("Serialized BVH has a primitive out of range"));
+        }
+
+        // Inner children come after their parent in depth first order, so traversal ends
+        for (index, node) in nodes.iter().enumerate() {
+            for slot in 0..$width {
+                let (child, count) = (node.children[slot], node.counts[slot]);
+                let valid = if child == INVALID_ID {
+                    true
+                } else if count == 0 {
+                    child as usize > index && (child as usize) < nodes.len()
+                } else {
+                    child as usize + count as usize <= prim_order.len()
+                };
+                if !valid {
+                    return Err(invalid_data("Serialized BVH has a child out of range"));
+                }
+            }
+        }
+        Ok($bvhname { nodes, prim_order })
+    }
+}
+)}
+
+flat_bvh_def!(FlatBvh2, FlatBvh2Node, 2);
+flat_bvh_def!(FlatBvh4, FlatBvh4Node, 4);
+
+#[cfg(test)]
+fn grid_mesh(device: &Device, n: u32) -> TriangleMesh {
+    let vertices = (0..(n + 1) * (n + 1)).map(|i| {
+        let (x, y) = ((i % (n + 1)) as f32, (i / (n + 1)) as f32);
+        Point3::new(x, y, (x * 0.7).sin() * (y * 0.4).cos())
+    }).collect();
+    let mut indices = Vec::new();
+    for y in 0..n {
+        for x in 0..n {
+            let v = y * (n + 1) + x;
+            indices.push(Triangle::new(v, v + 1, v + n + 2));
+            indices.push(Triangle::new(v, v + n + 2, v + n + 1));
+        }
+    }
+    TriangleMesh::new(device, indices, vertices)
+}
+
+#[test]
+fn test_flat_bvh_traversal() {
+    // A median split tree in place of one built by Embree
+    fn split(mesh: &TriangleMesh, mut prims: Vec<u32>, width: usize) -> (Bounds, TreeNode) {
+        let tri_bounds = |p: u32| triangle_bounds(mesh, p);
+        let bounds = prims.iter().map(|&p| tri_bounds(p)).reduce(|a, b| a.union(&b)).unwrap();
+        if prims.len() <= 3 {
+            return (bounds, TreeNode::Leaf(prims));
+        }
+        let axis = if bounds.upper.x - bounds.lower.x > bounds.upper.y - bounds.lower.y { 0 } else { 1 };
+        prims.sort_by(|&a, &b| tri_bounds(a).lower[axis].partial_cmp(&tri_bounds(b).lower[axis]).unwrap());
+        let chunk = (prims.len() + width - 1) / width;
+        (bounds, TreeNode::Inner(prims.chunks(chunk).map(|c| split(mesh, c.to_vec(), width)).collect()))
+    }
+
+    let device = Device::new();
+    let mesh = grid_mesh(&device, 12);
+    let all: Vec<u32> = (0..mesh.indices.len() as u32).collect();
+    let bvh2 = FlatBvh2::from_tree(&TreeNode::Inner(vec![split(&mesh, all.clone(), 2)]));
+    let bvh4 = FlatBvh4::from_tree(&TreeNode::Inner(vec![split(&mesh, all.clone(), 4)]));
+    let mut order = bvh4.prim_order.clone();
+    order.sort();
+    assert_eq!(order, all);
+
+    for i in 0..50 {
+        let origin = Point3::new((i % 7) as f32 * 1.9, (i / 7) as f32 * 1.7, 4.0);
+        let ray = Ray::new(origin, Vector3::new(0.1 * (i % 3) as f32, 0.05, -1.0), 0.0, f32::INFINITY);
+
+        let mut brute_force = RayHit { ray, hit: Hit::empty() };
+        for (prim, t) in mesh.indices.iter().enumerate() {
+            let points = [mesh.vertices[t.v0 as usize], mesh.vertices[t.v1 as usize], mesh.vertices[t.v2 as usize]];
+            if let Some(hit) = triangle_hit(&points, &brute_force.ray) {
+                brute_force.ray.tfar = hit.t;
+                brute_force.hit.prim_id = GeomID::new(prim as u32);
+            }
+        }
+        let mut traced2 = RayHit { ray, hit: Hit::empty() };
+        bvh2.intersect_mesh(&mesh, &mut traced2).unwrap();
+        let mut traced4 = RayHit { ray, hit: Hit::empty() };
+        bvh4.intersect_mesh(&mesh, &mut traced4).unwrap();
+        for traced in [traced2, traced4].iter() {
+            assert_eq!(traced.hit.prim_id.id, brute_force.hit.prim_id.id, "ray {}", i);
+            assert_eq!(traced.ray.tfar, brute_force.ray.tfar);
+        }
+    }
+    // A mesh the BVH wasn't built over
+    let mut rayhit = RayHit { ray: Ray::new(Point3::new(0.5, 0.5, 4.0), -Vector3::unit_z(), 0.0, f32::INFINITY), hit: Hit::empty() };
+    assert_eq!(bvh4.intersect_mesh(&grid_mesh(&device, 2), &mut rayhit), Err(ErrorKind::InvalidArgument));
+
+    let mut bytes = Vec::new();
+    bvh4.write(&mut bytes).unwrap();
+    assert_eq!(FlatBvh4::read(&mut &bytes[..]).unwrap(), bvh4);
+    assert!(FlatBvh2::read(&mut &bytes[..]).is_err());
+}
+
+#[test]
+fn test_flat_bvh_against_embree() {
+    use scene::SceneBuilder;
+
+    let device = Device::new();
+    let bvh = FlatBvh4::build(&device, &BvhBuilder::new(), &grid_mesh(&device, 20)).unwrap();
+    let mut scene = SceneBuilder::new(&device);
+    scene.attach(grid_mesh(&device, 20));
+    let scene = scene.build();
+    let mesh = grid_mesh(&device, 20);
+
+    for i in 0..100 {
+        let origin = Point3::new((i % 10) as f32 * 2.1, (i / 10) as f32 * 1.9, 3.0);
+        let ray = Ray::new(origin, Vector3::new(0.2, -0.1, -1.0), 0.0, f32::INFINITY);
+        let mut embree = RayHit { ray, hit: Hit::empty() };
+        scene.intersect(&mut embree);
+        let mut flat = RayHit { ray, hit: Hit::empty() };
+        bvh.intersect_mesh(&mesh, &mut flat).unwrap();
+        assert_eq!(flat.hit.is_hit(), embree.hit.is_hit());
+        if embree.hit.is_hit() {
+            assert_eq!(flat.hit.prim_id.id, embree.hit.prim_id.id);
+            assert!((flat.ray.tfar - embree.ray.tfar).abs() < 1e-4);
+        }
+    }
+}
+
+#[test]
+fn test_flat_bvh_read_corrupt() {
+    let b = Bounds::new(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 1.0, 1.0));
+    let bvh = FlatBvh2::from_tree(&TreeNode::Inner(vec![(b, TreeNode::Leaf(vec![0, 1])),
+        (b, TreeNode::Inner(vec![(b, TreeNode::Leaf(vec![2]))]))]));
+    let mut bytes = Vec::new();
+    bvh.write(&mut bytes).unwrap();
+    assert_eq!(FlatBvh2::read(&mut &bytes[..]).unwrap(), bvh);
+
+    // The header is 16 bytes and each node 64, with the children at 48 and counts at 56
+    let corrupt = |offset: usize, value: u32| {
+        let mut bytes = bytes.clone();
+        bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        FlatBvh2::read(&mut &bytes[..]).unwrap_err().kind()
+    };
+    assert_eq!(corrupt(8, u32::MAX), io::ErrorKind::UnexpectedEof);
+    assert_eq!(corrupt(12, u32::MAX), io::ErrorKind::UnexpectedEof);
+    // An inner child past the end, and one pointing back at its parent
+    assert_eq!(corrupt(16 + 52, 2), io::ErrorKind::InvalidData);
+    assert_eq!(corrupt(16 + 52, 0), io::ErrorKind::InvalidData);
+    // A leaf running past the end of prim_order, and a primitive past its length
+    assert_eq!(corrupt(16 + 56, 4), io::ErrorKind::InvalidData);
+    assert_eq!(corrupt(16 + 128, 3), io::ErrorKind::InvalidData);
+}
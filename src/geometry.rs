@@ -1,13 +1,20 @@
+use std::any::{Any, TypeId};
 use std::mem;
+use std::ptr;
 use std::ffi::c_void;
+use std::slice;
+use std::sync::Arc;
 use std::u32;
 
+use cgmath::*;
+
 use sys::*;
 
 use device::Device;
 use common::*;
+use ray::*;
 
-pub trait Geometry: Send + Sync + 'static {
+pub trait Geometry: Any + Send + Sync + 'static {
     fn handle(&self) -> &GeometryHandle;
     fn handle_mut(&mut self) -> &mut GeometryHandle;
 
@@ -18,27 +25,191 @@ pub trait Geometry: Send + Sync + 'static {
     fn set_geom_id(&mut self, _id: u32) {}
 
     fn bind_buffers(&mut self);
+
+    /// Looks up a named vertex attribute so it can be interpolated at a hit
+    fn vertex_attribute(&self, _name: &str) -> Option<AttributeSlot> {
+        None
+    }
+
+    /// Maps the primitive index Embree reports to the `Hit::prim_id` given to users, for
+    /// geometry which splits its primitives up internally. Only hits returned from a scene
+    /// query are mapped, not those seen by filter functions or made through an instance
+    fn prim_id_map(&self) -> Option<&[u32]> {
+        None
+    }
+
+    /// Interpolates a named vertex attribute at the hit coordinates `uv` of a primitive
+    fn interpolate_attribute(&self, name: &str, prim_id: u32, uv: Vector2<f32>) -> Option<Vec<f32>> {
+        let attribute = self.vertex_attribute(name)?;
+        let mut values = vec![0.0; attribute.format.component_count() as usize];
+        self.handle().interpolate(BufferType::VertexAttribute, attribute.slot, prim_id, uv, &mut values);
+        Some(values)
+    }
+}
+
+impl dyn Geometry {
+    pub fn is<T: Geometry>(&self) -> bool {
+        Any::type_id(self) == TypeId::of::<T>()
+    }
+
+    pub fn downcast_ref<T: Geometry>(&self) -> Option<&T> {
+        if self.is::<T>() {
+            unsafe { Some(&*(self as *const dyn Geometry as *const T)) }
+        } else {
+            None
+        }
+    }
+
+    pub fn downcast_mut<T: Geometry>(&mut self) -> Option<&mut T> {
+        if self.is::<T>() {
+            unsafe { Some(&mut *(self as *mut dyn Geometry as *mut T)) }
+        } else {
+            None
+        }
+    }
+}
+
+/// Maximum number of vertex attribute slots Embree supports on a geometry
+pub const MAX_VERTEX_ATTRIBUTES: u32 = 16;
+
+/// Location and layout of a vertex attribute bound to a geometry
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AttributeSlot {
+    pub slot: u32,
+    pub format: Format,
 }
 
+/// A named per-vertex attribute stored as a flat array of floats.
+/// Each vertex uses `format.component_count()` consecutive values
+#[derive(Debug, Clone)]
+pub struct VertexAttribute {
+    pub name: String,
+    pub format: Format,
+    pub data: Vec<f32>,
+}
+
+impl VertexAttribute {
+    pub fn new(name: &str, format: Format, data: Vec<f32>) -> Self {
+        assert!(format.is_float(), "Vertex attributes must use a float format");
+        assert!(data.len() % format.component_count() as usize == 0,
+            "Attribute data length must be a multiple of the format's component count");
+        VertexAttribute {
+            name: name.to_string(),
+            format,
+            data,
+        }
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        self.data.len() / self.format.component_count() as usize
+    }
+
+    /// The values for vertex `i`
+    pub fn get(&self, i: usize) -> &[f32] {
+        let n = self.format.component_count() as usize;
+        &self.data[i*n..(i+1)*n]
+    }
+
+    pub fn get_mut(&mut self, i: usize) -> &mut [f32] {
+        let n = self.format.component_count() as usize;
+        &mut self.data[i*n..(i+1)*n]
+    }
+
+    pub(crate) unsafe fn bind(&mut self, handle: &mut GeometryHandle, slot: u32) {
+        // Reads are done 16 bytes at a time so the last vertex must be padded out
+        let n = self.format.component_count() as usize;
+        self.data.reserve((4 - n % 4) % 4);
+        handle.bind_shared_buffer_raw(self.data.as_ptr() as *const c_void, BufferType::VertexAttribute,
+            self.format, slot, n * mem::size_of::<f32>(), self.vertex_count());
+    }
+}
+
+/// Adds an attribute to a list of attributes where the index is the slot, replacing one with the same name
+pub(crate) fn insert_vertex_attribute(attributes: &mut Vec<VertexAttribute>, attribute: VertexAttribute) -> u32 {
+    if let Some(slot) = attributes.iter().position(|a| a.name == attribute.name) {
+        attributes[slot] = attribute;
+        return slot as u32;
+    }
+    assert!((attributes.len() as u32) < MAX_VERTEX_ATTRIBUTES, "Too many vertex attributes");
+    attributes.push(attribute);
+    attributes.len() as u32 - 1
+}
+
+pub(crate) fn find_vertex_attribute(attributes: &[VertexAttribute], name: &str) -> Option<AttributeSlot> {
+    attributes.iter().position(|a| a.name == name).map(|slot| AttributeSlot {
+        slot: slot as u32,
+        format: attributes[slot].format,
+    })
+}
+
+pub(crate) unsafe fn bind_vertex_attributes(handle: &mut GeometryHandle, attributes: &mut [VertexAttribute]) {
+    rtcSetGeometryVertexAttributeCount(handle.ptr, attributes.len() as u32);
+    for (slot, attribute) in attributes.iter_mut().enumerate() {
+        attribute.bind(handle, slot as u32);
+    }
+}
+
+/// Decides whether a potential hit is accepted. The ray's `tfar` is the distance of the hit
+pub type FilterFunction = Arc<dyn Fn(&Ray, &Hit) -> bool + Send + Sync>;
+
 #[repr(C)]
 pub struct GeometryHandle {
     pub(crate) ptr: RTCGeometry,
+    geom_type: GeometryType,
+    intersect_filter: Option<FilterFunction>,
+    occluded_filter: Option<FilterFunction>,
 }
 
 impl GeometryHandle {
     pub(crate) fn new(device: &Device, geom_type: GeometryType) -> Self {
         let ptr = unsafe { rtcNewGeometry(device.ptr, geom_type.into()) };
-        GeometryHandle { ptr }
+        GeometryHandle { ptr, geom_type, intersect_filter: None, occluded_filter: None }
     }
 
     pub(crate) fn as_raw_ptr(&self) -> RTCGeometry {
         self.ptr
     }
 
+    pub fn geometry_type(&self) -> GeometryType {
+        self.geom_type
+    }
+
     pub(crate) fn set_build_quality(&mut self, quality: BuildQuality) {
         unsafe { rtcSetGeometryBuildQuality(self.ptr, quality.into()); }
     }
 
+    /// Sets a filter which can reject hits found by `Scene::intersect`.
+    /// Must be set before the geometry is attached to a scene
+    pub fn set_intersect_filter<F>(&mut self, filter: F)
+        where F: Fn(&Ray, &Hit) -> bool + Send + Sync + 'static {
+        self.intersect_filter = Some(Arc::new(filter));
+    }
+
+    /// Sets a filter which can reject hits found by `Scene::occluded`.
+    /// Must be set before the geometry is attached to a scene
+    pub fn set_occluded_filter<F>(&mut self, filter: F)
+        where F: Fn(&Ray, &Hit) -> bool + Send + Sync + 'static {
+        self.occluded_filter = Some(Arc::new(filter));
+    }
+
+    pub(crate) fn has_occluded_filter(&self) -> bool {
+        self.occluded_filter.is_some()
+    }
+
+    pub(crate) fn has_filters(&self) -> bool {
+        self.intersect_filter.is_some() || self.occluded_filter.is_some()
+    }
+
+    /// Registers the filter trampolines. The geometry user data must point to the `T` owning this handle
+    pub(crate) unsafe fn bind_filter_functions<T: Geometry>(&mut self) {
+        if self.intersect_filter.is_some() {
+            rtcSetGeometryIntersectFilterFunction(self.ptr, Some(intersect_filter_func::<T>));
+        }
+        if self.occluded_filter.is_some() {
+            rtcSetGeometryOccludedFilterFunction(self.ptr, Some(occluded_filter_func::<T>));
+        }
+    }
+
     // pub(crate) fn set_instance_transform(&mut self, transform: &cgmath::Matrix4<f32>) {
     //     unsafe {
     //         rtcSetGeometryTransform(self.ptr, 0,
@@ -64,16 +235,61 @@ impl GeometryHandle {
                 warn!("Vertex and vertex attribute buffers require padding at the end");
             }
         }
-        debug_assert!(byte_offset % 4 == 0, "offset must be 4 byte aligned");
-        debug_assert!(mem::size_of::<T>() % 4 == 0, "stride must be 4 byte aligned");
+        if format != Format::u8x1 {
+            debug_assert!(byte_offset % 4 == 0, "offset must be 4 byte aligned");
+        }
+        let ptr = (data.as_ptr() as *const u8).wrapping_add(byte_offset);
+        self.bind_shared_buffer_raw(ptr as *const c_void, buf_type, format, slot, mem::size_of::<T>(), data.len());
+    }
+
+    /// Binds `count` elements of `byte_stride` bytes starting at `data`
+    pub(crate) unsafe fn bind_shared_buffer_raw(&mut self, data: *const c_void, buf_type: BufferType, format: Format, slot: u32, byte_stride: usize, count: usize) {
+        if format != Format::u8x1 {
+            debug_assert!(byte_stride % 4 == 0, "stride must be 4 byte aligned");
+        }
         rtcSetSharedGeometryBuffer(self.ptr,
             buf_type.into(),
             slot,
             format.into(),
-            data.as_ptr() as *const c_void,
-            byte_offset,
-            mem::size_of::<T>(),
-            data.len());
+            data,
+            0,
+            byte_stride,
+            count);
+    }
+
+    /// Interpolates `out.len()` values of a vertex or vertex attribute buffer at the
+    /// barycentric coordinates `uv` of a primitive
+    pub(crate) fn interpolate(&self, buf_type: BufferType, slot: u32, prim_id: u32, uv: Vector2<f32>, out: &mut [f32]) {
+        self.interpolate_derivatives(buf_type, slot, prim_id, uv, out, None);
+    }
+
+    /// Same as `interpolate` but also writes the first order derivatives with respect to u and v
+    pub(crate) fn interpolate_derivatives(&self, buf_type: BufferType, slot: u32, prim_id: u32, uv: Vector2<f32>,
+        out: &mut [f32], derivatives: Option<(&mut [f32], &mut [f32])>)
+    {
+        let (dpdu, dpdv) = match derivatives {
+            Some((dpdu, dpdv)) => {
+                debug_assert!(dpdu.len() == out.len() && dpdv.len() == out.len());
+                (dpdu.as_mut_ptr(), dpdv.as_mut_ptr())
+            },
+            None => (ptr::null_mut(), ptr::null_mut()),
+        };
+        let args = RTCInterpolateArguments {
+            geometry: self.ptr,
+            primID: prim_id,
+            u: uv.x,
+            v: uv.y,
+            bufferType: buf_type.into(),
+            bufferSlot: slot,
+            P: out.as_mut_ptr(),
+            dPdu: dpdu,
+            dPdv: dpdv,
+            ddPdudu: ptr::null_mut(),
+            ddPdvdv: ptr::null_mut(),
+            ddPdudv: ptr::null_mut(),
+            valueCount: out.len() as u32,
+        };
+        unsafe { rtcInterpolate(&args); }
     }
     
     pub(crate) fn commit(&mut self) {
@@ -87,7 +303,12 @@ unsafe impl Sync for GeometryHandle {}
 impl Clone for GeometryHandle {
     fn clone(&self) -> GeometryHandle {
         unsafe { rtcRetainGeometry(self.ptr) }
-        GeometryHandle { ptr: self.ptr }
+        GeometryHandle {
+            ptr: self.ptr,
+            geom_type: self.geom_type,
+            intersect_filter: self.intersect_filter.clone(),
+            occluded_filter: self.occluded_filter.clone(),
+        }
     }
 }
 
@@ -97,16 +318,51 @@ impl Drop for GeometryHandle {
     }
 }
 
+unsafe fn run_filter(args: *const RTCFilterFunctionNArguments, filter: &FilterFunction) {
+    let n = (*args).N as usize;
+    let valid = slice::from_raw_parts_mut((*args).valid, n);
+    let rays = (*args).ray as *mut f32;
+    let hits = (*args).hit as *mut f32;
+    for (i, valid) in valid.iter_mut().enumerate() {
+        if *valid != 0 && !filter(&read_soa_ray(rays, n, i), &read_soa_hit(hits, n, i)) {
+            *valid = 0;
+        }
+    }
+}
+
+unsafe extern "C" fn intersect_filter_func<T: Geometry>(args: *const RTCFilterFunctionNArguments) {
+    let geometry = &*((*args).geometryUserPtr as *const T);
+    if let Some(filter) = geometry.handle().intersect_filter.as_ref() {
+        run_filter(args, filter);
+    }
+}
+
+unsafe extern "C" fn occluded_filter_func<T: Geometry>(args: *const RTCFilterFunctionNArguments) {
+    let geometry = &*((*args).geometryUserPtr as *const T);
+    if let Some(filter) = geometry.handle().occluded_filter.as_ref() {
+        run_filter(args, filter);
+    }
+}
+
 #[repr(i32)]
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum GeometryType {
     Triangle = RTC_GEOMETRY_TYPE_TRIANGLE,
     Quad = RTC_GEOMETRY_TYPE_QUAD,
-    // Grid = RTC_GEOMETRY_TYPE_GRID,
-    // Subdivision = RTC_GEOMETRY_TYPE_SUBDIVISION,
-    //  TODO: various curve types...
+    Grid = RTC_GEOMETRY_TYPE_GRID,
+    Subdivision = RTC_GEOMETRY_TYPE_SUBDIVISION,
+    FlatLinearCurve = RTC_GEOMETRY_TYPE_FLAT_LINEAR_CURVE,
+    RoundBezierCurve = RTC_GEOMETRY_TYPE_ROUND_BEZIER_CURVE,
+    FlatBezierCurve = RTC_GEOMETRY_TYPE_FLAT_BEZIER_CURVE,
+    NormalOrientedBezierCurve = RTC_GEOMETRY_TYPE_NORMAL_ORIENTED_BEZIER_CURVE,
+    RoundBSplineCurve = RTC_GEOMETRY_TYPE_ROUND_BSPLINE_CURVE,
+    FlatBSplineCurve = RTC_GEOMETRY_TYPE_FLAT_BSPLINE_CURVE,
+    NormalOrientedBSplineCurve = RTC_GEOMETRY_TYPE_NORMAL_ORIENTED_BSPLINE_CURVE,
+    RoundHermiteCurve = RTC_GEOMETRY_TYPE_ROUND_HERMITE_CURVE,
+    FlatHermiteCurve = RTC_GEOMETRY_TYPE_FLAT_HERMITE_CURVE,
+    NormalOrientedHermiteCurve = RTC_GEOMETRY_TYPE_NORMAL_ORIENTED_HERMITE_CURVE,
     Sphere = RTC_GEOMETRY_TYPE_SPHERE_POINT,
-    // RayFacingDisc = RTC_GEOMETRY_TYPE_DISC_POINT,
+    RayFacingDisc = RTC_GEOMETRY_TYPE_DISC_POINT,
     Disc = RTC_GEOMETRY_TYPE_ORIENTED_DISC_POINT,
     User = RTC_GEOMETRY_TYPE_USER,
     // Instance = RTC_GEOMETRY_TYPE_INSTANCE,
@@ -114,6 +370,13 @@ pub enum GeometryType {
 
 into_primitive!(GeometryType, i32);
 
+impl GeometryType {
+    /// Whether rtcInterpolate can be used on the vertex buffers of this geometry type
+    pub(crate) fn supports_interpolation(&self) -> bool {
+        matches!(self, GeometryType::Triangle | GeometryType::Quad | GeometryType::Grid | GeometryType::Subdivision)
+    }
+}
+
 #[repr(i32)]
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub(crate) enum BufferType {
@@ -121,16 +384,16 @@ pub(crate) enum BufferType {
     Vertex = RTC_BUFFER_TYPE_VERTEX,
     VertexAttribute = RTC_BUFFER_TYPE_VERTEX_ATTRIBUTE,
     Normal = RTC_BUFFER_TYPE_NORMAL,
-    // Tangent = RTC_BUFFER_TYPE_TANGENT,
-    // Grid = RTC_BUFFER_TYPE_GRID,
-    // Face = RTC_BUFFER_TYPE_FACE,
-    // Level = RTC_BUFFER_TYPE_LEVEL,
-    // EdgeCreaseIndex = RTC_BUFFER_TYPE_EDGE_CREASE_INDEX,
-    // EdgeCreaseWeight = RTC_BUFFER_TYPE_EDGE_CREASE_WEIGHT,
-    // VertexCreaseIndex = RTC_BUFFER_TYPE_VERTEX_CREASE_INDEX,
-    // VertexCreaseWeight = RTC_BUFFER_TYPE_VERTEX_CREASE_WEIGHT,
-    // Hole = RTC_BUFFER_TYPE_HOLE,
-    // Flags = RTC_BUFFER_TYPE_FLAGS,
+    Tangent = RTC_BUFFER_TYPE_TANGENT,
+    Grid = RTC_BUFFER_TYPE_GRID,
+    Face = RTC_BUFFER_TYPE_FACE,
+    Level = RTC_BUFFER_TYPE_LEVEL,
+    EdgeCreaseIndex = RTC_BUFFER_TYPE_EDGE_CREASE_INDEX,
+    EdgeCreaseWeight = RTC_BUFFER_TYPE_EDGE_CREASE_WEIGHT,
+    VertexCreaseIndex = RTC_BUFFER_TYPE_VERTEX_CREASE_INDEX,
+    VertexCreaseWeight = RTC_BUFFER_TYPE_VERTEX_CREASE_WEIGHT,
+    Hole = RTC_BUFFER_TYPE_HOLE,
+    Flags = RTC_BUFFER_TYPE_FLAGS,
 }
 
 into_primitive!(BufferType, i32);
\ No newline at end of file
@@ -0,0 +1,165 @@
+use cgmath::*;
+
+use common::*;
+use device::*;
+use geometry::*;
+
+/// Largest width or height (in vertices) Embree supports for a single grid
+pub const MAX_GRID_RESOLUTION: u32 = 32767;
+
+/// A regular grid of `width` x `height` vertices taken from the vertex buffer.
+/// Vertex (x, y) of the grid is at `start_vertex + y * stride + x`
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Grid {
+    pub start_vertex: u32,
+    pub stride: u32,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Grid {
+    pub fn new(start_vertex: u32, stride: u32, width: u16, height: u16) -> Self {
+        debug_assert!(width >= 2 && height >= 2, "Grids need at least 2x2 vertices");
+        debug_assert!(width as u32 <= MAX_GRID_RESOLUTION && height as u32 <= MAX_GRID_RESOLUTION);
+        Grid {
+            start_vertex,
+            stride,
+            width,
+            height,
+        }
+    }
+
+    /// Index into the vertex buffer of grid vertex (x, y)
+    pub fn vertex_index(&self, x: u32, y: u32) -> u32 {
+        self.start_vertex + y * self.stride + x
+    }
+}
+
+#[test]
+fn test_grid_layout() {
+    use sys::RTCGrid;
+    assert_eq!(std::mem::size_of::<Grid>(), std::mem::size_of::<RTCGrid>());
+    assert_eq!(offset_of!(Grid, start_vertex), offset_of!(RTCGrid, startVertexID));
+    assert_eq!(offset_of!(Grid, stride), offset_of!(RTCGrid, stride));
+    assert_eq!(offset_of!(Grid, width), offset_of!(RTCGrid, width));
+    assert_eq!(offset_of!(Grid, height), offset_of!(RTCGrid, height));
+}
+
+/// Geometry made of grids of vertices, each of which is tessellated into quads by Embree.
+/// Hits report the index of the grid as `prim_id` and uv ranges over the whole grid
+pub struct GridMesh {
+    pub(crate) handle: GeometryHandle,
+    pub grids: Vec<Grid>,
+    pub vertices: Vec<Point3<f32>>,
+    /// Vertex attributes, bound to slots in the order they were added
+    pub attributes: Vec<VertexAttribute>,
+}
+
+impl GridMesh {
+    pub fn new(device: &Device, grids: Vec<Grid>, vertices: Vec<Point3<f32>>) -> Self {
+        let handle = GeometryHandle::new(device, GeometryType::Grid);
+        GridMesh {
+            handle,
+            grids,
+            vertices,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Creates a heightfield from a row major array of `width` x `depth` heights.
+    /// Height (x, z) is placed at `origin + (x * spacing.x, height, z * spacing.y)`.
+    /// The heightfield is split into tiles of at most `tile_size` vertices along each side,
+    /// which share their border vertices so no vertices are duplicated
+    pub fn from_heightmap(device: &Device, heights: &[f32], width: usize, depth: usize,
+        origin: Point3<f32>, spacing: Vector2<f32>, tile_size: u32) -> Self
+    {
+        assert!(heights.len() == width * depth, "Heightmap size doesn't match its dimensions");
+        let vertices = (0..depth).flat_map(|z| (0..width).map(move |x| (x, z)))
+            .map(|(x, z)| origin + Vector3::new(x as f32 * spacing.x, heights[z * width + x], z as f32 * spacing.y))
+            .collect();
+        let grids = tile_grids(width as u32, depth as u32, tile_size);
+        GridMesh::new(device, grids, vertices)
+    }
+
+    /// Adds a named vertex attribute and returns the slot it will be bound to.
+    /// An existing attribute with the same name is replaced and keeps its slot
+    pub fn add_vertex_attribute(&mut self, attribute: VertexAttribute) -> u32 {
+        assert!(attribute.vertex_count() == self.vertices.len(), "Attribute must have a value for every vertex");
+        insert_vertex_attribute(&mut self.attributes, attribute)
+    }
+
+    pub fn get_vertex_attribute(&self, name: &str) -> Option<&VertexAttribute> {
+        self.attributes.iter().find(|a| a.name == name)
+    }
+
+    /// Converts the uv of a hit on a grid to fractional vertex coordinates within that grid
+    pub fn grid_coordinates(&self, prim_id: u32, uv: Vector2<f32>) -> Vector2<f32> {
+        let grid = &self.grids[prim_id as usize];
+        Vector2::new(uv.x * (grid.width - 1) as f32, uv.y * (grid.height - 1) as f32)
+    }
+}
+
+/// Splits a `width` x `height` array of vertices into grids of at most `tile_size`
+/// vertices per side. Neighbouring grids overlap by one row or column of vertices
+pub fn tile_grids(width: u32, height: u32, tile_size: u32) -> Vec<Grid> {
+    assert!(width >= 2 && height >= 2, "Grids need at least 2x2 vertices");
+    let tile_size = tile_size.min(MAX_GRID_RESOLUTION);
+    assert!(tile_size >= 2, "Tiles need at least 2x2 vertices");
+
+    let step = tile_size - 1;
+    let mut grids = Vec::new();
+    let mut y = 0;
+    while y < height - 1 {
+        let tile_height = step.min(height - 1 - y) + 1;
+        let mut x = 0;
+        while x < width - 1 {
+            let tile_width = step.min(width - 1 - x) + 1;
+            grids.push(Grid::new(y * width + x, width, tile_width as u16, tile_height as u16));
+            x += step;
+        }
+        y += step;
+    }
+    grids
+}
+
+#[test]
+fn test_tile_grids() {
+    let grids = tile_grids(10, 4, 4);
+    // Columns start at 0, 3, 6 and the last covers 6..9
+    assert_eq!(grids.len(), 3);
+    assert_eq!(grids[2].start_vertex, 6);
+    assert_eq!(grids[2].width, 4);
+    assert_eq!(grids[0].height, 4);
+
+    let grids = tile_grids(4097, 4097, MAX_GRID_RESOLUTION + 100);
+    assert_eq!(grids.len(), 1);
+    let grids = tile_grids(4097, 4097, 1025);
+    assert_eq!(grids.len(), 16);
+    assert!(grids.iter().all(|g| g.width == 1025 && g.height == 1025));
+}
+
+impl Geometry for GridMesh {
+    fn handle(&self) -> &GeometryHandle {
+        &self.handle
+    }
+
+    fn handle_mut(&mut self) -> &mut GeometryHandle {
+        &mut self.handle
+    }
+
+    fn bind_buffers(&mut self) {
+        self.vertices.reserve(1);
+
+        unsafe {
+            self.handle.bind_shared_geometry_buffer(&self.grids, BufferType::Grid, Format::grid, 0, 0);
+            self.handle.bind_shared_geometry_buffer(&self.vertices, BufferType::Vertex, Format::f32x3, 0, 0);
+
+            bind_vertex_attributes(&mut self.handle, &mut self.attributes);
+        }
+    }
+
+    fn vertex_attribute(&self, name: &str) -> Option<AttributeSlot> {
+        find_vertex_attribute(&self.attributes, name)
+    }
+}
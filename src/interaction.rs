@@ -0,0 +1,280 @@
+use std::f32;
+
+use cgmath::*;
+
+use common::*;
+use geometry::*;
+use polygon_geometry::{NORMAL_ATTRIBUTE, TEXCOORD_ATTRIBUTE};
+use ray::*;
+use scene::Scene;
+
+/// The local differential geometry of a surface at a ray hit, in world space
+#[derive(Debug, Copy, Clone)]
+pub struct SurfaceInteraction {
+    pub p: Point3<f32>,
+    /// Conservative bound on the absolute floating point error of each component of `p`
+    pub p_error: Vector3<f32>,
+    /// Unit geometric normal. Flipped to lie in the same hemisphere as `shading_n`
+    pub n: Vector3<f32>,
+    /// Unit shading normal, interpolated from the "normal" vertex attribute if the geometry has one
+    pub shading_n: Vector3<f32>,
+    /// Unit tangent and bitangent which form an orthonormal frame with `shading_n`.
+    /// The tangent follows `dpdu`
+    pub tangent: Vector3<f32>,
+    pub bitangent: Vector3<f32>,
+    /// Interpolated from the "uv" vertex attribute if the geometry has one, otherwise the hit's uv
+    pub uv: Vector2<f32>,
+    pub dpdu: Vector3<f32>,
+    pub dpdv: Vector3<f32>,
+    pub geom_id: GeomID,
+    pub prim_id: GeomID,
+    pub inst_id: GeomID,
+}
+
+impl SurfaceInteraction {
+    /// Returns None if the ray didn't hit anything.
+    ///
+    /// Scenes built with this crate can't contain instances, so for an instanced hit the
+    /// geometry isn't looked up and the interaction is built from the ray and `Ng` alone
+    pub fn from_hit(scene: &Scene, rayhit: &RayHit) -> Option<Self> {
+        let ray = &rayhit.ray;
+        let hit = &rayhit.hit;
+        if !hit.is_hit() {
+            return None;
+        }
+
+        let mut n = hit.Ng.normalize();
+        // An instanced hit's geom_id refers to the instanced scene, not this one
+        let geometry = if hit.inst_id.is_invalid() { scene.geometry(hit.geom_id) } else { None };
+        // Remapped primitive ids no longer identify what Embree would interpolate
+        let interpolated = geometry.filter(|g| g.handle().geometry_type().supports_interpolation() && g.prim_id_map().is_none());
+
+        let (p, p_error, mut dpdu, mut dpdv) = match interpolated {
+            Some(g) => {
+                // Interpolating the vertices is more accurate than stepping along the ray
+                let mut p = [0.0; 3];
+                let mut dpdu = [0.0; 3];
+                let mut dpdv = [0.0; 3];
+                g.handle().interpolate_derivatives(BufferType::Vertex, 0, hit.prim_id.unwrap(), hit.uv,
+                    &mut p, Some((&mut dpdu, &mut dpdv)));
+                let p = Point3::from(p);
+                let dpdu = Vector3::from(dpdu);
+                let dpdv = Vector3::from(dpdv);
+                // p = p0 + u*(p1 - p0) + v*(p2 - p0) where dpdu and dpdv are the edges
+                let p_error = gamma(7) * (abs(p.to_vec()) + 2.0 * (hit.uv.x.abs() * abs(dpdu) + hit.uv.y.abs() * abs(dpdv)));
+                (p, p_error, dpdu, dpdv)
+            },
+            None => {
+                let offset = ray.tfar * ray.dir;
+                let p = ray.origin + offset;
+                let p_error = gamma(5) * (abs(ray.origin.to_vec()) + abs(offset));
+                let (dpdu, dpdv) = coordinate_system(n);
+                (p, p_error, dpdu, dpdv)
+            },
+        };
+
+        let mut shading_n = n;
+        let mut uv = hit.uv;
+        if let Some(g) = geometry {
+            let has_attribute = |name: &str, format: Format| g.vertex_attribute(name).map(|a| a.format) == Some(format);
+            // The geometry decides how its attributes are interpolated, so ask it rather than Embree
+            if has_attribute(NORMAL_ATTRIBUTE, Format::f32x3) {
+                if let Some(ns) = g.interpolate_attribute(NORMAL_ATTRIBUTE, hit.prim_id.unwrap(), hit.uv) {
+                    let ns = Vector3::new(ns[0], ns[1], ns[2]);
+                    if ns.magnitude2() > 0.0 {
+                        shading_n = ns.normalize();
+                        if dot(n, shading_n) < 0.0 {
+                            n = -n;
+                        }
+                    }
+                }
+            }
+            if has_attribute(TEXCOORD_ATTRIBUTE, Format::f32x2) {
+                match interpolated {
+                    // Only Embree's interpolation gives the derivatives
+                    Some(g) => {
+                        let slot = g.vertex_attribute(TEXCOORD_ATTRIBUTE).unwrap().slot;
+                        let mut st = [0.0; 2];
+                        let mut dstdu = [0.0; 2];
+                        let mut dstdv = [0.0; 2];
+                        g.handle().interpolate_derivatives(BufferType::VertexAttribute, slot, hit.prim_id.unwrap(), hit.uv,
+                            &mut st, Some((&mut dstdu, &mut dstdv)));
+                        uv = Vector2::from(st);
+
+                        // Use the chain rule to get the derivatives with respect to the texture coordinates
+                        let det = dstdu[0] * dstdv[1] - dstdv[0] * dstdu[1];
+                        let inv_det = 1.0 / det;
+                        let dpds = (dpdu * dstdv[1] - dpdv * dstdu[1]) * inv_det;
+                        let dpdt = (dpdv * dstdu[0] - dpdu * dstdv[0]) * inv_det;
+                        if is_finite(dpds) && is_finite(dpdt) && dpds.cross(dpdt).magnitude2() > 0.0 {
+                            dpdu = dpds;
+                            dpdv = dpdt;
+                        }
+                    },
+                    None => {
+                        if let Some(st) = g.interpolate_attribute(TEXCOORD_ATTRIBUTE, hit.prim_id.unwrap(), hit.uv) {
+                            uv = Vector2::new(st[0], st[1]);
+                        }
+                    },
+                }
+            }
+        }
+
+        let (tangent, bitangent) = shading_frame(shading_n, dpdu);
+
+        Some(SurfaceInteraction {
+            p,
+            p_error,
+            n,
+            shading_n,
+            tangent,
+            bitangent,
+            uv,
+            dpdu,
+            dpdv,
+            geom_id: hit.geom_id,
+            prim_id: hit.prim_id,
+            inst_id: hit.inst_id,
+        })
+    }
+
+    /// Applies an object to world transform, including to the error bounds
+    pub fn transformed(&self, transform: &Matrix4<f32>) -> Self {
+        let normal_transform = transform.invert().expect("Transform is non-invertible").transpose();
+        let n = normal_transform.transform_vector(self.n).normalize();
+        let shading_n = normal_transform.transform_vector(self.shading_n).normalize();
+        let dpdu = transform.transform_vector(self.dpdu);
+        let (tangent, bitangent) = shading_frame(shading_n, transform.transform_vector(self.tangent));
+        SurfaceInteraction {
+            p: transform.transform_point(self.p),
+            p_error: transformed_error(transform, self.p, self.p_error),
+            n,
+            shading_n,
+            tangent,
+            bitangent,
+            uv: self.uv,
+            dpdu,
+            dpdv: transform.transform_vector(self.dpdv),
+            geom_id: self.geom_id,
+            prim_id: self.prim_id,
+            inst_id: self.inst_id,
+        }
+    }
+
+    /// Offsets `p` along the geometric normal, to the side `dir` points towards, by
+    /// just enough that a ray leaving in `dir` can't re-intersect the surface it started on
+    pub fn offset_ray_origin(&self, dir: Vector3<f32>) -> Point3<f32> {
+        let d = dot(abs(self.n), self.p_error);
+        let mut offset = d * self.n;
+        if dot(dir, self.n) < 0.0 {
+            offset = -offset;
+        }
+        let mut origin = self.p + offset;
+        // Round away from p so the offset isn't lost to rounding
+        for i in 0..3 {
+            if offset[i] > 0.0 {
+                origin[i] = next_float_up(origin[i]);
+            } else if offset[i] < 0.0 {
+                origin[i] = next_float_down(origin[i]);
+            }
+        }
+        origin
+    }
+
+    /// Creates a ray leaving the surface in direction `dir`
+    pub fn spawn_ray(&self, dir: Vector3<f32>) -> Ray {
+        Ray::new(self.offset_ray_origin(dir), dir, 0.0, f32::INFINITY)
+    }
+
+    /// Transforms a direction from world space into the local shading frame (tangent, bitangent, shading_n)
+    pub fn world_to_local(&self, v: Vector3<f32>) -> Vector3<f32> {
+        Vector3::new(dot(v, self.tangent), dot(v, self.bitangent), dot(v, self.shading_n))
+    }
+
+    pub fn local_to_world(&self, v: Vector3<f32>) -> Vector3<f32> {
+        v.x * self.tangent + v.y * self.bitangent + v.z * self.shading_n
+    }
+}
+
+fn abs(v: Vector3<f32>) -> Vector3<f32> {
+    Vector3::new(v.x.abs(), v.y.abs(), v.z.abs())
+}
+
+fn is_finite(v: Vector3<f32>) -> bool {
+    v.x.is_finite() && v.y.is_finite() && v.z.is_finite()
+}
+
+/// Builds an orthonormal basis around the unit vector `n`
+/// (Duff et al. 2017, "Building an Orthonormal Basis, Revisited")
+pub(crate) fn coordinate_system(n: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let sign = if n.z >= 0.0 { 1.0 } else { -1.0 };
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    (Vector3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+        Vector3::new(b, sign + n.y * n.y * a, -n.y))
+}
+
+/// Gram-Schmidt orthogonalizes `dir` against `n`, falling back to an arbitrary frame when degenerate
+fn shading_frame(n: Vector3<f32>, dir: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let t = dir - n * dot(n, dir);
+    if t.magnitude2() > 0.0 && is_finite(t) {
+        let t = t.normalize();
+        (t, n.cross(t))
+    } else {
+        coordinate_system(n)
+    }
+}
+
+fn transformed_error(m: &Matrix4<f32>, p: Point3<f32>, p_error: Vector3<f32>) -> Vector3<f32> {
+    let g3 = gamma(3);
+    let row = |r: usize| {
+        let e = m[0][r].abs() * p_error.x + m[1][r].abs() * p_error.y + m[2][r].abs() * p_error.z;
+        let a = (m[0][r] * p.x).abs() + (m[1][r] * p.y).abs() + (m[2][r] * p.z).abs() + m[3][r].abs();
+        (g3 + 1.0) * e + g3 * a
+    };
+    Vector3::new(row(0), row(1), row(2))
+}
+
+#[test]
+fn test_offset_ray_origin() {
+    let p = Point3::new(1.0e3, -2.0, 0.5);
+    let si = SurfaceInteraction {
+        p,
+        p_error: Vector3::new(1.0e-4, 1.0e-6, 1.0e-6),
+        n: Vector3::unit_x(),
+        shading_n: Vector3::unit_x(),
+        tangent: Vector3::unit_y(),
+        bitangent: Vector3::unit_z(),
+        uv: Vector2::zero(),
+        dpdu: Vector3::unit_y(),
+        dpdv: Vector3::unit_z(),
+        geom_id: GeomID::new(0),
+        prim_id: GeomID::new(0),
+        inst_id: GeomID::invalid(),
+    };
+    assert!(si.offset_ray_origin(Vector3::unit_x()).x > p.x + 1.0e-4);
+    assert!(si.offset_ray_origin(-Vector3::unit_x()).x < p.x - 1.0e-4);
+}
+
+#[test]
+fn test_ray_facing_disc_attributes() {
+    use device::Device;
+    use point_geometry::*;
+    use scene::SceneBuilder;
+
+    let device = Device::new();
+    let mut discs = RayFacingDiscGeometry::new(&device, vec![RayFacingDisc { center: Point3::new(0.0, 0.0, 0.0), radius: 1.0 }]);
+    discs.add_vertex_attribute(VertexAttribute::new(NORMAL_ATTRIBUTE, Format::f32x3, vec![0.6, 0.0, 0.8]));
+    discs.add_vertex_attribute(VertexAttribute::new(TEXCOORD_ATTRIBUTE, Format::f32x2, vec![0.25, 0.75]));
+    let mut builder = SceneBuilder::new(&device);
+    builder.attach(discs);
+    let scene = builder.build();
+
+    // Points aren't interpolated by Embree, so the values come from the disc itself
+    let mut rayhit = RayHit { ray: Ray::new(Point3::new(0.1, 0.1, 5.0), -Vector3::unit_z(), 0.0, f32::INFINITY), hit: Hit::empty() };
+    scene.intersect(&mut rayhit);
+    let si = SurfaceInteraction::from_hit(&scene, &rayhit).unwrap();
+    assert!((si.shading_n - Vector3::new(0.6, 0.0, 0.8)).magnitude() < 1e-6);
+    assert_eq!(si.uv, Vector2::new(0.25, 0.75));
+    assert!((si.p - Point3::new(0.1, 0.1, 0.0)).magnitude() < 1e-5);
+}
@@ -12,21 +12,51 @@ extern crate embree_sys as sys;
 #[macro_use]
 mod common;
 
+mod analytic_geometry;
+mod bilinear_patch_geometry;
+mod bvh;
+mod csg_geometry;
+mod curve_geometry;
 mod device;
 mod scene;
 mod error;
+mod flat_bvh;
 mod geometry;
+mod grid_geometry;
+mod interaction;
+mod lod_geometry;
+mod mesh_batcher;
+mod patch_geometry;
 mod point_geometry;
 mod polygon_geometry;
+mod quantized_geometry;
 mod ray;
+mod sdf_geometry;
+mod subdivision_geometry;
 mod user_geometry;
+mod voxel_geometry;
 
-pub use common::{Bounds, BuildQuality, GeomID};
+pub use analytic_geometry::*;
+pub use bilinear_patch_geometry::*;
+pub use bvh::*;
+pub use common::{Bounds, BuildQuality, Format, GeomID};
+pub use csg_geometry::*;
+pub use curve_geometry::*;
 pub use device::*;
 pub use scene::*;
 pub use error::*;
+pub use flat_bvh::*;
 pub use geometry::*;
+pub use grid_geometry::*;
+pub use interaction::*;
+pub use lod_geometry::*;
+pub use mesh_batcher::*;
+pub use patch_geometry::*;
 pub use point_geometry::*;
 pub use polygon_geometry::*;
+pub use quantized_geometry::*;
 pub use ray::*;
-pub use user_geometry::*;
\ No newline at end of file
+pub use sdf_geometry::*;
+pub use subdivision_geometry::*;
+pub use user_geometry::*;
+pub use voxel_geometry::*;
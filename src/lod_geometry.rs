@@ -0,0 +1,245 @@
+use std::f32;
+use std::sync::Arc;
+
+use cgmath::*;
+
+use common::*;
+use device::Device;
+use polygon_geometry::*;
+use ray::*;
+use scene::*;
+use user_geometry::*;
+
+/// Objects with several levels of detail, each traced with the level chosen for the ray.
+/// Many objects can share one `LodGeometry` so a whole forest is a single geometry,
+/// and objects placing the same `LodLevels` share their scenes
+pub type LodGeometry = UserGeometry<LodObject>;
+
+/// How a `LodObject` picks a level for a ray. Distances are from the ray's origin to the centre
+/// of the object's bounds. Every ray picks its own level, so a shadow or reflection ray may see
+/// a different level than the camera ray it started from, and should be offset from the surface
+/// by more than the levels differ
+#[derive(Debug, Clone)]
+pub enum LodSelector {
+    /// Level `i` is used while the distance is below `distances[i]`, and the last level beyond them
+    Distance(Vec<f32>),
+    /// Uses the coarsest level whose mean edge length fits within the width of a ray cone
+    /// growing by `spread_angle` radians, typically the angle covered by a pixel
+    Footprint { spread_angle: f32 },
+}
+
+/// The meshes of an object from finest to coarsest, each in its own scene, shared by the
+/// `LodObject`s placing it. Hits number the triangles of all levels in order as their
+/// `prim_id`, which `level_primitive` splits back into the level and its triangle
+pub struct LodLevels {
+    scenes: Vec<Scene>,
+    /// The `prim_id` of the first triangle of each level
+    first_prims: Vec<u32>,
+    edge_lengths: Vec<f32>,
+    bounds: Bounds,
+}
+
+impl LodLevels {
+    pub fn new(device: &Device, levels: Vec<TriangleMesh>) -> Self {
+        assert!(!levels.is_empty(), "A LOD object needs at least one level");
+        let bounds = Bounds::from_points(levels.iter().flat_map(|mesh| mesh.vertices.iter().cloned()));
+        let edge_lengths = levels.iter().map(mean_edge_length).collect();
+        let first_prims = levels.iter().scan(0, |first, mesh| {
+            let level_first = *first;
+            *first += mesh.indices.len() as u32;
+            Some(level_first)
+        }).collect();
+        let scenes = levels.into_iter().map(|mesh| {
+            let mut builder = SceneBuilder::new(device);
+            builder.attach(mesh);
+            builder.build()
+        }).collect();
+        LodLevels {
+            scenes,
+            first_prims,
+            edge_lengths,
+            bounds,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.scenes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scenes.is_empty()
+    }
+
+    /// The scene holding a level's mesh
+    pub fn level(&self, level: usize) -> &Scene {
+        &self.scenes[level]
+    }
+
+    /// The (level, triangle) of a `prim_id` reported by a hit on an object using these levels
+    pub fn level_primitive(&self, prim_id: u32) -> (usize, u32) {
+        // The last level starting at or before the primitive, skipping levels without triangles
+        let level = self.first_prims.partition_point(|&first| first <= prim_id) - 1;
+        (level, prim_id - self.first_prims[level])
+    }
+}
+
+/// An instance of shared `LodLevels`, placed in the geometry by `transform`
+pub struct LodObject {
+    levels: Arc<LodLevels>,
+    transform: Matrix4<f32>,
+    inverse: Matrix4<f32>,
+    /// How much the transform scales edge lengths
+    scale: f32,
+    bounds: Bounds,
+    pub selector: LodSelector,
+}
+
+impl LodObject {
+    pub fn new(levels: Arc<LodLevels>, transform: Matrix4<f32>, selector: LodSelector) -> Self {
+        let inverse = transform.invert().expect("Transform is non-invertible");
+        let scale = transform.determinant().abs().cbrt();
+        let (lower, upper) = (levels.bounds.lower, levels.bounds.upper);
+        let bounds = Bounds::from_points((0..8).map(|i| {
+            transform.transform_point(Point3::new(if i & 1 == 0 { lower.x } else { upper.x },
+                if i & 2 == 0 { lower.y } else { upper.y },
+                if i & 4 == 0 { lower.z } else { upper.z }))
+        }));
+        LodObject { levels, transform, inverse, scale, bounds, selector }
+    }
+
+    pub fn levels(&self) -> &Arc<LodLevels> {
+        &self.levels
+    }
+
+    pub fn transform(&self) -> Matrix4<f32> {
+        self.transform
+    }
+
+    /// The level used to trace a ray
+    pub fn select_level(&self, ray: &Ray) -> usize {
+        let bounds = self.levels.bounds;
+        let center = self.transform.transform_point(bounds.lower + (bounds.upper - bounds.lower) * 0.5);
+        let distance = (center - ray.origin).magnitude();
+        let last = self.levels.len() - 1;
+        match self.selector {
+            LodSelector::Distance(ref distances) => {
+                distances.iter().position(|&d| distance < d).unwrap_or(last).min(last)
+            }
+            LodSelector::Footprint { spread_angle } => {
+                let width = distance * spread_angle;
+                (0..self.levels.len()).rev().find(|&i| self.levels.edge_lengths[i] * self.scale <= width).unwrap_or(0)
+            }
+        }
+    }
+
+    /// The ray in the space of the levels' meshes, where `t` is unchanged
+    fn local_ray(&self, ray: &Ray) -> Ray {
+        let mut local = *ray;
+        local.origin = self.inverse.transform_point(ray.origin);
+        local.dir = self.inverse.transform_vector(ray.dir);
+        local
+    }
+}
+
+fn mean_edge_length(mesh: &TriangleMesh) -> f32 {
+    if mesh.indices.is_empty() {
+        return 0.0;
+    }
+    let v = |i: u32| mesh.vertices[i as usize];
+    let total: f32 = mesh.indices.iter().map(|t| {
+        (v(t.v1) - v(t.v0)).magnitude() + (v(t.v2) - v(t.v1)).magnitude() + (v(t.v0) - v(t.v2)).magnitude()
+    }).sum();
+    total / (3 * mesh.indices.len()) as f32
+}
+
+impl UserPrimitive for LodObject {
+    fn intersect(&self, ray: &Ray) -> UserPrimHit {
+        let mut rayhit = RayHit { ray: self.local_ray(ray), hit: Hit::empty() };
+        let level = self.select_level(ray);
+        self.levels.scenes[level].intersect(&mut rayhit);
+        if !rayhit.hit.is_hit() {
+            return UserPrimHit::miss();
+        }
+        let prim_id = self.levels.first_prims[level] + rayhit.hit.prim_id.id;
+        let normal = self.inverse.transpose().transform_vector(rayhit.hit.Ng);
+        UserPrimHit::new(rayhit.ray.tfar, normal, rayhit.hit.uv).with_prim_id(prim_id)
+    }
+
+    fn occluded(&self, ray: &Ray) -> bool {
+        let level = self.select_level(ray);
+        self.levels.scenes[level].occluded(&mut self.local_ray(ray))
+    }
+
+    fn bounds(&self) -> Bounds {
+        self.bounds
+    }
+}
+
+#[test]
+fn test_lod_selection() {
+    let device = Device::new();
+    let triangle = || {
+        TriangleMesh::new(&device, vec![Triangle::new(0, 1, 2)],
+            vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0)])
+    };
+    let ray_from = |z: f32| Ray::new(Point3::new(0.5, 0.5, z), -Vector3::unit_z(), 0.0, f32::INFINITY);
+
+    let mut levels = LodLevels::new(&device, vec![triangle(), triangle(), triangle()]);
+    assert_eq!(levels.len(), 3);
+    // Coarser levels have longer edges
+    levels.edge_lengths = vec![0.1, 1.0, 10.0];
+    let levels = Arc::new(levels);
+    let mut object = LodObject::new(levels.clone(), Matrix4::identity(), LodSelector::Distance(vec![10.0, 100.0]));
+    let levels_at = |object: &LodObject| [1.0, 50.0, 500.0].iter().map(|&z| object.select_level(&ray_from(z))).collect::<Vec<_>>();
+    assert_eq!(levels_at(&object), vec![0, 1, 2]);
+
+    object.selector = LodSelector::Footprint { spread_angle: 0.02 };
+    assert_eq!(levels_at(&object), vec![0, 1, 2]);
+    assert_eq!(object.bounds().upper, Point3::new(1.0, 1.0, 0.0));
+
+    // Edges of a scaled up object are longer, so it stays finer further away
+    let scaled = LodObject::new(levels, Matrix4::from_scale(2.0), LodSelector::Footprint { spread_angle: 0.02 });
+    assert_eq!(levels_at(&scaled), vec![0, 0, 1]);
+    assert_eq!(scaled.bounds().upper, Point3::new(2.0, 2.0, 0.0));
+}
+
+#[test]
+fn test_lod_geometry_hits() {
+    let device = Device::new();
+    let vertices = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(1.0, 1.0, 0.0), Point3::new(0.0, 1.0, 0.0)];
+    // A square of two triangles, then one triangle covering its lower half
+    let fine = TriangleMesh::new(&device, vec![Triangle::new(0, 1, 3), Triangle::new(1, 2, 3)], vertices.clone());
+    let coarse = TriangleMesh::new(&device, vec![Triangle::new(0, 1, 3)], vertices);
+    let levels = Arc::new(LodLevels::new(&device, vec![fine, coarse]));
+    assert_eq!(levels.level_primitive(2), (1, 0));
+
+    // The second object is the first moved along x and turned to face +x
+    let moved = Matrix4::from_translation(Vector3::new(5.0, 0.0, 0.0)) * Matrix4::from_angle_y(Deg(90.0));
+    let objects = vec![
+        LodObject::new(levels.clone(), Matrix4::identity(), LodSelector::Distance(vec![10.0])),
+        LodObject::new(levels.clone(), moved, LodSelector::Distance(vec![10.0])),
+    ];
+    assert_eq!(Arc::strong_count(&levels), 3);
+
+    let mut builder = SceneBuilder::new(&device);
+    builder.attach(LodGeometry::new(&device, objects));
+    let scene = builder.build();
+    let trace = |origin: Point3<f32>, dir: Vector3<f32>| {
+        let mut rayhit = RayHit { ray: Ray::new(origin, dir, 0.0, f32::INFINITY), hit: Hit::empty() };
+        scene.intersect(&mut rayhit);
+        rayhit
+    };
+    let hit = trace(Point3::new(0.75, 0.75, 1.0), -Vector3::unit_z()).hit;
+    let geometry = scene.downcast_geometry::<LodGeometry>(hit.geom_id).unwrap();
+    assert_eq!(hit.prim_id.id, 1);
+    assert_eq!(levels.level_primitive(trace(Point3::new(0.25, 0.25, 50.0), -Vector3::unit_z()).hit.prim_id.id), (1, 0));
+    // The coarse level doesn't cover the upper half of the square
+    assert!(!trace(Point3::new(0.75, 0.75, 50.0), -Vector3::unit_z()).hit.is_hit());
+
+    // The moved object spans z in [-1, 0] at x = 5
+    let rayhit = trace(Point3::new(6.0, 0.25, -0.25), -Vector3::unit_x());
+    assert_eq!(rayhit.hit.prim_id.id, 0);
+    assert!((rayhit.ray.tfar - 1.0).abs() < 1e-5);
+    assert!((rayhit.hit.Ng.normalize() - Vector3::unit_x()).magnitude() < 1e-5);
+    assert_eq!(geometry.prims[1].bounds().lower.x, 5.0);
+}
@@ -0,0 +1,258 @@
+use cgmath::*;
+
+use vec_map::*;
+
+use common::*;
+use device::Device;
+use geometry::*;
+use polygon_geometry::*;
+use ray::*;
+use scene::*;
+
+/// Meshes with fewer triangles than this are batched by `SceneBuilder::attach_meshes`
+pub const SMALL_MESH_TRIANGLES: usize = 1024;
+/// Fewest small meshes `SceneBuilder::attach_meshes` will bother batching
+pub const MIN_BATCHED_MESHES: usize = 16;
+
+/// The source mesh of a run of primitives in a geometry, which starts at `first_prim`
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct BatchRange {
+    first_prim: u32,
+    source: u32,
+}
+
+/// Maps hits on geometries attached by a `MeshBatcher` back to the meshes they came from
+#[derive(Debug, Clone, Default)]
+pub struct MeshBatchTable {
+    geometries: VecMap<Vec<BatchRange>>,
+}
+
+impl MeshBatchTable {
+    pub fn new() -> Self {
+        MeshBatchTable { geometries: VecMap::new() }
+    }
+
+    /// The (source mesh, source primitive) of a primitive of an attached geometry,
+    /// or `None` if the geometry wasn't attached through the batcher
+    pub fn source_primitive(&self, geom_id: u32, prim_id: u32) -> Option<(u32, u32)> {
+        let ranges = self.geometries.get(geom_id as usize)?;
+        // The last range starting at or before the primitive
+        let i = match ranges.binary_search_by_key(&prim_id, |r| r.first_prim) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        Some((ranges[i].source, prim_id - ranges[i].first_prim))
+    }
+
+    pub fn source_hit(&self, hit: &Hit) -> Option<(u32, u32)> {
+        if hit.geom_id.is_invalid() || !hit.inst_id.is_invalid() {
+            return None;
+        }
+        self.source_primitive(hit.geom_id.id, hit.prim_id.id)
+    }
+
+    fn insert(&mut self, geom_id: GeomID, ranges: Vec<BatchRange>) {
+        self.geometries.insert(geom_id.id as usize, ranges);
+    }
+}
+
+/// Merges many `TriangleMesh`es into a few large geometries. Meshes are only merged with
+/// others that have the same vertex attributes, and transforms are baked into the vertices.
+/// Meshes with filter functions are kept as they are, since a merged mesh can't carry them
+pub struct MeshBatcher {
+    device: Device,
+    /// Most triangles merged into a single geometry
+    pub max_batch_triangles: usize,
+    meshes: Vec<TriangleMesh>,
+}
+
+impl MeshBatcher {
+    pub fn new(device: &Device) -> Self {
+        MeshBatcher {
+            device: device.clone(),
+            max_batch_triangles: 1 << 20,
+            meshes: Vec::new(),
+        }
+    }
+
+    /// Adds a mesh and returns its source index in the `MeshBatchTable`
+    pub fn add(&mut self, mut mesh: TriangleMesh, transform: Option<Matrix4<f32>>) -> u32 {
+        if let Some(transform) = transform {
+            mesh.transform_mesh(transform);
+        }
+        self.meshes.push(mesh);
+        self.meshes.len() as u32 - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.meshes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.meshes.is_empty()
+    }
+
+    /// Attaches the merged geometries and returns the table mapping their hits back
+    pub fn attach(self, scene: &mut SceneBuilder) -> MeshBatchTable {
+        let mut table = MeshBatchTable::new();
+        self.attach_to_table(scene, &mut table, |i| i);
+        table
+    }
+
+    fn attach_to_table<F: Fn(u32) -> u32>(self, scene: &mut SceneBuilder, table: &mut MeshBatchTable, source: F) {
+        for (mesh, mut ranges) in self.merge() {
+            for r in ranges.iter_mut() {
+                r.source = source(r.source);
+            }
+            let id = scene.attach(mesh);
+            table.insert(id, ranges);
+        }
+    }
+
+    fn merge(self) -> Vec<(TriangleMesh, Vec<BatchRange>)> {
+        let device = self.device;
+        let max_triangles = self.max_batch_triangles;
+        let mut batches: Vec<(TriangleMesh, Vec<BatchRange>)> = Vec::new();
+        // Batches still accepting meshes, one per attribute layout
+        let mut open: Vec<usize> = Vec::new();
+
+        for (source, mesh) in self.meshes.into_iter().enumerate() {
+            if mesh.handle.has_filters() {
+                batches.push((mesh, vec![BatchRange { first_prim: 0, source: source as u32 }]));
+                continue;
+            }
+            let layout = open.iter().position(|&b| same_attribute_layout(&batches[b].0, &mesh));
+            let batch = match layout {
+                Some(i) if batches[open[i]].0.indices.len() + mesh.indices.len() <= max_triangles => open[i],
+                _ => {
+                    let mut merged = TriangleMesh::new(&device, Vec::new(), Vec::new());
+                    for a in mesh.attributes.iter() {
+                        merged.add_vertex_attribute(VertexAttribute::new(&a.name, a.format, Vec::new()));
+                    }
+                    batches.push((merged, Vec::new()));
+                    // A full batch is replaced by the new one
+                    match layout {
+                        Some(i) => open[i] = batches.len() - 1,
+                        None => open.push(batches.len() - 1),
+                    }
+                    batches.len() - 1
+                }
+            };
+
+            let (ref mut merged, ref mut ranges) = batches[batch];
+            ranges.push(BatchRange { first_prim: merged.indices.len() as u32, source: source as u32 });
+            let offset = merged.vertices.len() as u32;
+            merged.indices.extend(mesh.indices.iter().map(|t| Triangle::new(t.v0 + offset, t.v1 + offset, t.v2 + offset)));
+            merged.vertices.extend_from_slice(&mesh.vertices);
+            for (dst, src) in merged.attributes.iter_mut().zip(mesh.attributes.iter()) {
+                dst.data.extend_from_slice(&src.data);
+            }
+        }
+        batches
+    }
+}
+
+fn same_attribute_layout(a: &TriangleMesh, b: &TriangleMesh) -> bool {
+    a.attributes.len() == b.attributes.len()
+        && a.attributes.iter().zip(b.attributes.iter()).all(|(x, y)| x.name == y.name && x.format == y.format)
+}
+
+impl SceneBuilder {
+    /// Attaches meshes, batching the small ones without filter functions when there are enough
+    /// of them to be worth it. Source indices in the returned table are positions in `meshes`
+    pub fn attach_meshes(&mut self, device: &Device, meshes: Vec<(TriangleMesh, Option<Matrix4<f32>>)>) -> MeshBatchTable {
+        let batchable = |mesh: &TriangleMesh| mesh.indices.len() < SMALL_MESH_TRIANGLES && !mesh.handle.has_filters();
+        let small_count = meshes.iter().filter(|m| batchable(&m.0)).count();
+        let batch_small = small_count >= MIN_BATCHED_MESHES;
+
+        let mut table = MeshBatchTable::new();
+        let mut batcher = MeshBatcher::new(device);
+        let mut batched_sources = Vec::new();
+        for (source, (mut mesh, transform)) in meshes.into_iter().enumerate() {
+            if batch_small && batchable(&mesh) {
+                batcher.add(mesh, transform);
+                batched_sources.push(source as u32);
+            } else {
+                if let Some(transform) = transform {
+                    mesh.transform_mesh(transform);
+                }
+                let id = self.attach(mesh);
+                table.insert(id, vec![BatchRange { first_prim: 0, source: source as u32 }]);
+            }
+        }
+        batcher.attach_to_table(self, &mut table, |i| batched_sources[i as usize]);
+        table
+    }
+}
+
+#[test]
+fn test_mesh_batcher_merge() {
+    let device = Device::new();
+    let triangle = |x: f32, with_uv: bool| {
+        let mut mesh = TriangleMesh::new(&device, vec![Triangle::new(0, 1, 2)],
+            vec![Point3::new(x, 0.0, 0.0), Point3::new(x + 1.0, 0.0, 0.0), Point3::new(x, 1.0, 0.0)]);
+        if with_uv {
+            mesh.set_texcoord_buffer(vec![Vector2::new(0.0, 0.0), Vector2::new(1.0, 0.0), Vector2::new(0.0, 1.0)]);
+        }
+        mesh
+    };
+    let mut batcher = MeshBatcher::new(&device);
+    batcher.max_batch_triangles = 2;
+    batcher.add(triangle(0.0, false), None);
+    batcher.add(triangle(1.0, true), Some(Matrix4::from_translation(Vector3::new(0.0, 0.0, 5.0))));
+    batcher.add(triangle(2.0, false), None);
+    batcher.add(triangle(3.0, false), None);
+    batcher.add(triangle(4.0, true), None);
+
+    let batches = batcher.merge();
+    let sources: Vec<Vec<u32>> = batches.iter().map(|b| b.1.iter().map(|r| r.source).collect()).collect();
+    assert_eq!(sources, vec![vec![0, 2], vec![1, 4], vec![3]]);
+
+    let (ref uv_mesh, _) = batches[1];
+    assert_eq!(uv_mesh.indices[1].v0, 3);
+    assert_eq!(uv_mesh.vertices[0].z, 5.0);
+    assert_eq!(uv_mesh.get_vertex_attribute(TEXCOORD_ATTRIBUTE).unwrap().vertex_count(), 6);
+
+    let mut table = MeshBatchTable::new();
+    table.insert(GeomID::new(3), batches[0].1.clone());
+    assert_eq!(table.source_primitive(3, 1), Some((2, 0)));
+    assert_eq!(table.source_primitive(3, 0), Some((0, 0)));
+    assert_eq!(table.source_primitive(1, 0), None);
+}
+
+#[test]
+fn test_mesh_batcher_keeps_filters() {
+    let device = Device::new();
+    let triangle = |z: f32| TriangleMesh::new(&device, vec![Triangle::new(0, 1, 2)],
+        vec![Point3::new(0.0, 0.0, z), Point3::new(1.0, 0.0, z), Point3::new(0.0, 1.0, z)]);
+    let mut meshes = Vec::new();
+    for i in 0..MIN_BATCHED_MESHES {
+        meshes.push((triangle(-1.0 - i as f32), None));
+    }
+    // The nearest triangle rejects every hit
+    let mut filtered = triangle(0.0);
+    filtered.handle_mut().set_intersect_filter(|_, _| false);
+    meshes.push((filtered, None));
+
+    let mut builder = SceneBuilder::new(&device);
+    let table = builder.attach_meshes(&device, meshes);
+    let scene = builder.build();
+
+    let ray = Ray::new(Point3::new(0.25, 0.25, 1.0), -Vector3::unit_z(), 0.0, f32::INFINITY);
+    let mut rayhit = RayHit { ray, hit: Hit::empty() };
+    scene.intersect(&mut rayhit);
+    assert_eq!(rayhit.ray.tfar, 2.0);
+    assert_eq!(table.source_hit(&rayhit.hit), Some((0, 0)));
+
+    let mut batcher = MeshBatcher::new(&device);
+    batcher.add(triangle(0.0), None);
+    let mut filtered = triangle(1.0);
+    filtered.handle_mut().set_occluded_filter(|_, _| false);
+    batcher.add(filtered, None);
+    batcher.add(triangle(2.0), None);
+    let batches = batcher.merge();
+    let sources: Vec<Vec<u32>> = batches.iter().map(|b| b.1.iter().map(|r| r.source).collect()).collect();
+    assert_eq!(sources, vec![vec![0, 2], vec![1]]);
+    assert!(batches[1].0.handle.has_filters());
+}
@@ -0,0 +1,487 @@
+use std::f32;
+use std::mem;
+
+use cgmath::*;
+
+use common::*;
+use device::*;
+use geometry::*;
+use interaction::coordinate_system;
+use ray::*;
+use user_geometry::*;
+
+/// Bezier patches are split into 4^depth pieces when building their bounding hierarchy
+const BEZIER_HIERARCHY_DEPTH: u32 = 3;
+const NEWTON_ITERATIONS: u32 = 16;
+
+/// A bicubic Bezier patch. `points[4 * j + i]` is the control point i along u and j along v
+#[derive(Debug, Copy, Clone)]
+pub struct BezierPatch {
+    pub points: [Point3<f32>; 16],
+}
+
+/// A rational B-spline (NURBS) patch. `points[count_u * j + i]` is the control point
+/// i along u and j along v. Weights must be positive
+#[derive(Debug, Clone)]
+pub struct NurbsPatch {
+    pub degree_u: usize,
+    pub degree_v: usize,
+    pub knots_u: Vec<f32>,
+    pub knots_v: Vec<f32>,
+    pub count_u: usize,
+    pub points: Vec<Point3<f32>>,
+    pub weights: Vec<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Patch {
+    Bezier(BezierPatch),
+    Nurbs(NurbsPatch),
+}
+
+/// A point on a patch and its partial derivatives
+#[derive(Debug, Copy, Clone)]
+pub struct PatchPoint {
+    pub p: Point3<f32>,
+    pub dpdu: Vector3<f32>,
+    pub dpdv: Vector3<f32>,
+}
+
+/// Cubic Bernstein polynomials and their derivatives at t
+fn bernstein(t: f32) -> ([f32; 4], [f32; 4]) {
+    let s = 1.0 - t;
+    ([s * s * s, 3.0 * t * s * s, 3.0 * t * t * s, t * t * t],
+        [-3.0 * s * s, 3.0 * s * (s - 2.0 * t), 3.0 * t * (2.0 * s - t), 3.0 * t * t])
+}
+
+/// The control points of the part of a cubic Bezier curve between t0 and t1
+fn bezier_segment(p: [Point3<f32>; 4], t0: f32, t1: f32) -> [Point3<f32>; 4] {
+    // Splits by de Casteljau's algorithm returning the (left, right) halves
+    let split = |p: [Point3<f32>; 4], t: f32| {
+        let lerp = |a: Point3<f32>, b: Point3<f32>| a + (b - a) * t;
+        let (p01, p12, p23) = (lerp(p[0], p[1]), lerp(p[1], p[2]), lerp(p[2], p[3]));
+        let (p012, p123) = (lerp(p01, p12), lerp(p12, p23));
+        let mid = lerp(p012, p123);
+        ([p[0], p01, p012, mid], [mid, p123, p23, p[3]])
+    };
+    let left = if t1 < 1.0 { split(p, t1).0 } else { p };
+    if t0 > 0.0 { split(left, t0 / t1).1 } else { left }
+}
+
+impl BezierPatch {
+    pub fn new(points: [Point3<f32>; 16]) -> Self {
+        BezierPatch { points }
+    }
+
+    pub fn evaluate(&self, u: f32, v: f32) -> PatchPoint {
+        let (bu, dbu) = bernstein(u);
+        let (bv, dbv) = bernstein(v);
+        let mut p = Vector3::zero();
+        let mut dpdu = Vector3::zero();
+        let mut dpdv = Vector3::zero();
+        for j in 0..4 {
+            for i in 0..4 {
+                let c = self.points[4 * j + i].to_vec();
+                p += c * (bu[i] * bv[j]);
+                dpdu += c * (dbu[i] * bv[j]);
+                dpdv += c * (bu[i] * dbv[j]);
+            }
+        }
+        PatchPoint { p: Point3::from_vec(p), dpdu, dpdv }
+    }
+
+    /// The control points of the part of the patch over [u0, u1] x [v0, v1]
+    pub fn sub_patch(&self, u0: f32, u1: f32, v0: f32, v1: f32) -> BezierPatch {
+        let mut rows = self.points;
+        for j in 0..4 {
+            let row = [rows[4 * j], rows[4 * j + 1], rows[4 * j + 2], rows[4 * j + 3]];
+            rows[4 * j..4 * j + 4].copy_from_slice(&bezier_segment(row, u0, u1));
+        }
+        let mut points = rows;
+        for i in 0..4 {
+            let column = bezier_segment([rows[i], rows[4 + i], rows[8 + i], rows[12 + i]], v0, v1);
+            for j in 0..4 {
+                points[4 * j + i] = column[j];
+            }
+        }
+        BezierPatch { points }
+    }
+}
+
+/// The index of the knot span containing `t`
+fn find_span(knots: &[f32], degree: usize, count: usize, t: f32) -> usize {
+    if t >= knots[count] {
+        return count - 1;
+    }
+    let mut span = degree;
+    while span + 1 < count && t >= knots[span + 1] {
+        span += 1;
+    }
+    span
+}
+
+/// The `degree + 1` basis functions which are non-zero in `span` and their derivatives at t
+fn basis_functions(knots: &[f32], degree: usize, span: usize, t: f32) -> (Vec<f32>, Vec<f32>) {
+    // Cox-de Boor recursion building the degree p functions from degree p - 1
+    let mut n = vec![1.0];
+    let mut lower = Vec::new();
+    for p in 1..=degree {
+        let mut next = vec![0.0; p + 1];
+        for r in 0..p {
+            // n[r] is the degree p - 1 function with index span - p + 1 + r
+            let i = span + 1 + r - p;
+            let right = knots[i + p] - t;
+            let left = t - knots[i];
+            let term = if knots[i + p] > knots[i] { n[r] / (knots[i + p] - knots[i]) } else { 0.0 };
+            next[r] += right * term;
+            next[r + 1] += left * term;
+        }
+        lower = mem::replace(&mut n, next);
+    }
+    let mut dn = vec![0.0; degree + 1];
+    if degree > 0 {
+        for r in 0..=degree {
+            let i = span + r - degree;
+            let term = |k: usize, value: f32| {
+                let d = knots[k + degree] - knots[k];
+                if d > 0.0 { value / d } else { 0.0 }
+            };
+            let a = if r >= 1 { term(i, lower[r - 1]) } else { 0.0 };
+            let b = if r < degree { term(i + 1, lower[r]) } else { 0.0 };
+            dn[r] = degree as f32 * (a - b);
+        }
+    }
+    (n, dn)
+}
+
+impl NurbsPatch {
+    pub fn count_v(&self) -> usize {
+        self.points.len() / self.count_u
+    }
+
+    fn validate(&self) {
+        // Checked first as count_v divides by it
+        assert!(self.count_u > 0, "Patches need at least one control point along u");
+        assert!(self.points.len() % self.count_u == 0, "Control points don't form a grid");
+        assert_eq!(self.points.len(), self.weights.len(), "Every control point needs a weight");
+        assert_eq!(self.knots_u.len(), self.count_u + self.degree_u + 1, "Wrong number of u knots");
+        assert_eq!(self.knots_v.len(), self.count_v() + self.degree_v + 1, "Wrong number of v knots");
+    }
+
+    /// The parametric range of the patch as (u0, u1, v0, v1)
+    pub fn domain(&self) -> (f32, f32, f32, f32) {
+        (self.knots_u[self.degree_u], self.knots_u[self.count_u],
+            self.knots_v[self.degree_v], self.knots_v[self.count_v()])
+    }
+
+    pub fn evaluate(&self, u: f32, v: f32) -> PatchPoint {
+        let span_u = find_span(&self.knots_u, self.degree_u, self.count_u, u);
+        let span_v = find_span(&self.knots_v, self.degree_v, self.count_v(), v);
+        let (nu, dnu) = basis_functions(&self.knots_u, self.degree_u, span_u, u);
+        let (nv, dnv) = basis_functions(&self.knots_v, self.degree_v, span_v, v);
+
+        // Sums in homogeneous coordinates, then projects with the quotient rule
+        let (mut a, mut au, mut av) = (Vector3::zero(), Vector3::zero(), Vector3::zero());
+        let (mut w, mut wu, mut wv) = (0.0, 0.0, 0.0);
+        for (r, j) in (span_v - self.degree_v..=span_v).enumerate() {
+            for (s, i) in (span_u - self.degree_u..=span_u).enumerate() {
+                let k = self.count_u * j + i;
+                let weight = self.weights[k];
+                let c = self.points[k].to_vec() * weight;
+                a += c * (nu[s] * nv[r]);
+                au += c * (dnu[s] * nv[r]);
+                av += c * (nu[s] * dnv[r]);
+                w += weight * nu[s] * nv[r];
+                wu += weight * dnu[s] * nv[r];
+                wv += weight * nu[s] * dnv[r];
+            }
+        }
+        let p = a / w;
+        PatchPoint {
+            p: Point3::from_vec(p),
+            dpdu: (au - p * wu) / w,
+            dpdv: (av - p * wv) / w,
+        }
+    }
+
+    /// Bounds of the control points which affect the given knot spans
+    fn span_bounds(&self, span_u: usize, span_v: usize) -> Bounds {
+        let points = (span_v - self.degree_v..=span_v).flat_map(|j| {
+            (span_u - self.degree_u..=span_u).map(move |i| self.count_u * j + i)
+        }).map(|k| self.points[k]);
+        Bounds::from_points(points)
+    }
+}
+
+impl Patch {
+    pub fn evaluate(&self, u: f32, v: f32) -> PatchPoint {
+        match *self {
+            Patch::Bezier(ref patch) => patch.evaluate(u, v),
+            Patch::Nurbs(ref patch) => patch.evaluate(u, v),
+        }
+    }
+
+    pub fn domain(&self) -> (f32, f32, f32, f32) {
+        match *self {
+            Patch::Bezier(_) => (0.0, 1.0, 0.0, 1.0),
+            Patch::Nurbs(ref patch) => patch.domain(),
+        }
+    }
+}
+
+/// A parametric rectangle of a patch and bounds of the surface over it
+struct PatchNode {
+    bounds: Bounds,
+    domain: (f32, f32, f32, f32),
+    children: Vec<PatchNode>,
+}
+
+fn bezier_node(patch: &BezierPatch, domain: (f32, f32, f32, f32), depth: u32) -> PatchNode {
+    let (u0, u1, v0, v1) = domain;
+    // The surface lies within the convex hull of its control points
+    let bounds = Bounds::from_points(patch.sub_patch(u0, u1, v0, v1).points.iter().cloned());
+    let children = if depth == 0 {
+        Vec::new()
+    } else {
+        let (um, vm) = (0.5 * (u0 + u1), 0.5 * (v0 + v1));
+        [(u0, um, v0, vm), (um, u1, v0, vm), (u0, um, vm, v1), (um, u1, vm, v1)].iter()
+            .map(|&d| bezier_node(patch, d, depth - 1))
+            .collect()
+    };
+    PatchNode { bounds, domain, children }
+}
+
+fn nurbs_node(patch: &NurbsPatch) -> PatchNode {
+    let mut children = Vec::new();
+    for span_v in patch.degree_v..patch.count_v() {
+        for span_u in patch.degree_u..patch.count_u {
+            let (u0, u1) = (patch.knots_u[span_u], patch.knots_u[span_u + 1]);
+            let (v0, v1) = (patch.knots_v[span_v], patch.knots_v[span_v + 1]);
+            if u1 > u0 && v1 > v0 {
+                children.push(PatchNode {
+                    bounds: patch.span_bounds(span_u, span_v),
+                    domain: (u0, u1, v0, v1),
+                    children: Vec::new(),
+                });
+            }
+        }
+    }
+    // Positive weights keep the surface within the hull of the control points
+    PatchNode { bounds: Bounds::from_points(patch.points.iter().cloned()), domain: patch.domain(), children }
+}
+
+/// A patch with its bounding hierarchy, as intersected by Embree
+pub struct TracedPatch {
+    patch: Patch,
+    root: PatchNode,
+    tolerance: f32,
+}
+
+impl TracedPatch {
+    pub fn new(patch: Patch) -> Self {
+        let root = match patch {
+            Patch::Bezier(ref bezier) => bezier_node(bezier, (0.0, 1.0, 0.0, 1.0), BEZIER_HIERARCHY_DEPTH),
+            Patch::Nurbs(ref nurbs) => {
+                nurbs.validate();
+                nurbs_node(nurbs)
+            }
+        };
+        let tolerance = 1e-5 * (root.bounds.upper - root.bounds.lower).magnitude().max(f32::MIN_POSITIVE);
+        TracedPatch { patch, root, tolerance }
+    }
+
+    pub fn patch(&self) -> &Patch {
+        &self.patch
+    }
+
+    /// The closest intersection within the ray's range with uv the parametric coordinates
+    pub fn hit(&self, ray: &Ray) -> Option<UserPrimHit> {
+        // The ray is the intersection of two planes and Newton's method finds where
+        //  the surface crosses both of them
+        let (n1, n2) = coordinate_system(ray.dir.normalize());
+        let planes = [(n1, -dot(n1, ray.origin.to_vec())), (n2, -dot(n2, ray.origin.to_vec()))];
+        let mut best: Option<UserPrimHit> = None;
+        self.hit_node(&self.root, ray, &planes, &mut best);
+        best
+    }
+
+    fn hit_node(&self, node: &PatchNode, ray: &Ray, planes: &[(Vector3<f32>, f32); 2], best: &mut Option<UserPrimHit>) {
+        let mut ray = *ray;
+        if let Some(ref hit) = *best {
+            ray.tfar = hit.t;
+        }
+        if !node.bounds.hits(&ray) {
+            return;
+        }
+        if !node.children.is_empty() {
+            for child in node.children.iter() {
+                self.hit_node(child, &ray, planes, best);
+            }
+            return;
+        }
+
+        // Seeds from the centre and the middle of each quarter find roots close together
+        let (u0, u1, v0, v1) = node.domain;
+        let (du, dv) = (u1 - u0, v1 - v0);
+        for &(su, sv) in [(0.5, 0.5), (0.25, 0.25), (0.75, 0.25), (0.25, 0.75), (0.75, 0.75)].iter() {
+            if let Some(hit) = self.newton(u0 + su * du, v0 + sv * dv, &ray, planes) {
+                if best.map_or(true, |b| hit.t < b.t) {
+                    ray.tfar = hit.t;
+                    *best = Some(hit);
+                }
+            }
+        }
+    }
+
+    fn newton(&self, mut u: f32, mut v: f32, ray: &Ray, planes: &[(Vector3<f32>, f32); 2]) -> Option<UserPrimHit> {
+        let (du0, du1, dv0, dv1) = self.patch.domain();
+        for _ in 0..NEWTON_ITERATIONS {
+            let s = self.patch.evaluate(u, v);
+            let f = [dot(planes[0].0, s.p.to_vec()) + planes[0].1, dot(planes[1].0, s.p.to_vec()) + planes[1].1];
+            if f[0].abs() < self.tolerance && f[1].abs() < self.tolerance {
+                let t = dot(s.p - ray.origin, ray.dir) / ray.dir.magnitude2();
+                if !ray.in_range(t) {
+                    return None;
+                }
+                return Some(UserPrimHit::new(t, s.dpdu.cross(s.dpdv), Vector2::new(u, v)));
+            }
+            let (a, b) = (dot(planes[0].0, s.dpdu), dot(planes[0].0, s.dpdv));
+            let (c, d) = (dot(planes[1].0, s.dpdu), dot(planes[1].0, s.dpdv));
+            let det = a * d - b * c;
+            if det == 0.0 || !det.is_finite() {
+                return None;
+            }
+            u -= (d * f[0] - b * f[1]) / det;
+            v -= (a * f[1] - c * f[0]) / det;
+            // Roots outside the patch belong to other patches
+            if u < du0 || u > du1 || v < dv0 || v > dv1 {
+                return None;
+            }
+        }
+        None
+    }
+}
+
+impl UserPrimitive for TracedPatch {
+    fn intersect(&self, ray: &Ray) -> UserPrimHit {
+        self.hit(ray).unwrap_or_else(UserPrimHit::miss)
+    }
+
+    fn bounds(&self) -> Bounds {
+        self.root.bounds
+    }
+}
+
+/// Bezier and NURBS patches intersected directly with Newton's method. Hits report the
+/// parametric coordinates of the patch as uv
+pub struct PatchGeometry {
+    handle: GeometryHandle,
+    id: u32,
+    patches: Vec<TracedPatch>,
+}
+
+impl PatchGeometry {
+    pub fn new(device: &Device, patches: Vec<Patch>) -> Self {
+        PatchGeometry {
+            handle: GeometryHandle::new(device, GeometryType::User),
+            id: 0,
+            patches: patches.into_iter().map(TracedPatch::new).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.patches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patches.is_empty()
+    }
+
+    pub fn patch(&self, prim_id: u32) -> &Patch {
+        &self.patches[prim_id as usize].patch
+    }
+
+    /// The surface point and derivatives at a hit on this geometry
+    pub fn evaluate_hit(&self, hit: &Hit) -> PatchPoint {
+        self.patch(hit.prim_id.id).evaluate(hit.uv.x, hit.uv.y)
+    }
+}
+
+impl Geometry for PatchGeometry {
+    fn handle(&self) -> &GeometryHandle {
+        &self.handle
+    }
+
+    fn handle_mut(&mut self) -> &mut GeometryHandle {
+        &mut self.handle
+    }
+
+    fn set_geom_id(&mut self, id: u32) {
+        self.id = id;
+    }
+
+    fn bind_buffers(&mut self) {
+        let count = self.patches.len();
+        unsafe { bind_user_primitives(self, count); }
+    }
+}
+
+impl UserPrimitiveSource for PatchGeometry {
+    type Primitive<'a> = TracedPatch;
+
+    fn geom_id(&self) -> u32 {
+        self.id
+    }
+
+    fn with_primitive<'a, R, F: FnOnce(&Self::Primitive<'a>) -> R>(&'a self, prim_id: u32, f: F) -> R {
+        f(&self.patches[prim_id as usize])
+    }
+}
+
+#[test]
+fn test_bezier_patch_hit() {
+    // A bump over the unit square
+    let mut points = [Point3::origin(); 16];
+    for j in 0..4 {
+        for i in 0..4 {
+            let z = if (i == 1 || i == 2) && (j == 1 || j == 2) { 1.0 } else { 0.0 };
+            points[4 * j + i] = Point3::new(i as f32 / 3.0, j as f32 / 3.0, z);
+        }
+    }
+    let patch = TracedPatch::new(Patch::Bezier(BezierPatch::new(points)));
+    for &(x, y) in [(0.5, 0.5), (0.1, 0.8), (0.7, 0.3)].iter() {
+        let ray = Ray::new(Point3::new(x, y, 5.0), -Vector3::unit_z(), 0.0, f32::INFINITY);
+        let hit = patch.hit(&ray).unwrap();
+        // x and y are linear in u and v so the parametric coordinates match
+        assert!((hit.uv - Vector2::new(x, y)).magnitude() < 1e-4);
+        let p = patch.patch().evaluate(hit.uv.x, hit.uv.y).p;
+        assert!((ray.point_at_dist(hit.t) - p).magnitude() < 1e-4);
+        assert!(hit.Ng.z > 0.0);
+    }
+    assert!(patch.hit(&Ray::new(Point3::new(1.5, 0.5, 5.0), -Vector3::unit_z(), 0.0, f32::INFINITY)).is_none());
+}
+
+#[test]
+fn test_nurbs_patch_hit() {
+    // A quarter of a unit cylinder around z, exact with a rational quadratic
+    let w = 0.5f32.sqrt();
+    let nurbs = NurbsPatch {
+        degree_u: 2,
+        degree_v: 1,
+        knots_u: vec![0.0, 0.0, 0.0, 1.0, 1.0, 1.0],
+        knots_v: vec![0.0, 0.0, 1.0, 1.0],
+        count_u: 3,
+        points: vec![Point3::new(1.0, 0.0, 0.0), Point3::new(1.0, 1.0, 0.0), Point3::new(0.0, 1.0, 0.0),
+            Point3::new(1.0, 0.0, 1.0), Point3::new(1.0, 1.0, 1.0), Point3::new(0.0, 1.0, 1.0)],
+        weights: vec![1.0, w, 1.0, 1.0, w, 1.0],
+    };
+    let patch = TracedPatch::new(Patch::Nurbs(nurbs));
+    for &angle in [0.1f32, 0.5, 1.2].iter() {
+        let ray = Ray::new(Point3::new(0.0, 0.0, 0.5), Vector3::new(angle.cos(), angle.sin(), 0.0), 0.0, f32::INFINITY);
+        let hit = patch.hit(&ray).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-4);
+        assert!((hit.uv.y - 0.5).abs() < 1e-4);
+        let n = hit.Ng.normalize();
+        assert!((n.x.abs() - angle.cos()).abs() < 1e-3);
+    }
+}
@@ -43,6 +43,70 @@ impl Geometry for SphereGeometry {
     }
 }
 
+/// A disc which always faces the ray, e.g. for rendering point clouds as splats
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct RayFacingDisc {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
+pub struct RayFacingDiscGeometry {
+    pub(crate) handle: GeometryHandle,
+    pub prims: Vec<RayFacingDisc>,
+    /// Per point attributes, bound to slots in the order they were added
+    pub attributes: Vec<VertexAttribute>,
+}
+
+impl RayFacingDiscGeometry {
+    pub fn new(device: &Device, prims: Vec<RayFacingDisc>) -> Self {
+        let handle = GeometryHandle::new(device, GeometryType::RayFacingDisc);
+        RayFacingDiscGeometry {
+            handle,
+            prims,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Adds a named per point attribute and returns the slot it will be bound to.
+    /// An existing attribute with the same name is replaced and keeps its slot
+    pub fn add_vertex_attribute(&mut self, attribute: VertexAttribute) -> u32 {
+        assert!(attribute.vertex_count() == self.prims.len(), "Attribute must have a value for every point");
+        insert_vertex_attribute(&mut self.attributes, attribute)
+    }
+
+    pub fn get_vertex_attribute(&self, name: &str) -> Option<&VertexAttribute> {
+        self.attributes.iter().find(|a| a.name == name)
+    }
+}
+
+impl Geometry for RayFacingDiscGeometry {
+    fn handle(&self) -> &GeometryHandle {
+        &self.handle
+    }
+
+    fn handle_mut(&mut self) -> &mut GeometryHandle {
+        &mut self.handle
+    }
+
+    fn bind_buffers(&mut self) {
+        self.prims.reserve(1);
+        unsafe {
+            self.handle.bind_shared_geometry_buffer(&self.prims, BufferType::Vertex, Format::f32x4, 0, 0);
+            bind_vertex_attributes(&mut self.handle, &mut self.attributes);
+        }
+    }
+
+    fn vertex_attribute(&self, name: &str) -> Option<AttributeSlot> {
+        find_vertex_attribute(&self.attributes, name)
+    }
+
+    fn interpolate_attribute(&self, name: &str, prim_id: u32, _uv: Vector2<f32>) -> Option<Vec<f32>> {
+        // Every hit on a point has the value of that point
+        self.get_vertex_attribute(name).map(|a| a.get(prim_id as usize).to_vec())
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct Disc {
@@ -1,10 +1,10 @@
 use cgmath::*;
 
-use sys::*;
-
 use device::*;
 use common::*;
 use geometry::*;
+use ray::Ray;
+use user_geometry::UserPrimHit;
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -24,8 +24,36 @@ impl Triangle {
     }
 }
 
+/// Intersects a triangle the way Embree does, with uv the weights of `p1` and `p2`
+/// and the normal (p1 - p0) x (p2 - p0)
+pub(crate) fn triangle_hit(p: &[Point3<f32>; 3], ray: &Ray) -> Option<UserPrimHit> {
+    let e1 = p[1] - p[0];
+    let e2 = p[2] - p[0];
+    let pvec = ray.dir.cross(e2);
+    let det = dot(e1, pvec);
+    if det == 0.0 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let tvec = ray.origin - p[0];
+    let u = dot(tvec, pvec) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let qvec = tvec.cross(e1);
+    let v = dot(ray.dir, qvec) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = dot(e2, qvec) * inv_det;
+    if !ray.in_range(t) {
+        return None;
+    }
+    Some(UserPrimHit::new(t, e1.cross(e2), Vector2::new(u, v)))
+}
+
 /// A quad is defined as a pair of triangles (v0, v1, v3) & (v2, v3, v1).
-/// All of the vertices should be co-planar
+/// All of the vertices should be co-planar, otherwise use a `BilinearPatchGeometry`
 /// Triangles and quads can be mixed by using a quad with v2 == v3
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -70,9 +98,10 @@ impl PolygonType for Quad {
     const POLYGON_TYPE: GeometryType = GeometryType::Quad;
 }
 
-// Internal use constants
-const NORMALS_SLOT: u32 = 0;
-const UV_SLOT: u32 = 1;
+/// Name of the attribute registered by `set_normal_buffer`
+pub const NORMAL_ATTRIBUTE: &str = "normal";
+/// Name of the attribute registered by `set_texcoord_buffer`
+pub const TEXCOORD_ATTRIBUTE: &str = "uv";
 
 macro_rules! polygon_geometry_def {
     ($geometryname:ident, $polygon:ty, $geometry_constructor:ident) => (
@@ -80,8 +109,8 @@ pub struct $geometryname {
     pub(crate) handle: GeometryHandle,
     pub indices: Vec<$polygon>,
     pub vertices: Vec<Point3<f32>>,
-    pub normals: Option<Vec<Vector3<f32>>>,
-    pub tex_coords: Option<Vec<Vector2<f32>>>,
+    /// Vertex attributes, bound to slots in the order they were added
+    pub attributes: Vec<VertexAttribute>,
 }
 
 impl $geometryname {
@@ -91,27 +120,45 @@ impl $geometryname {
             handle: handle,
             indices: index_buffer,
             vertices: vertex_buffer,
-            normals: None,
-            tex_coords: None,
+            attributes: Vec::new(),
         }
     }
 
+    /// Adds a named vertex attribute and returns the slot it will be bound to.
+    /// An existing attribute with the same name is replaced and keeps its slot
+    pub fn add_vertex_attribute(&mut self, attribute: VertexAttribute) -> u32 {
+        assert!(attribute.vertex_count() == self.vertices.len(), "Attribute must have a value for every vertex");
+        insert_vertex_attribute(&mut self.attributes, attribute)
+    }
+
+    pub fn get_vertex_attribute(&self, name: &str) -> Option<&VertexAttribute> {
+        self.attributes.iter().find(|a| a.name == name)
+    }
+
+    pub fn get_vertex_attribute_mut(&mut self, name: &str) -> Option<&mut VertexAttribute> {
+        self.attributes.iter_mut().find(|a| a.name == name)
+    }
+
     pub fn set_normal_buffer(&mut self, buf: Vec<Vector3<f32>>) {
-        self.normals = Some(buf);
+        let data = buf.iter().flat_map(|n| [n.x, n.y, n.z]).collect();
+        self.add_vertex_attribute(VertexAttribute::new(NORMAL_ATTRIBUTE, Format::f32x3, data));
     }
 
     pub fn set_texcoord_buffer(&mut self, buf: Vec<Vector2<f32>>) {
-        self.tex_coords = Some(buf);
+        let data = buf.iter().flat_map(|uv| [uv.x, uv.y]).collect();
+        self.add_vertex_attribute(VertexAttribute::new(TEXCOORD_ATTRIBUTE, Format::f32x2, data));
     }
 
     pub fn transform_mesh(&mut self, transform: Matrix4<f32>) {
         for v in self.vertices.iter_mut() {
             *v = transform.transform_point(*v);
         }
-        if let Some(ref mut normal_buf) = self.normals {
+        if let Some(normal_attrib) = self.get_vertex_attribute_mut(NORMAL_ATTRIBUTE) {
             let normal_transform = transform.invert().expect("Transform is non-invertible").transpose();
-            for n in normal_buf.iter_mut() {
-                *n = normal_transform.transform_vector(*n);
+            for i in 0..normal_attrib.vertex_count() {
+                let n = normal_attrib.get_mut(i);
+                let t = normal_transform.transform_vector(Vector3::new(n[0], n[1], n[2]));
+                n.copy_from_slice(&[t.x, t.y, t.z]);
             }
         }
     }
@@ -127,10 +174,6 @@ impl Geometry for $geometryname {
     }
 
     fn bind_buffers(&mut self) {
-        let mut attrib_count = 0;
-        if self.normals.is_some() { attrib_count = NORMALS_SLOT + 1; }
-        if self.tex_coords.is_some() { attrib_count = UV_SLOT + 1; }
-
         self.indices.reserve(1);
         self.vertices.reserve(1);
         
@@ -138,20 +181,196 @@ impl Geometry for $geometryname {
             self.handle.bind_shared_geometry_buffer(&mut self.indices, BufferType::Index, <$polygon>::FORMAT, 0, 0);
             self.handle.bind_shared_geometry_buffer(&mut self.vertices, BufferType::Vertex, Format::f32x3, 0, 0);
 
-            rtcSetGeometryVertexAttributeCount(self.handle.ptr, attrib_count);
-
-            if let Some(ref mut data) = self.normals {
-                data.reserve(1);
-                self.handle.bind_shared_geometry_buffer(data, BufferType::VertexAttribute, Format::f32x3, NORMALS_SLOT, 0);
-            }
-            if let Some(ref mut data) = self.tex_coords {
-                data.reserve(1);
-                self.handle.bind_shared_geometry_buffer(data, BufferType::VertexAttribute, Format::f32x2, UV_SLOT, 0);
-            }
+            bind_vertex_attributes(&mut self.handle, &mut self.attributes);
         }
     }
+
+    fn vertex_attribute(&self, name: &str) -> Option<AttributeSlot> {
+        find_vertex_attribute(&self.attributes, name)
+    }
 }
 )}
 
 polygon_geometry_def!(TriangleMesh, Triangle, Triangles);
 polygon_geometry_def!(QuadMesh, Quad, Quads);
+
+/// A mesh of faces with any number of vertices. Faces are split into quads and
+/// triangles when the mesh is attached, and hits report the index of the original face
+/// as their `prim_id`.
+///
+/// The remapping happens once a scene query returns, so filter functions and hits through
+/// an instance still see the id of the split primitive; `face_of_primitive` maps it to its
+/// face. A hit's `uv` is always relative to the split quad or triangle, not the face
+pub struct PolygonMesh {
+    pub(crate) handle: GeometryHandle,
+    /// Number of vertices in each face
+    pub face_vertex_counts: Vec<u32>,
+    /// Vertex indices of every face in order
+    pub indices: Vec<u32>,
+    pub vertices: Vec<Point3<f32>>,
+    quads: Vec<Quad>,
+    face_ids: Vec<u32>,
+}
+
+impl PolygonMesh {
+    pub fn new(device: &Device, face_vertex_counts: Vec<u32>, index_buffer: Vec<u32>, vertex_buffer: Vec<Point3<f32>>) -> Self {
+        PolygonMesh {
+            handle: GeometryHandle::new(device, GeometryType::Quad),
+            face_vertex_counts,
+            indices: index_buffer,
+            vertices: vertex_buffer,
+            quads: Vec::new(),
+            face_ids: Vec::new(),
+        }
+    }
+
+    /// The face an internal primitive was split from
+    pub fn face_of_primitive(&self, prim_id: u32) -> u32 {
+        self.face_ids[prim_id as usize]
+    }
+
+    fn split_faces(&mut self) {
+        let total: u32 = self.face_vertex_counts.iter().sum();
+        assert_eq!(total as usize, self.indices.len(), "Face vertex counts don't match the index buffer");
+        self.quads.clear();
+        self.face_ids.clear();
+
+        let mut start = 0;
+        for (face, &count) in self.face_vertex_counts.iter().enumerate() {
+            let count = count as usize;
+            assert!(count >= 3, "Faces need at least 3 vertices");
+            let face_indices = &self.indices[start..start + count];
+            let points: Vec<Point3<f32>> = face_indices.iter().map(|&i| self.vertices[i as usize]).collect();
+            start += count;
+
+            if count == 4 && is_convex_quad(&points) {
+                self.quads.push(Quad::new(face_indices[0], face_indices[1], face_indices[2], face_indices[3]));
+                self.face_ids.push(face as u32);
+                continue;
+            }
+            for t in triangulate_polygon(&points) {
+                // A quad with v2 == v3 is a triangle
+                let (a, b, c) = (face_indices[t[0]], face_indices[t[1]], face_indices[t[2]]);
+                self.quads.push(Quad::new(a, b, c, c));
+                self.face_ids.push(face as u32);
+            }
+        }
+    }
+}
+
+impl Geometry for PolygonMesh {
+    fn handle(&self) -> &GeometryHandle {
+        &self.handle
+    }
+
+    fn handle_mut(&mut self) -> &mut GeometryHandle {
+        &mut self.handle
+    }
+
+    fn bind_buffers(&mut self) {
+        self.split_faces();
+        self.quads.reserve(1);
+        self.vertices.reserve(1);
+
+        unsafe {
+            self.handle.bind_shared_geometry_buffer(&self.quads, BufferType::Index, Quad::FORMAT, 0, 0);
+            self.handle.bind_shared_geometry_buffer(&self.vertices, BufferType::Vertex, Format::f32x3, 0, 0);
+        }
+    }
+
+    fn prim_id_map(&self) -> Option<&[u32]> {
+        Some(&self.face_ids)
+    }
+}
+
+/// Normal of a polygon by Newell's method, which is robust for non-planar polygons
+fn polygon_normal(points: &[Point3<f32>]) -> Vector3<f32> {
+    let mut n = Vector3::zero();
+    for i in 0..points.len() {
+        let (a, b) = (points[i], points[(i + 1) % points.len()]);
+        n += Vector3::new((a.y - b.y) * (a.z + b.z), (a.z - b.z) * (a.x + b.x), (a.x - b.x) * (a.y + b.y));
+    }
+    n
+}
+
+/// Projects a polygon onto the plane it mostly lies in, keeping its winding counterclockwise
+fn project_polygon(points: &[Point3<f32>]) -> Vec<Vector2<f32>> {
+    let n = polygon_normal(points);
+    let axis = if n.x.abs() > n.y.abs() && n.x.abs() > n.z.abs() { 0 } else if n.y.abs() > n.z.abs() { 1 } else { 2 };
+    let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+    let flip = n[axis] < 0.0;
+    points.iter().map(|p| if flip { Vector2::new(p[b], p[a]) } else { Vector2::new(p[a], p[b]) }).collect()
+}
+
+fn cross2(o: Vector2<f32>, a: Vector2<f32>, b: Vector2<f32>) -> f32 {
+    (a.x - o.x) * (b.y - o.y) - (a.y - o.y) * (b.x - o.x)
+}
+
+fn is_convex_quad(points: &[Point3<f32>]) -> bool {
+    let p = project_polygon(points);
+    (0..4).all(|i| cross2(p[i], p[(i + 1) % 4], p[(i + 2) % 4]) > 0.0)
+}
+
+/// Splits a simple polygon, which may be concave, into triangles by ear clipping.
+/// Returns indices into `points` with the polygon's winding
+pub(crate) fn triangulate_polygon(points: &[Point3<f32>]) -> Vec<[usize; 3]> {
+    let p = project_polygon(points);
+    let mut remaining: Vec<usize> = (0..points.len()).collect();
+    let mut triangles = Vec::with_capacity(points.len() - 2);
+    while remaining.len() > 3 {
+        let m = remaining.len();
+        let ear = (0..m).find(|&i| {
+            let (a, b, c) = (remaining[(i + m - 1) % m], remaining[i], remaining[(i + 1) % m]);
+            if cross2(p[a], p[b], p[c]) <= 0.0 {
+                return false;
+            }
+            // No other vertex may lie within the ear
+            remaining.iter().all(|&v| {
+                v == a || v == b || v == c
+                    || cross2(p[a], p[b], p[v]) < 0.0 || cross2(p[b], p[c], p[v]) < 0.0 || cross2(p[c], p[a], p[v]) < 0.0
+            })
+        });
+        match ear {
+            Some(i) => {
+                triangles.push([remaining[(i + m - 1) % m], remaining[i], remaining[(i + 1) % m]]);
+                remaining.remove(i);
+            }
+            None => {
+                // Degenerate or self intersecting, so fall back to a fan
+                for i in 1..m - 1 {
+                    triangles.push([remaining[0], remaining[i], remaining[i + 1]]);
+                }
+                return triangles;
+            }
+        }
+    }
+    triangles.push([remaining[0], remaining[1], remaining[2]]);
+    triangles
+}
+
+#[test]
+fn test_triangulate_concave_polygon() {
+    // An L shape, which a fan from vertex 0 would triangulate outside of the polygon
+    let points = [Point3::new(0.0, 0.0, 0.0), Point3::new(2.0, 0.0, 0.0), Point3::new(2.0, 1.0, 0.0),
+        Point3::new(1.0, 1.0, 0.0), Point3::new(1.0, 2.0, 0.0), Point3::new(0.0, 2.0, 0.0)];
+    let triangles = triangulate_polygon(&points);
+    assert_eq!(triangles.len(), 4);
+    let p = project_polygon(&points);
+    let area: f32 = triangles.iter().map(|t| 0.5 * cross2(p[t[0]], p[t[1]], p[t[2]])).sum();
+    assert_eq!(area, 3.0);
+    assert!(triangles.iter().all(|t| cross2(p[t[0]], p[t[1]], p[t[2]]) > 0.0));
+    assert!(!is_convex_quad(&[points[0], points[1], points[3], points[5]]));
+}
+
+#[test]
+fn test_polygon_mesh_face_ids() {
+    let device = Device::new();
+    let vertices = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(1.0, 1.0, 0.0),
+        Point3::new(0.0, 1.0, 0.0), Point3::new(2.0, 0.0, 0.0), Point3::new(2.0, 1.0, 0.0), Point3::new(1.5, 2.0, 0.0)];
+    // A triangle, a convex quad and a pentagon
+    let mut mesh = PolygonMesh::new(&device, vec![3, 4, 5], vec![0, 1, 3, 0, 1, 2, 3, 1, 4, 5, 6, 2], vertices);
+    mesh.split_faces();
+    assert_eq!(mesh.quads.len(), 1 + 1 + 3);
+    assert_eq!(mesh.prim_id_map().unwrap(), &[0, 1, 2, 2, 2][..]);
+    assert_eq!(mesh.face_of_primitive(3), 2);
+}
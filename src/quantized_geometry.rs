@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::f32;
+use std::mem;
+use std::ops::Range;
+
+use cgmath::*;
+
+use common::*;
+use device::Device;
+use geometry::*;
+use polygon_geometry::{triangle_hit, Triangle};
+use ray::*;
+use user_geometry::*;
+
+/// Most triangles in a cluster
+pub const CLUSTER_TRIANGLES: usize = 256;
+/// Most vertices a cluster's triangles can span in the mesh's vertices, so local indices fit in a byte
+const CLUSTER_VERTICES: usize = 256;
+
+/// A triangle mesh stored in clusters of quantized vertices, which are decoded while
+/// tracing. Hits report the index of the triangle in the source mesh as their `prim_id`.
+/// Besides the smaller buffers, Embree's BVH only holds one primitive per cluster
+/// rather than copies of every triangle
+pub struct QuantizedMeshGeometry {
+    handle: GeometryHandle,
+    id: u32,
+    mesh: QuantizedMesh,
+}
+
+/// Vertices are stored once on a grid shared by the whole mesh, so vertices shared between
+/// clusters decode to the same point and the mesh stays watertight. Each keeps the low 16 bits
+/// of its grid coordinates, which a cluster no wider than 2^16 steps completes from its base.
+/// Triangles too large for that are kept in clusters of their own with full coordinates
+struct QuantizedMesh {
+    grid_origin: Point3<f32>,
+    grid_step: f32,
+    positions: Vec<[u16; 3]>,
+    wide_positions: Vec<[i32; 3]>,
+    /// Every triangle of the source mesh, indexing from its cluster's `first_vertex`
+    indices: Vec<[u8; 3]>,
+    clusters: Vec<QuantizedCluster>,
+}
+
+/// Up to `CLUSTER_TRIANGLES` consecutive triangles of the mesh
+struct QuantizedCluster {
+    /// Grid coordinates of the cluster's lower bound
+    base: [i32; 3],
+    first_triangle: u32,
+    triangle_count: u32,
+    /// The vertex local index 0 refers to, in `wide_positions` for a wide cluster
+    first_vertex: u32,
+    wide: bool,
+}
+
+impl QuantizedMesh {
+    fn grid_vertex(&self, cluster: &QuantizedCluster, local: u8) -> [i32; 3] {
+        let i = cluster.first_vertex as usize + local as usize;
+        if cluster.wide {
+            return self.wide_positions[i];
+        }
+        let q = self.positions[i];
+        let axis = |a: usize| cluster.base[a] + (q[a] as i32 - cluster.base[a]).rem_euclid(1 << 16);
+        [axis(0), axis(1), axis(2)]
+    }
+
+    fn decode(&self, g: [i32; 3]) -> Point3<f32> {
+        self.grid_origin + Vector3::new(g[0] as f32, g[1] as f32, g[2] as f32) * self.grid_step
+    }
+
+    fn triangle(&self, cluster: &QuantizedCluster, prim_id: u32) -> [Point3<f32>; 3] {
+        let t = self.indices[prim_id as usize];
+        let v = |local: u8| self.decode(self.grid_vertex(cluster, local));
+        [v(t[0]), v(t[1]), v(t[2])]
+    }
+
+    fn byte_size(&self) -> usize {
+        self.positions.capacity() * mem::size_of::<[u16; 3]>()
+            + self.wide_positions.capacity() * mem::size_of::<[i32; 3]>()
+            + self.indices.capacity() * mem::size_of::<[u8; 3]>()
+            + self.clusters.capacity() * mem::size_of::<QuantizedCluster>()
+    }
+}
+
+/// A cluster with the mesh holding its vertices
+pub(crate) struct ClusterRef<'a> {
+    mesh: &'a QuantizedMesh,
+    cluster: &'a QuantizedCluster,
+}
+
+impl<'a> ClusterRef<'a> {
+    fn prim_ids(&self) -> Range<u32> {
+        self.cluster.first_triangle..self.cluster.first_triangle + self.cluster.triangle_count
+    }
+}
+
+impl<'a> UserPrimitive for ClusterRef<'a> {
+    fn intersect(&self, ray: &Ray) -> UserPrimHit {
+        let mut ray = *ray;
+        let mut closest = UserPrimHit::miss();
+        for prim_id in self.prim_ids() {
+            if let Some(hit) = triangle_hit(&self.mesh.triangle(self.cluster, prim_id), &ray) {
+                ray.tfar = hit.t;
+                closest = hit.with_prim_id(prim_id);
+            }
+        }
+        closest
+    }
+
+    fn occluded(&self, ray: &Ray) -> bool {
+        self.prim_ids().any(|prim_id| triangle_hit(&self.mesh.triangle(self.cluster, prim_id), ray).is_some())
+    }
+
+    fn bounds(&self) -> Bounds {
+        let mut lower = [i32::MAX; 3];
+        let mut upper = [i32::MIN; 3];
+        for prim_id in self.prim_ids() {
+            for &local in self.mesh.indices[prim_id as usize].iter() {
+                let g = self.mesh.grid_vertex(self.cluster, local);
+                for axis in 0..3 {
+                    lower[axis] = lower[axis].min(g[axis]);
+                    upper[axis] = upper[axis].max(g[axis]);
+                }
+            }
+        }
+        Bounds::new(self.mesh.decode(lower), self.mesh.decode(upper))
+    }
+}
+
+fn triangle_bounds(t: &Triangle, vertices: &[Point3<f32>]) -> Bounds {
+    Bounds::from_points([t.v0, t.v1, t.v2].iter().map(|&v| vertices[v as usize]))
+}
+
+/// Widest side of the bounds
+fn max_extent(b: &Bounds) -> f32 {
+    let e = b.upper - b.lower;
+    e.x.max(e.y).max(e.z)
+}
+
+/// Numbers the vertices in the order triangles other than the `skipped` ones first use them
+fn first_use_order<F: Fn(&Triangle) -> bool>(triangles: &[Triangle], skipped: F) -> HashMap<u32, u32> {
+    let mut order = HashMap::new();
+    for t in triangles.iter().filter(|t| !skipped(t)) {
+        for &v in [t.v0, t.v1, t.v2].iter() {
+            let next = order.len() as u32;
+            order.entry(v).or_insert(next);
+        }
+    }
+    order
+}
+
+/// Splits a mesh into runs of consecutive triangles whose vertices span few enough of
+/// the numbered vertices for a cluster, no wider than `extent_limit`. Wider triangles
+/// have a run of their own. Returns each run with its bounds
+fn cluster_ranges(triangles: &[Triangle], vertices: &[Point3<f32>], order: &HashMap<u32, u32>, extent_limit: f32)
+    -> Vec<((usize, usize), Bounds)> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    let mut span: Option<(u32, u32)> = None;
+    let mut bounds: Option<Bounds> = None;
+    for (i, t) in triangles.iter().enumerate() {
+        let tb = triangle_bounds(t, vertices);
+        let oversized = max_extent(&tb) > extent_limit;
+        let corners = [t.v0, t.v1, t.v2];
+        let numbered = |span: Option<(u32, u32)>| corners.iter().filter_map(|v| order.get(v)).fold(span, |s, &n| {
+            Some(s.map_or((n, n), |(lo, hi)| (lo.min(n), hi.max(n))))
+        });
+        if let Some(b) = bounds {
+            let wide_span = numbered(span).is_some_and(|(lo, hi)| (hi - lo) as usize >= CLUSTER_VERTICES);
+            if i - start == CLUSTER_TRIANGLES || oversized || wide_span || max_extent(&b.union(&tb)) > extent_limit {
+                ranges.push(((start, i), b));
+                start = i;
+                span = None;
+                bounds = None;
+            }
+        }
+        if oversized {
+            ranges.push(((i, i + 1), tb));
+            start = i + 1;
+            continue;
+        }
+        bounds = Some(bounds.map_or(tb, |b| b.union(&tb)));
+        span = numbered(span);
+    }
+    if let Some(b) = bounds {
+        ranges.push(((start, triangles.len()), b));
+    }
+    ranges
+}
+
+/// Quantizes a mesh into clusters on a grid shared by the whole mesh. The grid is as fine
+/// as the median cluster allows and wider clusters are split to fit, so a few long clusters
+/// or large triangles don't coarsen every vertex
+fn quantize_mesh(triangles: &[Triangle], vertices: &[Point3<f32>]) -> QuantizedMesh {
+    let all = first_use_order(triangles, |_| false);
+    let mut extents: Vec<f32> = cluster_ranges(triangles, vertices, &all, f32::INFINITY).iter().map(|r| max_extent(&r.1)).collect();
+    extents.sort_by(f32::total_cmp);
+    let extent_limit = extents.get(extents.len() / 2).cloned().unwrap_or(0.0);
+
+    let oversized = |t: &Triangle| max_extent(&triangle_bounds(t, vertices)) > extent_limit;
+    let order = first_use_order(triangles, oversized);
+    let ranges = cluster_ranges(triangles, vertices, &order, extent_limit);
+    let mesh_bounds = ranges.iter().map(|r| r.1).reduce(|a, b| a.union(&b));
+    let grid_origin = mesh_bounds.map_or(Point3::origin(), |b| b.lower);
+    // Rounding can add a step to the extent of a cluster. Grid coordinates
+    //  are decoded through f32, which holds integers exactly up to 2^24
+    let grid_step = (extent_limit / (u16::MAX - 1) as f32)
+        .max(mesh_bounds.map_or(0.0, |b| max_extent(&b)) / (1 << 24) as f32);
+    let grid_step = if grid_step > 0.0 { grid_step } else { 1.0 };
+    let to_grid = |p: Point3<f32>| {
+        let g = (p - grid_origin) / grid_step;
+        [g.x.round() as i32, g.y.round() as i32, g.z.round() as i32]
+    };
+
+    let mut positions = vec![[0; 3]; order.len()];
+    for (&v, &n) in order.iter() {
+        let g = to_grid(vertices[v as usize]);
+        positions[n as usize] = [g[0] as u16, g[1] as u16, g[2] as u16];
+    }
+    let mut wide_positions = Vec::new();
+    let mut indices = Vec::with_capacity(triangles.len());
+    let clusters = ranges.iter().map(|&((start, end), bounds)| {
+        let corners = |t: &Triangle| [t.v0, t.v1, t.v2];
+        let wide = end - start == 1 && oversized(&triangles[start]);
+        let first_vertex = if wide {
+            wide_positions.extend(corners(&triangles[start]).iter().map(|&v| to_grid(vertices[v as usize])));
+            indices.push([0, 1, 2]);
+            wide_positions.len() as u32 - 3
+        } else {
+            let first = triangles[start..end].iter().flat_map(|t| corners(t).to_vec()).map(|v| order[&v]).min().unwrap();
+            indices.extend(triangles[start..end].iter().map(|t| {
+                let c = corners(t);
+                let local = |v: u32| (order[&v] - first) as u8;
+                [local(c[0]), local(c[1]), local(c[2])]
+            }));
+            first
+        };
+        QuantizedCluster {
+            base: to_grid(bounds.lower),
+            first_triangle: start as u32,
+            triangle_count: (end - start) as u32,
+            first_vertex,
+            wide,
+        }
+    }).collect();
+    QuantizedMesh { grid_origin, grid_step, positions, wide_positions, indices, clusters }
+}
+
+impl QuantizedMeshGeometry {
+    pub fn from_mesh(device: &Device, triangles: &[Triangle], vertices: &[Point3<f32>]) -> Self {
+        QuantizedMeshGeometry {
+            handle: GeometryHandle::new(device, GeometryType::User),
+            id: 0,
+            mesh: quantize_mesh(triangles, vertices),
+        }
+    }
+
+    pub fn cluster_count(&self) -> usize {
+        self.mesh.clusters.len()
+    }
+
+    /// The decoded vertices of a triangle of the source mesh, as reported by a hit's `prim_id`
+    pub fn triangle(&self, prim_id: u32) -> [Point3<f32>; 3] {
+        let clusters = &self.mesh.clusters;
+        let cluster = &clusters[clusters.partition_point(|c| c.first_triangle <= prim_id) - 1];
+        self.mesh.triangle(cluster, prim_id)
+    }
+
+    /// Furthest a decoded vertex can be from its original position along each axis
+    pub fn quantization_error(&self) -> f32 {
+        0.5 * self.mesh.grid_step
+    }
+
+    /// Bytes used by the quantized mesh
+    pub fn byte_size(&self) -> usize {
+        self.mesh.byte_size()
+    }
+}
+
+impl Geometry for QuantizedMeshGeometry {
+    fn handle(&self) -> &GeometryHandle {
+        &self.handle
+    }
+
+    fn handle_mut(&mut self) -> &mut GeometryHandle {
+        &mut self.handle
+    }
+
+    fn set_geom_id(&mut self, id: u32) {
+        self.id = id;
+    }
+
+    fn bind_buffers(&mut self) {
+        let count = self.mesh.clusters.len();
+        unsafe { bind_user_primitives(self, count); }
+    }
+}
+
+impl UserPrimitiveSource for QuantizedMeshGeometry {
+    type Primitive<'a> = ClusterRef<'a>;
+
+    fn geom_id(&self) -> u32 {
+        self.id
+    }
+
+    fn with_primitive<'a, R, F: FnOnce(&Self::Primitive<'a>) -> R>(&'a self, prim_id: u32, f: F) -> R {
+        f(&ClusterRef { mesh: &self.mesh, cluster: &self.mesh.clusters[prim_id as usize] })
+    }
+}
+
+/// An n x n grid of quads with sides of 0.25, split into triangles
+#[cfg(test)]
+fn grid_mesh(n: u32) -> (Vec<Triangle>, Vec<Point3<f32>>) {
+    let vertices = (0..(n + 1) * (n + 1)).map(|i| {
+        let (x, y) = ((i % (n + 1)) as f32, (i / (n + 1)) as f32);
+        Point3::new(x * 0.25, y * 0.25, (x * 0.3).sin() + y * 0.01)
+    }).collect();
+    let mut triangles = Vec::new();
+    for y in 0..n {
+        for x in 0..n {
+            let v = y * (n + 1) + x;
+            triangles.push(Triangle::new(v, v + 1, v + n + 2));
+            triangles.push(Triangle::new(v, v + n + 2, v + n + 1));
+        }
+    }
+    (triangles, vertices)
+}
+
+/// Checks every decoded vertex is within `error` of the source mesh along each axis
+#[cfg(test)]
+fn assert_decodes(mesh: &QuantizedMesh, triangles: &[Triangle], vertices: &[Point3<f32>], error: f32) {
+    for cluster in mesh.clusters.iter() {
+        for prim_id in (ClusterRef { mesh, cluster }).prim_ids() {
+            let t = triangles[prim_id as usize];
+            let decoded = mesh.triangle(cluster, prim_id);
+            for (k, &v) in [t.v0, t.v1, t.v2].iter().enumerate() {
+                let d = decoded[k] - vertices[v as usize];
+                assert!(d.x.abs().max(d.y.abs()).max(d.z.abs()) <= error);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_quantized_mesh() {
+    let n = 40;
+    let (triangles, vertices) = grid_mesh(n);
+    let mesh = quantize_mesh(&triangles, &vertices);
+    assert!(mesh.clusters.len() >= triangles.len() / CLUSTER_TRIANGLES);
+    assert_eq!(mesh.clusters.iter().map(|c| c.triangle_count as usize).sum::<usize>(), triangles.len());
+    assert_eq!(mesh.positions.len(), vertices.len());
+    assert_decodes(&mesh, &triangles, &vertices, 0.5 * mesh.grid_step * 1.01);
+
+    let uncompressed = triangles.len() * mem::size_of::<Triangle>() + vertices.len() * mem::size_of::<[f32; 4]>();
+    assert!(mesh.byte_size() * 3 <= uncompressed);
+
+    // Hits report the source triangle
+    let target = 2 * (7 * n + 13) + 1;
+    let cluster = mesh.clusters.iter().find(|c| (ClusterRef { mesh: &mesh, cluster: c }).prim_ids().any(|p| p == target)).unwrap();
+    let p = mesh.triangle(cluster, target);
+    let centroid = p[0] + ((p[1] - p[0]) + (p[2] - p[0])) / 3.0;
+    let ray = Ray::new(centroid + Vector3::unit_z() * 5.0, -Vector3::unit_z(), 0.0, f32::INFINITY);
+    let hit = ClusterRef { mesh: &mesh, cluster }.intersect(&ray);
+    assert_eq!(hit.prim_id, Some(target));
+    assert!((hit.t - 5.0).abs() < 1e-4);
+    assert!(hit.Ng.z > 0.0);
+}
+
+#[test]
+fn test_quantized_long_cluster() {
+    let (mut triangles, mut vertices) = grid_mesh(40);
+    let grid_step = quantize_mesh(&triangles, &vertices).grid_step;
+
+    // A thin strip a hundred times longer than the grid
+    let first = vertices.len() as u32;
+    for i in 0..=200 {
+        vertices.push(Point3::new(i as f32 * 5.0, -1.0, 0.0));
+        vertices.push(Point3::new(i as f32 * 5.0, -1.25, 0.0));
+    }
+    for i in 0..200 {
+        let v = first + 2 * i;
+        triangles.push(Triangle::new(v, v + 1, v + 3));
+        triangles.push(Triangle::new(v, v + 3, v + 2));
+    }
+    let mesh = quantize_mesh(&triangles, &vertices);
+    assert_eq!(mesh.grid_step, grid_step);
+    assert!(mesh.clusters.iter().all(|cluster| {
+        let bounds = ClusterRef { mesh: &mesh, cluster }.bounds();
+        bounds.upper.x - bounds.lower.x <= 10.0 + grid_step
+    }));
+    assert_eq!(mesh.clusters.iter().map(|c| c.triangle_count as usize).sum::<usize>(), triangles.len());
+    assert_decodes(&mesh, &triangles, &vertices, grid_step);
+}
+
+#[test]
+fn test_quantized_large_triangle() {
+    let (mut triangles, mut vertices) = grid_mesh(40);
+    let grid_step = quantize_mesh(&triangles, &vertices).grid_step;
+
+    // A triangle far wider than the whole grid, sharing a corner with it
+    let first = vertices.len() as u32;
+    vertices.push(Point3::new(1.0e3, 0.0, 0.0));
+    vertices.push(Point3::new(0.0, 1.0e3, 0.0));
+    triangles.insert(100, Triangle::new(0, first, first + 1));
+    let mesh = quantize_mesh(&triangles, &vertices);
+    assert_eq!(mesh.grid_step, grid_step);
+    assert_eq!(mesh.clusters.iter().filter(|c| c.wide).count(), 1);
+    let cluster = mesh.clusters.iter().find(|c| c.wide).unwrap();
+    assert_eq!((cluster.first_triangle, cluster.triangle_count), (100, 1));
+    assert_decodes(&mesh, &triangles, &vertices, 0.5 * grid_step * 1.01);
+
+    let ray = Ray::new(Point3::new(500.0, 100.0, 1.0), -Vector3::unit_z(), 0.0, f32::INFINITY);
+    assert_eq!(ClusterRef { mesh: &mesh, cluster }.intersect(&ray).prim_id, Some(100));
+}
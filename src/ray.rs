@@ -4,7 +4,7 @@ use sys::*;
 
 use cgmath::*;
 
-use common::GeomID;
+use common::{GeomID, INVALID_ID};
 
 #[repr(C)]
 #[repr(align(16))]
@@ -13,11 +13,11 @@ pub struct Ray {
     pub origin: Point3<f32>,
     pub tnear: f32,
     pub dir: Vector3<f32>,
-    time: f32,
+    pub(crate) time: f32,
     pub tfar: f32,
-    mask: u32,
-    id: u32,
-    flags: u32,
+    pub(crate) mask: u32,
+    pub(crate) id: u32,
+    pub(crate) flags: u32,
 }
 
 impl Ray {
@@ -110,3 +110,230 @@ impl RayHit {
         self as *mut RayHit as *mut RTCRayHit
     }
 }
+
+macro_rules! ray_packet_def {
+    ($rayname:ident, $hitname:ident, $rayhitname:ident, $n:expr, $align:literal,
+     $rtcray:ident, $rtchit:ident, $rtcrayhit:ident, $layout_test:ident) => (
+/// A packet of rays in structure of arrays layout
+#[repr(C)]
+#[repr(align($align))]
+#[derive(Debug, Copy, Clone)]
+pub struct $rayname {
+    pub origin_x: [f32; $n],
+    pub origin_y: [f32; $n],
+    pub origin_z: [f32; $n],
+    pub tnear: [f32; $n],
+    pub dir_x: [f32; $n],
+    pub dir_y: [f32; $n],
+    pub dir_z: [f32; $n],
+    time: [f32; $n],
+    pub tfar: [f32; $n],
+    mask: [u32; $n],
+    id: [u32; $n],
+    flags: [u32; $n],
+}
+
+impl $rayname {
+    pub fn from_rays(rays: &[Ray; $n]) -> Self {
+        let mut packet = $rayname {
+            origin_x: [0.0; $n],
+            origin_y: [0.0; $n],
+            origin_z: [0.0; $n],
+            tnear: [0.0; $n],
+            dir_x: [0.0; $n],
+            dir_y: [0.0; $n],
+            dir_z: [0.0; $n],
+            time: [0.0; $n],
+            tfar: [0.0; $n],
+            mask: [u32::MAX; $n],
+            id: [0; $n],
+            flags: [0; $n],
+        };
+        for (i, ray) in rays.iter().enumerate() {
+            packet.set(i, ray);
+        }
+        packet
+    }
+
+    pub fn get(&self, i: usize) -> Ray {
+        Ray {
+            origin: Point3::new(self.origin_x[i], self.origin_y[i], self.origin_z[i]),
+            tnear: self.tnear[i],
+            dir: Vector3::new(self.dir_x[i], self.dir_y[i], self.dir_z[i]),
+            time: self.time[i],
+            tfar: self.tfar[i],
+            mask: self.mask[i],
+            id: self.id[i],
+            flags: self.flags[i],
+        }
+    }
+
+    pub fn set(&mut self, i: usize, ray: &Ray) {
+        self.origin_x[i] = ray.origin.x;
+        self.origin_y[i] = ray.origin.y;
+        self.origin_z[i] = ray.origin.z;
+        self.tnear[i] = ray.tnear;
+        self.dir_x[i] = ray.dir.x;
+        self.dir_y[i] = ray.dir.y;
+        self.dir_z[i] = ray.dir.z;
+        self.time[i] = ray.time;
+        self.tfar[i] = ray.tfar;
+        self.mask[i] = ray.mask;
+        self.id[i] = ray.id;
+        self.flags[i] = ray.flags;
+    }
+
+    pub fn as_raw_ptr(&mut self) -> *mut $rtcray {
+        self as *mut $rayname as *mut $rtcray
+    }
+}
+
+#[repr(C)]
+#[repr(align($align))]
+#[derive(Debug, Copy, Clone)]
+#[allow(non_snake_case)]
+pub struct $hitname {
+    pub Ng_x: [f32; $n],
+    pub Ng_y: [f32; $n],
+    pub Ng_z: [f32; $n],
+    pub u: [f32; $n],
+    pub v: [f32; $n],
+    pub prim_id: [u32; $n],
+    pub geom_id: [u32; $n],
+    pub inst_id: [u32; $n],
+}
+
+impl $hitname {
+    pub fn empty() -> Self {
+        $hitname {
+            Ng_x: [0.0; $n],
+            Ng_y: [0.0; $n],
+            Ng_z: [0.0; $n],
+            u: [0.0; $n],
+            v: [0.0; $n],
+            prim_id: [INVALID_ID; $n],
+            geom_id: [INVALID_ID; $n],
+            inst_id: [INVALID_ID; $n],
+        }
+    }
+
+    pub fn get(&self, i: usize) -> Hit {
+        Hit {
+            Ng: Vector3::new(self.Ng_x[i], self.Ng_y[i], self.Ng_z[i]),
+            uv: Vector2::new(self.u[i], self.v[i]),
+            prim_id: self.prim_id[i].into(),
+            geom_id: self.geom_id[i].into(),
+            inst_id: self.inst_id[i].into(),
+        }
+    }
+
+    pub fn is_hit(&self, i: usize) -> bool {
+        self.geom_id[i] != INVALID_ID
+    }
+}
+
+#[repr(C)]
+#[repr(align($align))]
+#[derive(Debug, Copy, Clone)]
+pub struct $rayhitname {
+    pub ray: $rayname,
+    pub hit: $hitname,
+}
+
+impl $rayhitname {
+    pub fn from_rays(rays: &[Ray; $n]) -> Self {
+        $rayhitname {
+            ray: $rayname::from_rays(rays),
+            hit: $hitname::empty(),
+        }
+    }
+
+    pub fn as_raw_ptr(&mut self) -> *mut $rtcrayhit {
+        self as *mut $rayhitname as *mut $rtcrayhit
+    }
+}
+
+#[test]
+fn $layout_test() {
+    assert_eq!(std::mem::size_of::<$rayhitname>(), std::mem::size_of::<$rtcrayhit>());
+    assert_eq!(offset_of!($rayname, tnear), offset_of!($rtcray, tnear));
+    assert_eq!(offset_of!($rayname, time), offset_of!($rtcray, time));
+    assert_eq!(offset_of!($rayname, flags), offset_of!($rtcray, flags));
+    assert_eq!(offset_of!($hitname, u), offset_of!($rtchit, u));
+    assert_eq!(offset_of!($hitname, geom_id), offset_of!($rtchit, geomID));
+    assert_eq!(offset_of!($hitname, inst_id), offset_of!($rtchit, instID));
+    assert_eq!(offset_of!($rayhitname, hit), offset_of!($rtcrayhit, hit));
+}
+)}
+
+ray_packet_def!(Ray4, Hit4, RayHit4, 4, 16, RTCRay4, RTCHit4, RTCRayHit4, test_packet4_layout);
+ray_packet_def!(Ray8, Hit8, RayHit8, 8, 32, RTCRay8, RTCHit8, RTCRayHit8, test_packet8_layout);
+ray_packet_def!(Ray16, Hit16, RayHit16, 16, 64, RTCRay16, RTCHit16, RTCRayHit16, test_packet16_layout);
+
+// Embree's RTCRayN and RTCHitN are N wide structures of arrays where every field is 4 bytes
+pub(crate) const SOA_RAY_FIELDS: usize = 12;
+pub(crate) const SOA_TNEAR: usize = 3;
+pub(crate) const SOA_TFAR: usize = 8;
+
+/// Pointer to `lane` of field number `field` in a structure of arrays of width `n`
+pub(crate) unsafe fn soa_field<F>(base: *mut f32, field: usize, n: usize, lane: usize) -> *mut F {
+    base.add(field * n + lane) as *mut F
+}
+
+pub(crate) unsafe fn read_soa_ray(rays: *mut f32, n: usize, lane: usize) -> Ray {
+    let f = |field| *soa_field::<f32>(rays, field, n, lane);
+    let u = |field| *soa_field::<u32>(rays, field, n, lane);
+    Ray {
+        origin: Point3::new(f(0), f(1), f(2)),
+        tnear: f(SOA_TNEAR),
+        dir: Vector3::new(f(4), f(5), f(6)),
+        time: f(7),
+        tfar: f(SOA_TFAR),
+        mask: u(9),
+        id: u(10),
+        flags: u(11),
+    }
+}
+
+pub(crate) unsafe fn read_soa_hit(hits: *mut f32, n: usize, lane: usize) -> Hit {
+    let f = |field| *soa_field::<f32>(hits, field, n, lane);
+    let u = |field| *soa_field::<u32>(hits, field, n, lane);
+    Hit {
+        Ng: Vector3::new(f(0), f(1), f(2)),
+        uv: Vector2::new(f(3), f(4)),
+        prim_id: u(5).into(),
+        geom_id: u(6).into(),
+        inst_id: u(7).into(),
+    }
+}
+
+pub(crate) unsafe fn write_soa_hit(hits: *mut f32, n: usize, lane: usize, hit: &Hit) {
+    *soa_field::<f32>(hits, 0, n, lane) = hit.Ng.x;
+    *soa_field::<f32>(hits, 1, n, lane) = hit.Ng.y;
+    *soa_field::<f32>(hits, 2, n, lane) = hit.Ng.z;
+    *soa_field::<f32>(hits, 3, n, lane) = hit.uv.x;
+    *soa_field::<f32>(hits, 4, n, lane) = hit.uv.y;
+    *soa_field::<u32>(hits, 5, n, lane) = hit.prim_id.id;
+    *soa_field::<u32>(hits, 6, n, lane) = hit.geom_id.id;
+    *soa_field::<u32>(hits, 7, n, lane) = hit.inst_id.id;
+}
+
+#[test]
+fn test_soa_hit_roundtrip() {
+    let mut hit = RayHit4::from_rays(&[Ray::new(Point3::origin(), Vector3::unit_z(), 0.0, 1.0); 4]);
+    let h = Hit {
+        Ng: Vector3::new(1.0, 2.0, 3.0),
+        uv: Vector2::new(0.25, 0.5),
+        prim_id: GeomID::new(7),
+        geom_id: GeomID::new(3),
+        inst_id: GeomID::invalid(),
+    };
+    unsafe {
+        let base = hit.as_raw_ptr() as *mut f32;
+        write_soa_hit(base.add(SOA_RAY_FIELDS * 4), 4, 2, &h);
+        assert_eq!(read_soa_ray(base, 4, 2).tfar, 1.0);
+    }
+    assert_eq!(hit.hit.get(2).prim_id, h.prim_id);
+    assert_eq!(hit.hit.get(2).uv, h.uv);
+    assert!(!hit.hit.is_hit(1));
+}
@@ -12,6 +12,8 @@ use ray::*;
 pub struct Scene {
     handle: SceneHandle,
     geometries: VecMap<Box<dyn Geometry>>,
+    /// Whether any geometry has a `prim_id_map` to apply to hits
+    remaps_prim_ids: bool,
 }
 
 pub struct SceneBuilder {
@@ -71,6 +73,7 @@ impl SceneBuilder {
         
         boxed.set_geom_id(id);
         boxed.bind_buffers();
+        unsafe { boxed.handle_mut().bind_filter_functions::<T>(); }
         boxed.handle_mut().commit();
         
         self.geometries.insert(id as usize, boxed as Box<dyn Geometry>);
@@ -94,9 +97,11 @@ impl SceneBuilder {
         unsafe {
             rtcCommitScene(self.handle.ptr);
         }
+        let remaps_prim_ids = self.geometries.values().any(|g| g.prim_id_map().is_some());
         Scene {
             handle: self.handle,
             geometries: self.geometries,
+            remaps_prim_ids,
         }
     }
 }
@@ -121,6 +126,26 @@ fn empty_intersect_context() -> RTCIntersectContext {
     }
 }
 
+// Embree reads the valid masks with aligned SIMD loads
+#[repr(C, align(16))]
+struct ValidMask4([i32; 4]);
+#[repr(C, align(32))]
+struct ValidMask8([i32; 8]);
+#[repr(C, align(64))]
+struct ValidMask16([i32; 16]);
+
+fn valid_mask4(valid: &[bool; 4]) -> ValidMask4 {
+    ValidMask4(valid.map(|v| if v { -1 } else { 0 }))
+}
+
+fn valid_mask8(valid: &[bool; 8]) -> ValidMask8 {
+    ValidMask8(valid.map(|v| if v { -1 } else { 0 }))
+}
+
+fn valid_mask16(valid: &[bool; 16]) -> ValidMask16 {
+    ValidMask16(valid.map(|v| if v { -1 } else { 0 }))
+}
+
 // struct GeometryQueryHandle<'a> {
 //     ptr: RTCGeometry,
 //     phantom: ::std::marker::PhantomData<&'a ()>,
@@ -131,12 +156,35 @@ fn empty_intersect_context() -> RTCIntersectContext {
 // }
 
 impl Scene {
+    pub fn geometry(&self, id: GeomID) -> Option<&dyn Geometry> {
+        if id.is_invalid() {
+            return None;
+        }
+        self.geometries.get(id.unwrap() as usize).map(|g| g.as_ref())
+    }
+
+    /// Returns the geometry with the given id if it has type T
+    pub fn downcast_geometry<T: Geometry>(&self, id: GeomID) -> Option<&T> {
+        self.geometry(id).and_then(|g| g.downcast_ref::<T>())
+    }
+
     pub fn bounds(&self) -> Bounds {
         let mut b = Bounds::zero();
         unsafe { rtcGetSceneBounds(self.handle.ptr, b.as_raw_ptr()); }
         b
     }
 
+    /// The primitive id reported to users for a hit on a geometry of this scene
+    fn map_prim_id(&self, geom_id: u32, inst_id: u32, prim_id: u32) -> u32 {
+        if !self.remaps_prim_ids || geom_id == INVALID_ID || inst_id != INVALID_ID {
+            return prim_id;
+        }
+        match self.geometries.get(geom_id as usize).and_then(|g| g.prim_id_map()) {
+            Some(map) => map[prim_id as usize],
+            None => prim_id,
+        }
+    }
+
     pub fn intersect(&self, rayhit: &mut RayHit) {
         let mut context: RTCIntersectContext = empty_intersect_context();
         unsafe {
@@ -144,6 +192,8 @@ impl Scene {
                 &mut context,
                 rayhit.as_raw_ptr());
         }
+        let hit = &mut rayhit.hit;
+        hit.prim_id = self.map_prim_id(hit.geom_id.id, hit.inst_id.id, hit.prim_id.id).into();
     }
 
     pub fn occluded(&self, ray: &mut Ray) -> bool {
@@ -156,6 +206,94 @@ impl Scene {
         ray.tfar == std::f32::NEG_INFINITY
     }
 
+    pub fn intersect4(&self, valid: &[bool; 4], rayhit: &mut RayHit4) {
+        let mut context: RTCIntersectContext = empty_intersect_context();
+        let valid = valid_mask4(valid);
+        unsafe {
+            rtcIntersect4(valid.0.as_ptr(), self.handle.as_ptr(), &mut context, rayhit.as_raw_ptr());
+        }
+        let hit = &mut rayhit.hit;
+        for i in 0..4 {
+            hit.prim_id[i] = self.map_prim_id(hit.geom_id[i], hit.inst_id[i], hit.prim_id[i]);
+        }
+    }
+
+    pub fn intersect8(&self, valid: &[bool; 8], rayhit: &mut RayHit8) {
+        let mut context: RTCIntersectContext = empty_intersect_context();
+        let valid = valid_mask8(valid);
+        unsafe {
+            rtcIntersect8(valid.0.as_ptr(), self.handle.as_ptr(), &mut context, rayhit.as_raw_ptr());
+        }
+        let hit = &mut rayhit.hit;
+        for i in 0..8 {
+            hit.prim_id[i] = self.map_prim_id(hit.geom_id[i], hit.inst_id[i], hit.prim_id[i]);
+        }
+    }
+
+    pub fn intersect16(&self, valid: &[bool; 16], rayhit: &mut RayHit16) {
+        let mut context: RTCIntersectContext = empty_intersect_context();
+        let valid = valid_mask16(valid);
+        unsafe {
+            rtcIntersect16(valid.0.as_ptr(), self.handle.as_ptr(), &mut context, rayhit.as_raw_ptr());
+        }
+        let hit = &mut rayhit.hit;
+        for i in 0..16 {
+            hit.prim_id[i] = self.map_prim_id(hit.geom_id[i], hit.inst_id[i], hit.prim_id[i]);
+        }
+    }
+
+    /// Returns which of the valid rays are occluded
+    pub fn occluded4(&self, valid: &[bool; 4], ray: &mut Ray4) -> [bool; 4] {
+        let mut context: RTCIntersectContext = empty_intersect_context();
+        let mask = valid_mask4(valid);
+        unsafe {
+            rtcOccluded4(mask.0.as_ptr(), self.handle.as_ptr(), &mut context, ray.as_raw_ptr());
+        }
+        let mut occluded = [false; 4];
+        for i in 0..4 {
+            occluded[i] = valid[i] && ray.tfar[i] == f32::NEG_INFINITY;
+        }
+        occluded
+    }
+
+    /// Returns which of the valid rays are occluded
+    pub fn occluded8(&self, valid: &[bool; 8], ray: &mut Ray8) -> [bool; 8] {
+        let mut context: RTCIntersectContext = empty_intersect_context();
+        let mask = valid_mask8(valid);
+        unsafe {
+            rtcOccluded8(mask.0.as_ptr(), self.handle.as_ptr(), &mut context, ray.as_raw_ptr());
+        }
+        let mut occluded = [false; 8];
+        for i in 0..8 {
+            occluded[i] = valid[i] && ray.tfar[i] == f32::NEG_INFINITY;
+        }
+        occluded
+    }
+
+    /// Returns which of the valid rays are occluded
+    pub fn occluded16(&self, valid: &[bool; 16], ray: &mut Ray16) -> [bool; 16] {
+        let mut context: RTCIntersectContext = empty_intersect_context();
+        let mask = valid_mask16(valid);
+        unsafe {
+            rtcOccluded16(mask.0.as_ptr(), self.handle.as_ptr(), &mut context, ray.as_raw_ptr());
+        }
+        let mut occluded = [false; 16];
+        for i in 0..16 {
+            occluded[i] = valid[i] && ray.tfar[i] == f32::NEG_INFINITY;
+        }
+        occluded
+    }
+
+    /// Interpolates the named vertex attribute of the hit geometry at the hit location.
+    /// Returns None for a miss or if the geometry has no attribute with that name
+    pub fn interpolate_attribute(&self, hit: &Hit, name: &str) -> Option<Vec<f32>> {
+        if !hit.is_hit() || hit.prim_id.is_invalid() {
+            return None;
+        }
+        let geometry = self.geometry(hit.geom_id)?;
+        geometry.interpolate_attribute(name, hit.prim_id.id, hit.uv)
+    }
+
     // fn query(&self, id: GeomID) -> GeometryQueryHandle<'_> {
     //     unimplemented!()
     // }
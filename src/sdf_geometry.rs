@@ -0,0 +1,141 @@
+use std::f32;
+
+use cgmath::*;
+
+use common::*;
+use ray::*;
+use user_geometry::*;
+
+/// A signed distance field rendered by sphere tracing. Multiple fields can share one
+/// `SdfGeometry` and they're placed in the same BVH as any other geometry in the scene
+pub type SdfGeometry = UserGeometry<Sdf>;
+
+pub struct Sdf {
+    distance: Box<dyn Fn(Point3<f32>) -> f32 + Send + Sync>,
+    bounds: Bounds,
+    lipschitz: f32,
+    /// Distance from the surface at which a hit is reported
+    pub epsilon: f32,
+    /// Steps taken before giving up on a ray, which is then treated as a miss
+    pub max_steps: u32,
+}
+
+impl Sdf {
+    /// `distance` must be negative inside the surface and is only evaluated within `bounds`.
+    /// `lipschitz` bounds how fast it changes, 1.0 for an exact distance field
+    pub fn new<F>(bounds: Bounds, lipschitz: f32, distance: F) -> Self
+        where F: Fn(Point3<f32>) -> f32 + Send + Sync + 'static {
+        assert!(lipschitz > 0.0, "Lipschitz bound must be positive");
+        let diagonal = (bounds.upper - bounds.lower).magnitude();
+        Sdf {
+            distance: Box::new(distance),
+            bounds,
+            lipschitz,
+            epsilon: diagonal * 1e-4,
+            max_steps: 256,
+        }
+    }
+
+    pub fn distance(&self, p: Point3<f32>) -> f32 {
+        (self.distance)(p)
+    }
+
+    /// Unnormalized gradient of the field by central differences
+    pub fn gradient(&self, p: Point3<f32>) -> Vector3<f32> {
+        let h = self.epsilon;
+        let dx = Vector3::new(h, 0.0, 0.0);
+        let dy = Vector3::new(0.0, h, 0.0);
+        let dz = Vector3::new(0.0, 0.0, h);
+        Vector3::new(
+            self.distance(p + dx) - self.distance(p - dx),
+            self.distance(p + dy) - self.distance(p - dy),
+            self.distance(p + dz) - self.distance(p - dz))
+    }
+
+    /// The range of `ray` within the bounding box, if any
+    fn clip(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let mut t0 = ray.tnear;
+        let mut t1 = ray.tfar;
+        for axis in 0..3 {
+            let inv_dir = 1.0 / ray.dir[axis];
+            let mut near = (self.bounds.lower[axis] - ray.origin[axis]) * inv_dir;
+            let mut far = (self.bounds.upper[axis] - ray.origin[axis]) * inv_dir;
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            // NaNs from a zero direction with the origin on a slab are ignored
+            t0 = if near > t0 { near } else { t0 };
+            t1 = if far < t1 { far } else { t1 };
+            if t0 > t1 {
+                return None;
+            }
+        }
+        Some((t0, t1))
+    }
+}
+
+impl UserPrimitive for Sdf {
+    fn intersect(&self, ray: &Ray) -> UserPrimHit {
+        let (t_start, t_end) = match self.clip(ray) {
+            Some(range) => range,
+            None => return UserPrimHit::miss(),
+        };
+        // Steps are in world space so account for unnormalized directions
+        let step_scale = 1.0 / (self.lipschitz * ray.dir.magnitude());
+        let mut t = t_start;
+        // Rays spawned on the surface start within epsilon of it, so they have to step
+        //  off it before a hit can be reported. Rays entering the bounds don't
+        let mut leaving_surface = !ray.in_range(t_start);
+        for _ in 0..self.max_steps {
+            if t > t_end {
+                break;
+            }
+            let p = ray.point_at_dist(t);
+            // Rays starting inside the surface trace to where they leave it
+            let d = self.distance(p).abs();
+            if d < self.epsilon {
+                if !leaving_surface && ray.in_range(t) {
+                    return UserPrimHit::new(t, self.gradient(p), Vector2::zero());
+                }
+                t += self.epsilon.max(d) * step_scale;
+                continue;
+            }
+            leaving_surface = false;
+            t += d * step_scale;
+        }
+        UserPrimHit::miss()
+    }
+
+    fn bounds(&self) -> Bounds {
+        self.bounds
+    }
+}
+
+#[test]
+fn test_sdf_sphere() {
+    let sdf = Sdf::new(Bounds::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0)), 1.0,
+        |p| p.to_vec().magnitude() - 1.0);
+    let hit = sdf.intersect(&Ray::new(Point3::new(0.0, 0.0, -5.0), Vector3::new(0.0, 0.0, 2.0), 0.0, f32::INFINITY));
+    assert!((hit.t - 2.0).abs() < 1e-3);
+    assert!(hit.Ng.normalize().z < -0.999);
+
+    let miss = sdf.intersect(&Ray::new(Point3::new(0.0, 2.0, -5.0), Vector3::unit_z(), 0.0, f32::INFINITY));
+    assert!(miss.t < 0.0);
+
+    let inside = sdf.intersect(&Ray::new(Point3::origin(), Vector3::unit_x(), 0.0, f32::INFINITY));
+    assert!((inside.t - 1.0).abs() < 1e-3);
+}
+
+#[test]
+fn test_sdf_ray_from_surface() {
+    // Two spheres side by side, which is concave between them
+    let sdf = Sdf::new(Bounds::new(Point3::new(-3.0, -1.0, -1.0), Point3::new(3.0, 1.0, 1.0)), 1.0, |p| {
+        let a = (p - Point3::new(-2.0, 0.0, 0.0)).magnitude() - 1.0;
+        let b = (p - Point3::new(2.0, 0.0, 0.0)).magnitude() - 1.0;
+        a.min(b)
+    });
+    // Leaves the right side of the left sphere towards the other sphere
+    let hit = sdf.intersect(&Ray::new(Point3::new(-1.0, 0.0, 0.0), Vector3::unit_x(), 0.0, f32::INFINITY));
+    assert!((hit.t - 2.0).abs() < 1e-3);
+    assert!(hit.Ng.normalize().x < -0.999);
+}
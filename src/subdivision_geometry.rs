@@ -0,0 +1,708 @@
+use std::cell::RefCell;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use cgmath::*;
+
+use sys::*;
+
+use common::*;
+use device::*;
+use geometry::*;
+
+/// How the subdivision surface treats its boundary edges and vertices
+#[repr(i32)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SubdivisionMode {
+    /// Boundary patches are ignored so the surface may shrink away from the boundary
+    NoBoundary = RTC_SUBDIVISION_MODE_NO_BOUNDARY,
+    /// Boundary patches are smoothly subdivided
+    SmoothBoundary = RTC_SUBDIVISION_MODE_SMOOTH_BOUNDARY,
+    /// Like `SmoothBoundary` but corner vertices with only two edges are pinned
+    PinCorners = RTC_SUBDIVISION_MODE_PIN_CORNERS,
+    /// All boundary edges are linear so the boundary follows the cage
+    PinBoundary = RTC_SUBDIVISION_MODE_PIN_BOUNDARY,
+    /// All edges are linear so the surface is the cage itself
+    PinAll = RTC_SUBDIVISION_MODE_PIN_ALL,
+}
+
+into_primitive!(SubdivisionMode, i32);
+
+/// A crease between two vertices of the cage. Weights range from 0 (smooth) to infinity (sharp)
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct EdgeCrease {
+    pub v0: u32,
+    pub v1: u32,
+    pub weight: f32,
+}
+
+impl EdgeCrease {
+    pub fn new(v0: u32, v1: u32, weight: f32) -> Self {
+        EdgeCrease {
+            v0,
+            v1,
+            weight,
+        }
+    }
+}
+
+/// A crease on a single vertex of the cage. Weights range from 0 (smooth) to infinity (sharp)
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct VertexCrease {
+    pub vertex: u32,
+    pub weight: f32,
+}
+
+impl VertexCrease {
+    pub fn new(vertex: u32, weight: f32) -> Self {
+        VertexCrease {
+            vertex,
+            weight,
+        }
+    }
+}
+
+// Byte offsets of the weights, which are bound as buffers of their own
+const EDGE_CREASE_WEIGHT_OFFSET: usize = 8;
+const VERTEX_CREASE_WEIGHT_OFFSET: usize = 4;
+
+#[test]
+fn test_crease_layout() {
+    // The index and weight buffers are bound to the same array, so the weights
+    // must follow the indices with the stride of the whole crease
+    assert_eq!(std::mem::size_of::<EdgeCrease>(), 12);
+    assert_eq!(offset_of!(EdgeCrease, v0), 0);
+    assert_eq!(offset_of!(EdgeCrease, v1), 4);
+    assert_eq!(offset_of!(EdgeCrease, weight), EDGE_CREASE_WEIGHT_OFFSET);
+    assert_eq!(std::mem::size_of::<VertexCrease>(), 8);
+    assert_eq!(offset_of!(VertexCrease, vertex), 0);
+    assert_eq!(offset_of!(VertexCrease, weight), VERTEX_CREASE_WEIGHT_OFFSET);
+}
+
+/// A batch of surface points passed to a displacement function. On entry `p` holds the
+/// undisplaced positions and on exit it should hold the displaced positions
+#[allow(non_snake_case)]
+pub struct DisplacementBatch<'a> {
+    /// The face being displaced
+    pub prim_id: u32,
+    pub time_step: u32,
+    pub u: &'a [f32],
+    pub v: &'a [f32],
+    pub Ng_x: &'a [f32],
+    pub Ng_y: &'a [f32],
+    pub Ng_z: &'a [f32],
+    pub P_x: &'a mut [f32],
+    pub P_y: &'a mut [f32],
+    pub P_z: &'a mut [f32],
+}
+
+impl<'a> DisplacementBatch<'a> {
+    pub fn len(&self) -> usize {
+        self.u.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.u.is_empty()
+    }
+
+    pub fn uv(&self, i: usize) -> Vector2<f32> {
+        Vector2::new(self.u[i], self.v[i])
+    }
+
+    /// The unnormalized geometric normal of the undisplaced surface
+    pub fn normal(&self, i: usize) -> Vector3<f32> {
+        Vector3::new(self.Ng_x[i], self.Ng_y[i], self.Ng_z[i])
+    }
+
+    pub fn position(&self, i: usize) -> Point3<f32> {
+        Point3::new(self.P_x[i], self.P_y[i], self.P_z[i])
+    }
+
+    pub fn set_position(&mut self, i: usize, p: Point3<f32>) {
+        self.P_x[i] = p.x;
+        self.P_y[i] = p.y;
+        self.P_z[i] = p.z;
+    }
+}
+
+/// An additional index buffer over the faces of a `SubdivisionMesh`. Vertex attributes
+/// bound to a topology are indexed by it instead of the mesh's indices, which allows
+/// face-varying data such as texture coordinates with seams
+#[derive(Debug, Clone)]
+pub struct Topology {
+    pub indices: Vec<u32>,
+    pub mode: SubdivisionMode,
+}
+
+struct Displacement {
+    func: Box<dyn Fn(&mut DisplacementBatch) + Send + Sync>,
+    bound: f32,
+    /// Set once a point has been clamped, so the warning is only logged once
+    exceeded_bound: AtomicBool,
+}
+
+/// A Catmull-Clark subdivision surface defined by a cage of arbitrary polygons.
+/// Hits report the cage face as `prim_id`
+pub struct SubdivisionMesh {
+    pub(crate) handle: GeometryHandle,
+    /// Number of vertices in each face
+    pub faces: Vec<u32>,
+    /// Vertex indices of each face, stored consecutively. Every index is also an edge from
+    /// that vertex to the next one in the face
+    pub indices: Vec<u32>,
+    pub vertices: Vec<Point3<f32>>,
+    pub edge_creases: Vec<EdgeCrease>,
+    pub vertex_creases: Vec<VertexCrease>,
+    /// Faces which are left out of the surface
+    pub holes: Vec<u32>,
+    /// Tessellation level of each edge, parallel to `indices`. If this is not set every
+    /// edge uses the tessellation rate
+    pub levels: Option<Vec<f32>>,
+    /// Vertex attributes, bound to slots in the order they were added
+    pub attributes: Vec<VertexAttribute>,
+    /// The topology each attribute is indexed by, parallel to `attributes`
+    attribute_topologies: Vec<u32>,
+    /// Topologies after the mesh's own, which is topology 0
+    topologies: Vec<Topology>,
+    boundary_mode: SubdivisionMode,
+    tessellation_rate: f32,
+    displacement: Option<Displacement>,
+}
+
+impl SubdivisionMesh {
+    pub fn new(device: &Device, faces: Vec<u32>, indices: Vec<u32>, vertices: Vec<Point3<f32>>) -> Self {
+        debug_assert!(faces.iter().map(|&n| n as usize).sum::<usize>() == indices.len(),
+            "Face vertex counts don't match the number of indices");
+        let handle = GeometryHandle::new(device, GeometryType::Subdivision);
+        SubdivisionMesh {
+            handle,
+            faces,
+            indices,
+            vertices,
+            edge_creases: Vec::new(),
+            vertex_creases: Vec::new(),
+            holes: Vec::new(),
+            levels: None,
+            attributes: Vec::new(),
+            attribute_topologies: Vec::new(),
+            topologies: Vec::new(),
+            boundary_mode: SubdivisionMode::SmoothBoundary,
+            tessellation_rate: 2.0,
+            displacement: None,
+        }
+    }
+
+    pub fn set_edge_creases(&mut self, creases: Vec<EdgeCrease>) {
+        self.edge_creases = creases;
+    }
+
+    pub fn set_vertex_creases(&mut self, creases: Vec<VertexCrease>) {
+        self.vertex_creases = creases;
+    }
+
+    pub fn set_holes(&mut self, holes: Vec<u32>) {
+        self.holes = holes;
+    }
+
+    pub fn set_edge_levels(&mut self, levels: Vec<f32>) {
+        assert!(levels.len() == self.indices.len(), "There must be one level for every edge");
+        self.levels = Some(levels);
+    }
+
+    pub fn set_boundary_mode(&mut self, mode: SubdivisionMode) {
+        self.boundary_mode = mode;
+    }
+
+    pub fn boundary_mode(&self) -> SubdivisionMode {
+        self.boundary_mode
+    }
+
+    /// Sets the number of segments each edge is tessellated into when there are no edge levels
+    pub fn set_tessellation_rate(&mut self, rate: f32) {
+        self.tessellation_rate = rate;
+    }
+
+    pub fn tessellation_rate(&self) -> f32 {
+        self.tessellation_rate
+    }
+
+    /// Sets a function which displaces the points of the tessellated surface.
+    /// Embree runs the function while building and bounds the displaced surface itself.
+    /// `bound` is the largest distance the function promises to move a point, so the surface
+    /// stays within the cage's bounds grown by it. Points moved further are clamped back to it,
+    /// which `displacement_exceeded_bound` reports.
+    /// The function is called from Embree's build threads, possibly concurrently
+    pub fn set_displacement_function<F>(&mut self, bound: f32, func: F)
+        where F: Fn(&mut DisplacementBatch) + Send + Sync + 'static
+    {
+        self.displacement = Some(Displacement {
+            func: Box::new(func),
+            bound,
+            exceeded_bound: AtomicBool::new(false),
+        });
+    }
+
+    pub fn clear_displacement_function(&mut self) {
+        self.displacement = None;
+    }
+
+    /// The bound passed to `set_displacement_function`, or 0 if there is no displacement
+    pub fn displacement_bound(&self) -> f32 {
+        self.displacement.as_ref().map_or(0.0, |d| d.bound)
+    }
+
+    /// Whether the displacement function has moved a point further than its bound,
+    /// and had it clamped, since the function was set
+    pub fn displacement_exceeded_bound(&self) -> bool {
+        self.displacement.as_ref().is_some_and(|d| d.exceeded_bound.load(Ordering::Relaxed))
+    }
+
+    /// Adds a named vertex attribute and returns the slot it will be bound to.
+    /// An existing attribute with the same name is replaced and keeps its slot
+    pub fn add_vertex_attribute(&mut self, attribute: VertexAttribute) -> u32 {
+        assert!(attribute.vertex_count() == self.vertices.len(), "Attribute must have a value for every vertex");
+        self.insert_attribute(0, attribute)
+    }
+
+    /// Adds an index buffer with its own boundary mode and returns its topology id
+    pub fn add_topology(&mut self, indices: Vec<u32>, mode: SubdivisionMode) -> u32 {
+        assert!(indices.len() == self.indices.len(), "Topologies must have the same number of indices as the mesh");
+        self.topologies.push(Topology { indices, mode });
+        self.topologies.len() as u32
+    }
+
+    pub fn topology_count(&self) -> u32 {
+        self.topologies.len() as u32 + 1
+    }
+
+    /// The indices of a topology, where topology 0 is the mesh's own indices
+    pub fn topology_indices(&self, topology: u32) -> &[u32] {
+        if topology == 0 {
+            &self.indices
+        } else {
+            &self.topologies[topology as usize - 1].indices
+        }
+    }
+
+    /// Adds a vertex attribute which is indexed by the given topology and returns the slot
+    /// it will be bound to. Interpolating the attribute at a hit uses that topology
+    pub fn add_face_varying_attribute(&mut self, topology: u32, attribute: VertexAttribute) -> u32 {
+        assert!(topology < self.topology_count(), "Invalid topology id");
+        assert!(self.topology_indices(topology).iter().all(|&i| (i as usize) < attribute.vertex_count()),
+            "Attribute must have a value for every index of its topology");
+        self.insert_attribute(topology, attribute)
+    }
+
+    /// The topology an attribute slot is indexed by
+    pub fn attribute_topology(&self, slot: u32) -> u32 {
+        self.attribute_topologies[slot as usize]
+    }
+
+    fn insert_attribute(&mut self, topology: u32, attribute: VertexAttribute) -> u32 {
+        let slot = insert_vertex_attribute(&mut self.attributes, attribute);
+        if slot as usize == self.attribute_topologies.len() {
+            self.attribute_topologies.push(topology);
+        } else {
+            self.attribute_topologies[slot as usize] = topology;
+        }
+        slot
+    }
+
+    pub fn get_vertex_attribute(&self, name: &str) -> Option<&VertexAttribute> {
+        self.attributes.iter().find(|a| a.name == name)
+    }
+
+    /// The first half edge of a face. The mesh must have been attached to a scene
+    pub fn first_half_edge(&self, face: u32) -> HalfEdge<'_> {
+        debug_assert!((face as usize) < self.faces.len());
+        let id = unsafe { rtcGetGeometryFirstHalfEdge(self.handle.as_raw_ptr(), face) };
+        HalfEdge { mesh: self, id, topology: 0 }
+    }
+
+    /// The first half edge of a face, following the connectivity of another topology
+    pub fn first_half_edge_in(&self, topology: u32, face: u32) -> HalfEdge<'_> {
+        self.first_half_edge(face).in_topology(topology)
+    }
+
+    /// The half edge which starts at index `id` of the index buffer
+    pub fn half_edge(&self, id: u32) -> HalfEdge<'_> {
+        debug_assert!((id as usize) < self.indices.len());
+        HalfEdge { mesh: self, id, topology: 0 }
+    }
+
+    /// Iterates over the half edges of a face in order
+    pub fn face_edges(&self, face: u32) -> FaceEdges<'_> {
+        let first = self.first_half_edge(face);
+        FaceEdges {
+            first,
+            next: Some(first),
+        }
+    }
+
+    /// The faces which share an edge with `face`
+    pub fn adjacent_faces(&self, face: u32) -> Vec<u32> {
+        let mut faces: Vec<u32> = self.face_edges(face)
+            .filter_map(|e| e.opposite())
+            .map(|e| e.face())
+            .filter(|&f| f != face)
+            .collect();
+        faces.sort();
+        faces.dedup();
+        faces
+    }
+
+    /// The index of the first index (and edge) of every face
+    pub fn face_offsets(&self) -> Vec<u32> {
+        let mut offset = 0;
+        self.faces.iter().map(|&n| {
+            let start = offset;
+            offset += n;
+            start
+        }).collect()
+    }
+}
+
+#[test]
+fn test_face_varying_attributes() {
+    let device = Device::new();
+    // Two quads sharing the edge from vertex 1 to 4, with a uv seam along it
+    let vertices = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(2.0, 0.0, 0.0),
+        Point3::new(0.0, 1.0, 0.0), Point3::new(1.0, 1.0, 0.0), Point3::new(2.0, 1.0, 0.0)];
+    let mut mesh = SubdivisionMesh::new(&device, vec![4, 4], vec![0, 1, 4, 3, 1, 2, 5, 4], vertices);
+    let uv_topology = mesh.add_topology(vec![0, 1, 2, 3, 4, 5, 6, 7], SubdivisionMode::PinBoundary);
+    assert_eq!(uv_topology, 1);
+    assert_eq!(mesh.topology_count(), 2);
+    assert_eq!(mesh.topology_indices(0), &[0, 1, 4, 3, 1, 2, 5, 4][..]);
+    assert_eq!(mesh.topology_indices(uv_topology), &[0, 1, 2, 3, 4, 5, 6, 7][..]);
+
+    let weights = VertexAttribute::new("weight", Format::f32x1, vec![1.0; 6]);
+    assert_eq!(mesh.add_vertex_attribute(weights), 0);
+    let uvs = VertexAttribute::new("uv", Format::f32x2, vec![0.0; 16]);
+    assert_eq!(mesh.add_face_varying_attribute(uv_topology, uvs), 1);
+    assert_eq!(mesh.attribute_topology(0), 0);
+    assert_eq!(mesh.attribute_topology(1), uv_topology);
+
+    // Replacing an attribute keeps its slot but takes the new topology
+    let uvs = VertexAttribute::new("uv", Format::f32x2, vec![0.0; 12]);
+    assert_eq!(mesh.add_face_varying_attribute(0, uvs), 1);
+    assert_eq!(mesh.attribute_topology(1), 0);
+}
+
+#[test]
+fn test_subdivision_face_layout() {
+    let device = Device::new();
+    // A quad and a triangle sharing the edge from vertex 1 to 2
+    let vertices = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(1.0, 1.0, 0.0),
+        Point3::new(0.0, 1.0, 0.0), Point3::new(2.0, 0.5, 0.0)];
+    let mut mesh = SubdivisionMesh::new(&device, vec![4, 3], vec![0, 1, 2, 3, 1, 4, 2], vertices);
+    assert_eq!(mesh.face_offsets(), vec![0, 4]);
+    mesh.set_edge_levels(vec![4.0; 7]);
+    assert_eq!(mesh.levels.as_ref().map(|l| l.len()), Some(7));
+    assert_eq!(mesh.boundary_mode(), SubdivisionMode::SmoothBoundary);
+}
+
+#[test]
+fn test_displacement_exceeded_bound() {
+    use ray::{Hit, Ray, RayHit};
+    use scene::SceneBuilder;
+
+    let device = Device::new();
+    let vertices = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(1.0, 1.0, 0.0), Point3::new(0.0, 1.0, 0.0)];
+    let mut mesh = SubdivisionMesh::new(&device, vec![4], vec![0, 1, 2, 3], vertices);
+    // Moves every point twice as far as its bound
+    mesh.set_displacement_function(1.0, |batch| {
+        for z in batch.P_z.iter_mut() {
+            *z += 2.0;
+        }
+    });
+    assert!(!mesh.displacement_exceeded_bound());
+    let mut builder = SceneBuilder::new(&device);
+    let geom_id = builder.attach(mesh);
+    let scene = builder.build();
+
+    let mut rayhit = RayHit { ray: Ray::new(Point3::new(0.5, 0.5, 5.0), -Vector3::unit_z(), 0.0, f32::INFINITY), hit: Hit::empty() };
+    scene.intersect(&mut rayhit);
+    assert!((rayhit.ray.tfar - 4.0).abs() < 1e-3);
+    assert!(scene.downcast_geometry::<SubdivisionMesh>(geom_id).unwrap().displacement_exceeded_bound());
+}
+
+impl Geometry for SubdivisionMesh {
+    fn handle(&self) -> &GeometryHandle {
+        &self.handle
+    }
+
+    fn handle_mut(&mut self) -> &mut GeometryHandle {
+        &mut self.handle
+    }
+
+    fn bind_buffers(&mut self) {
+        self.vertices.reserve(1);
+
+        unsafe {
+            let ptr = self.handle.as_raw_ptr();
+            self.handle.bind_shared_geometry_buffer(&self.faces, BufferType::Face, Format::u32x1, 0, 0);
+            self.handle.bind_shared_geometry_buffer(&self.indices, BufferType::Index, Format::u32x1, 0, 0);
+            self.handle.bind_shared_geometry_buffer(&self.vertices, BufferType::Vertex, Format::f32x3, 0, 0);
+
+            if !self.edge_creases.is_empty() {
+                self.handle.bind_shared_geometry_buffer(&self.edge_creases, BufferType::EdgeCreaseIndex, Format::u32x2, 0, 0);
+                self.handle.bind_shared_geometry_buffer(&self.edge_creases, BufferType::EdgeCreaseWeight, Format::f32x1, 0, EDGE_CREASE_WEIGHT_OFFSET);
+            }
+            if !self.vertex_creases.is_empty() {
+                self.handle.bind_shared_geometry_buffer(&self.vertex_creases, BufferType::VertexCreaseIndex, Format::u32x1, 0, 0);
+                self.handle.bind_shared_geometry_buffer(&self.vertex_creases, BufferType::VertexCreaseWeight, Format::f32x1, 0, VERTEX_CREASE_WEIGHT_OFFSET);
+            }
+            if !self.holes.is_empty() {
+                self.handle.bind_shared_geometry_buffer(&self.holes, BufferType::Hole, Format::u32x1, 0, 0);
+            }
+            if let Some(ref mut levels) = self.levels {
+                self.handle.bind_shared_geometry_buffer(levels, BufferType::Level, Format::f32x1, 0, 0);
+            }
+
+            rtcSetGeometryTopologyCount(ptr, self.topology_count());
+            rtcSetGeometrySubdivisionMode(ptr, 0, self.boundary_mode.into());
+            for (i, topology) in self.topologies.iter().enumerate() {
+                let id = i as u32 + 1;
+                self.handle.bind_shared_geometry_buffer(&topology.indices, BufferType::Index, Format::u32x1, id, 0);
+                rtcSetGeometrySubdivisionMode(ptr, id, topology.mode.into());
+            }
+            rtcSetGeometryTessellationRate(ptr, self.tessellation_rate);
+
+            let displacement_fn = if self.displacement.is_some() { Some(displacement_func as _) } else { None };
+            rtcSetGeometryDisplacementFunction(ptr, displacement_fn);
+
+            bind_vertex_attributes(&mut self.handle, &mut self.attributes);
+            for (slot, &topology) in self.attribute_topologies.iter().enumerate() {
+                rtcSetGeometryVertexAttributeTopology(ptr, slot as u32, topology);
+            }
+        }
+    }
+
+    fn vertex_attribute(&self, name: &str) -> Option<AttributeSlot> {
+        find_vertex_attribute(&self.attributes, name)
+    }
+}
+
+unsafe extern "C" fn displacement_func(args: *const RTCDisplacementFunctionNArguments) {
+    let args = &*args;
+    // The user data is set to the geometry when it's attached to a scene
+    let mesh: &SubdivisionMesh = (args.geometryUserPtr as *const SubdivisionMesh).as_ref().unwrap();
+    let displacement = match mesh.displacement {
+        Some(ref d) => d,
+        None => return,
+    };
+
+    let n = args.N as usize;
+    let mut batch = DisplacementBatch {
+        prim_id: args.primID,
+        time_step: args.timeStep,
+        u: slice::from_raw_parts(args.u, n),
+        v: slice::from_raw_parts(args.v, n),
+        Ng_x: slice::from_raw_parts(args.Ng_x, n),
+        Ng_y: slice::from_raw_parts(args.Ng_y, n),
+        Ng_z: slice::from_raw_parts(args.Ng_z, n),
+        P_x: slice::from_raw_parts_mut(args.P_x, n),
+        P_y: slice::from_raw_parts_mut(args.P_y, n),
+        P_z: slice::from_raw_parts_mut(args.P_z, n),
+    };
+
+    UNDISPLACED.with(|original| {
+        let mut original = original.borrow_mut();
+        original.clear();
+        original.extend((0..n).map(|i| batch.position(i)));
+        (displacement.func)(&mut batch);
+        if clamp_displacement(&mut batch, &original, displacement.bound)
+            && !displacement.exceeded_bound.swap(true, Ordering::Relaxed)
+        {
+            warn!("Displacement function moved points further than its bound, they have been clamped");
+        }
+    });
+}
+
+thread_local! {
+    /// The positions of the batch being displaced, reused between batches on each build thread
+    static UNDISPLACED: RefCell<Vec<Point3<f32>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Moves points displaced further than `bound` from their `original` position back onto
+/// the bound, and points displaced to a non-finite position back to where they started.
+/// Returns true if a point was further than the bound allows for rounding
+fn clamp_displacement(batch: &mut DisplacementBatch, original: &[Point3<f32>], bound: f32) -> bool {
+    let mut exceeded = false;
+    for (i, &p) in original.iter().enumerate() {
+        let offset = batch.position(i) - p;
+        let dist = offset.magnitude();
+        if dist <= bound {
+            continue;
+        }
+        // Leave a little slack for rounding in the user's function
+        exceeded |= dist.is_nan() || dist > bound * 1.0001;
+        let clamped = if dist.is_finite() { p + offset * (bound / dist) } else { p };
+        batch.set_position(i, clamped);
+    }
+    exceeded
+}
+
+#[test]
+fn test_clamp_displacement() {
+    let u = [0.0; 3];
+    let ng = [0.0; 3];
+    let mut x = [0.0, 3.0, f32::NAN];
+    let mut y = [0.5, 4.0, 0.0];
+    let mut z = [0.0; 3];
+    let mut batch = DisplacementBatch {
+        prim_id: 0,
+        time_step: 0,
+        u: &u,
+        v: &u,
+        Ng_x: &ng,
+        Ng_y: &ng,
+        Ng_z: &ng,
+        P_x: &mut x,
+        P_y: &mut y,
+        P_z: &mut z,
+    };
+    let original = [Point3::new(0.0, 0.0, 0.0); 3];
+    assert!(clamp_displacement(&mut batch, &original, 1.0));
+    // Within the bound
+    assert_eq!(batch.position(0), Point3::new(0.0, 0.5, 0.0));
+    // Pulled back along the displacement
+    assert!((batch.position(1) - Point3::new(0.6, 0.8, 0.0)).magnitude() < 1.0e-6);
+    assert_eq!(batch.position(2), original[2]);
+    assert!(!clamp_displacement(&mut batch, &original, 1.0));
+}
+
+/// A cursor over the half edge topology of a `SubdivisionMesh`, as built by Embree
+/// when the mesh is committed. Each half edge starts at a vertex of one face and
+/// points to the next vertex of that face
+#[derive(Copy, Clone)]
+pub struct HalfEdge<'a> {
+    mesh: &'a SubdivisionMesh,
+    id: u32,
+    topology: u32,
+}
+
+impl<'a> HalfEdge<'a> {
+    /// The position of this half edge's start vertex in the index buffer
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The face this half edge belongs to
+    pub fn face(&self) -> u32 {
+        unsafe { rtcGetGeometryFace(self.mesh.handle.as_raw_ptr(), self.id) }
+    }
+
+    pub fn next(&self) -> HalfEdge<'a> {
+        let id = unsafe { rtcGetGeometryNextHalfEdge(self.mesh.handle.as_raw_ptr(), self.id) };
+        HalfEdge { id, ..*self }
+    }
+
+    pub fn prev(&self) -> HalfEdge<'a> {
+        let id = unsafe { rtcGetGeometryPreviousHalfEdge(self.mesh.handle.as_raw_ptr(), self.id) };
+        HalfEdge { id, ..*self }
+    }
+
+    /// The topology whose connectivity is used by `opposite` and `start_vertex`
+    pub fn topology(&self) -> u32 {
+        self.topology
+    }
+
+    /// The same half edge but navigating the connectivity of another topology.
+    /// Faces and half edge ids are shared between topologies but boundaries may not be
+    pub fn in_topology(&self, topology: u32) -> HalfEdge<'a> {
+        debug_assert!(topology < self.mesh.topology_count());
+        HalfEdge { topology, ..*self }
+    }
+
+    /// The half edge of the neighbouring face going the opposite way, or None on a boundary
+    pub fn opposite(&self) -> Option<HalfEdge<'a>> {
+        // Embree returns the edge itself for boundary edges
+        let id = unsafe { rtcGetGeometryOppositeHalfEdge(self.mesh.handle.as_raw_ptr(), self.topology, self.id) };
+        if id == self.id {
+            None
+        } else {
+            Some(HalfEdge { id, ..*self })
+        }
+    }
+
+    pub fn is_boundary(&self) -> bool {
+        self.opposite().is_none()
+    }
+
+    /// The vertex this half edge starts at, indexing into the data of its topology
+    pub fn start_vertex(&self) -> u32 {
+        self.mesh.topology_indices(self.topology)[self.id as usize]
+    }
+
+    pub fn end_vertex(&self) -> u32 {
+        self.next().start_vertex()
+    }
+}
+
+impl<'a> PartialEq for HalfEdge<'a> {
+    fn eq(&self, other: &HalfEdge<'a>) -> bool {
+        self.id == other.id && self.topology == other.topology
+    }
+}
+
+impl<'a> ::std::fmt::Debug for HalfEdge<'a> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("HalfEdge").field("id", &self.id).field("topology", &self.topology).finish()
+    }
+}
+
+/// Iterator over the half edges of a face, see `SubdivisionMesh::face_edges`
+pub struct FaceEdges<'a> {
+    first: HalfEdge<'a>,
+    next: Option<HalfEdge<'a>>,
+}
+
+impl<'a> Iterator for FaceEdges<'a> {
+    type Item = HalfEdge<'a>;
+
+    fn next(&mut self) -> Option<HalfEdge<'a>> {
+        let edge = self.next?;
+        let next = edge.next();
+        self.next = if next == self.first { None } else { Some(next) };
+        Some(edge)
+    }
+}
+
+#[test]
+fn test_half_edges() {
+    use scene::SceneBuilder;
+
+    let device = Device::new();
+    // A 2 x 2 grid of quads, with vertex x + 3 * y at (x, y)
+    let vertices = (0..9).map(|i| Point3::new((i % 3) as f32, (i / 3) as f32, 0.0)).collect();
+    let indices = vec![0, 1, 4, 3, 1, 2, 5, 4, 3, 4, 7, 6, 4, 5, 8, 7];
+    let mut builder = SceneBuilder::new(&device);
+    let geom_id = builder.attach(SubdivisionMesh::new(&device, vec![4; 4], indices, vertices));
+    let scene = builder.build();
+    let mesh = scene.downcast_geometry::<SubdivisionMesh>(geom_id).unwrap();
+
+    let first = mesh.first_half_edge(1);
+    assert_eq!((first.id(), first.face()), (4, 1));
+    assert_eq!((first.next().id(), first.prev().id()), (5, 7));
+    assert_eq!(first.next().next().next().next(), first);
+    assert_eq!(mesh.face_edges(1).map(|e| e.id()).collect::<Vec<_>>(), vec![4, 5, 6, 7]);
+
+    // The edge from vertex 1 to 4 is shared by faces 0 and 1
+    let shared = mesh.half_edge(1);
+    assert_eq!((shared.start_vertex(), shared.end_vertex()), (1, 4));
+    let opposite = shared.opposite().unwrap();
+    assert_eq!((opposite.id(), opposite.face()), (7, 1));
+    assert_eq!((opposite.start_vertex(), opposite.end_vertex()), (4, 1));
+    assert_eq!(opposite.opposite(), Some(shared));
+
+    // The edge from vertex 0 to 1 is on the border
+    assert!(mesh.half_edge(0).is_boundary());
+    assert!(!shared.is_boundary());
+    assert_eq!(mesh.face_edges(0).filter(|e| e.is_boundary()).count(), 2);
+
+    assert_eq!(mesh.adjacent_faces(0), vec![1, 2]);
+    assert_eq!(mesh.adjacent_faces(3), vec![1, 2]);
+}
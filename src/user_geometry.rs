@@ -1,5 +1,6 @@
 use std::ffi::c_void;
 use std::ptr;
+use std::slice;
 use std::u32;
 use std::f32;
 
@@ -12,10 +13,35 @@ use device::*;
 use geometry::*;
 use ray::*;
 
-pub trait UserPrimitive: 'static + Send + Sync {
+pub trait UserPrimitive: Send + Sync {
     fn intersect(&self, ray: &Ray) -> UserPrimHit;
     fn bounds(&self) -> Bounds;
 
+    /// Whether there is any intersection within the ray's range. Override this if any
+    /// hit can be found faster than the closest one
+    fn occluded(&self, ray: &Ray) -> bool {
+        ray.in_range(self.intersect(ray).t)
+    }
+
+    /// Intersects a packet of rays, writing a hit (or a miss) for every valid lane.
+    /// Override this to vectorize the intersection
+    fn intersect_n(&self, rays: &RayPacket, hits: &mut [UserPrimHit]) {
+        for (i, hit) in hits.iter_mut().enumerate().take(rays.len()) {
+            if rays.is_valid(i) {
+                *hit = self.intersect(&rays.get(i));
+            }
+        }
+    }
+
+    /// Tests a packet of rays for occlusion, setting `occluded` for every valid lane
+    fn occluded_n(&self, rays: &RayPacket, occluded: &mut [bool]) {
+        for (i, occluded) in occluded.iter_mut().enumerate().take(rays.len()) {
+            if rays.is_valid(i) {
+                *occluded = self.occluded(&rays.get(i));
+            }
+        }
+    }
+
     // TODO: Maybe expose this for convenience?
     // fn transform_by(&mut self, mat: Matrix4);
 }
@@ -27,6 +53,9 @@ pub struct UserPrimHit {
     pub t: f32,
     pub Ng: Vector3<f32>,
     pub uv: Vector2<f32>,
+    /// Reported as the hit's `prim_id` in place of the primitive's index when set,
+    /// for primitives made up of several parts
+    pub prim_id: Option<u32>,
 }
 
 impl UserPrimHit {
@@ -35,6 +64,7 @@ impl UserPrimHit {
             t: t,
             Ng: normal,
             uv: uv,
+            prim_id: None,
         }
     }
 
@@ -43,17 +73,99 @@ impl UserPrimHit {
             t: f32::MIN,
             Ng: Vector3::zero(),
             uv: Vector2::zero(),
+            prim_id: None,
         }
     }
+
+    pub fn with_prim_id(mut self, prim_id: u32) -> Self {
+        self.prim_id = Some(prim_id);
+        self
+    }
+}
+
+/// The largest packet Embree passes to user geometry callbacks
+const MAX_PACKET_SIZE: usize = 16;
+
+/// A packet of rays in structure of arrays layout, as passed to `UserPrimitive::intersect_n`
+pub struct RayPacket<'a> {
+    /// Lanes are active where this is non-zero
+    pub valid: &'a [i32],
+    pub origin_x: &'a [f32],
+    pub origin_y: &'a [f32],
+    pub origin_z: &'a [f32],
+    pub tnear: &'a [f32],
+    pub dir_x: &'a [f32],
+    pub dir_y: &'a [f32],
+    pub dir_z: &'a [f32],
+    pub time: &'a [f32],
+    pub tfar: &'a [f32],
+    mask: &'a [u32],
+    id: &'a [u32],
+    flags: &'a [u32],
 }
 
+impl<'a> RayPacket<'a> {
+    /// `rays` points to the first field of N rays in structure of arrays layout
+    unsafe fn from_raw(valid: *const i32, rays: *const f32, n: usize) -> RayPacket<'a> {
+        let field = |i: usize| slice::from_raw_parts(rays.add(i * n), n);
+        let int_field = |i: usize| slice::from_raw_parts(rays.add(i * n) as *const u32, n);
+        RayPacket {
+            valid: slice::from_raw_parts(valid, n),
+            origin_x: field(0),
+            origin_y: field(1),
+            origin_z: field(2),
+            tnear: field(SOA_TNEAR),
+            dir_x: field(4),
+            dir_y: field(5),
+            dir_z: field(6),
+            time: field(7),
+            tfar: field(SOA_TFAR),
+            mask: int_field(9),
+            id: int_field(10),
+            flags: int_field(11),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.valid.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.valid.is_empty()
+    }
+
+    pub fn is_valid(&self, i: usize) -> bool {
+        self.valid[i] != 0
+    }
+
+    pub fn get(&self, i: usize) -> Ray {
+        Ray {
+            origin: Point3::new(self.origin_x[i], self.origin_y[i], self.origin_z[i]),
+            tnear: self.tnear[i],
+            dir: Vector3::new(self.dir_x[i], self.dir_y[i], self.dir_z[i]),
+            time: self.time[i],
+            tfar: self.tfar[i],
+            mask: self.mask[i],
+            id: self.id[i],
+            flags: self.flags[i],
+        }
+    }
+}
+
+// Embree reads these with aligned SIMD loads
+#[repr(C, align(64))]
+struct ValidMask([i32; MAX_PACKET_SIZE]);
+
+#[repr(C, align(64))]
+struct SoaHits([f32; 8 * MAX_PACKET_SIZE]);
+
 pub struct UserGeometry<T> {
     handle: GeometryHandle,
     id: u32,
     pub prims: Vec<T>,
 }
 
-impl<T: UserPrimitive> UserGeometry<T> {
+impl<T: UserPrimitive + 'static> UserGeometry<T> {
     pub fn new(device: &Device, prims: Vec<T>) -> Self {
         let handle = GeometryHandle::new(device, GeometryType::User);
         UserGeometry {
@@ -64,7 +176,7 @@ impl<T: UserPrimitive> UserGeometry<T> {
     }
 }
 
-impl<T: UserPrimitive> Geometry for UserGeometry<T> {
+impl<T: UserPrimitive + 'static> Geometry for UserGeometry<T> {
     fn handle(&self) -> &GeometryHandle {
         &self.handle
     }
@@ -78,65 +190,331 @@ impl<T: UserPrimitive> Geometry for UserGeometry<T> {
     }
 
     fn bind_buffers(&mut self) {
-        assert!(self.prims.len() <= u32::MAX as usize);
+        unsafe { bind_user_primitives(self, self.prims.len()); }
+    }
+}
 
-        // Pass self as user_ptr
-        let user_ptr = self as *const UserGeometry<T> as *mut c_void;
-        unsafe {
-            let handle = self.handle.as_raw_ptr();
-            rtcSetGeometryUserPrimitiveCount(handle, self.prims.len() as u32);
-            rtcSetGeometryUserData(handle, user_ptr);
-            rtcSetGeometryBoundsFunction(handle, Some(bounds_func::<T>), user_ptr);
-            rtcSetGeometryIntersectFunction(handle, Some(intersect_func::<T>));
-            rtcSetGeometryOccludedFunction(handle, Some(occluded_func::<T>));
-        }
+impl<T: UserPrimitive + 'static> UserPrimitiveSource for UserGeometry<T> {
+    type Primitive<'a> = T;
+
+    fn geom_id(&self) -> u32 {
+        self.id
+    }
+
+    fn with_primitive<'a, R, F: FnOnce(&Self::Primitive<'a>) -> R>(&'a self, prim_id: u32, f: F) -> R {
+        f(&self.prims[prim_id as usize])
     }
 }
 
-unsafe extern "C" fn bounds_func<T: UserPrimitive>(args: *const RTCBoundsFunctionArguments) {
-    // If the userPtr = &[T] then do this to retrieve a primitive
-    // let data_ptr = (*args).geometryUserPtr as *const T;
-    // let prim_ptr = data_ptr.offset((*args).primID as isize);
+/// Geometry whose primitives are intersected in Rust, for primitives which can't be stored
+/// independently in a `UserGeometry`, e.g. when they share an index and vertex buffer
+pub(crate) trait UserPrimitiveSource: Geometry {
+    /// The primitive, which may borrow from the geometry
+    type Primitive<'a>: UserPrimitive where Self: 'a;
+
+    fn geom_id(&self) -> u32;
+
+    /// Calls `f` with the primitive, which may be built on the fly
+    fn with_primitive<'a, R, F: FnOnce(&Self::Primitive<'a>) -> R>(&'a self, prim_id: u32, f: F) -> R;
+}
+
+/// Registers the callbacks for `count` primitives, passing `geometry` as the user data
+pub(crate) unsafe fn bind_user_primitives<G: UserPrimitiveSource>(geometry: &mut G, count: usize) {
+    assert!(count <= u32::MAX as usize);
+
+    let user_ptr = geometry as *mut G as *mut c_void;
+    let handle = geometry.handle().as_raw_ptr();
+    rtcSetGeometryUserPrimitiveCount(handle, count as u32);
+    rtcSetGeometryUserData(handle, user_ptr);
+    rtcSetGeometryBoundsFunction(handle, Some(bounds_func::<G>), user_ptr);
+    rtcSetGeometryIntersectFunction(handle, Some(intersect_func::<G>));
+    rtcSetGeometryOccludedFunction(handle, Some(occluded_func::<G>));
+}
+
+unsafe extern "C" fn bounds_func<G: UserPrimitiveSource>(args: *const RTCBoundsFunctionArguments) {
+    let geometry: &G = ((*args).geometryUserPtr as *const G).as_ref().unwrap();
+    let bounds = geometry.with_primitive((*args).primID, |prim| prim.bounds());
+    ptr::write((*args).bounds_o as *mut Bounds, bounds);
+}
+
+unsafe extern "C" fn intersect_func<G: UserPrimitiveSource>(args: *const RTCIntersectFunctionNArguments) {
+    let geometry: &G = ((*args).geometryUserPtr as *const G).as_ref().unwrap();
+    geometry.with_primitive((*args).primID, |prim| intersect_primitive(args, geometry.geom_id(), prim));
+}
+
+unsafe extern "C" fn occluded_func<G: UserPrimitiveSource>(args: *const RTCOccludedFunctionNArguments) {
+    let geometry: &G = ((*args).geometryUserPtr as *const G).as_ref().unwrap();
+    let has_filter = geometry.handle().has_occluded_filter();
+    geometry.with_primitive((*args).primID, |prim| occluded_primitive(args, geometry.geom_id(), has_filter, prim));
+}
+
+unsafe fn intersect_primitive<T: UserPrimitive>(args: *const RTCIntersectFunctionNArguments, geom_id: u32, prim: &T) {
+    let n = (*args).N as usize;
+    debug_assert!(n <= MAX_PACKET_SIZE);
+    let rayhit = (*args).rayhit as *mut f32;
 
-    // let prim: &T = prim_ptr.as_ref().unwrap();
+    let mut prim_hits = [UserPrimHit::miss(); MAX_PACKET_SIZE];
+    {
+        let packet = RayPacket::from_raw((*args).valid, rayhit, n);
+        if n == 1 {
+            if packet.is_valid(0) {
+                prim_hits[0] = prim.intersect(&packet.get(0));
+            }
+        } else {
+            prim.intersect_n(&packet, &mut prim_hits[..n]);
+        }
+    }
+
+    // Potential hits are passed through the geometry and context filter functions
+    //  before they're committed to the ray
+    let valid = slice::from_raw_parts((*args).valid, n);
+    let mut filter_valid = ValidMask([0; MAX_PACKET_SIZE]);
+    let mut potential_hits = SoaHits([0.0; 8 * MAX_PACKET_SIZE]);
+    let mut old_tfar = [0.0; MAX_PACKET_SIZE];
+    let mut offered = [false; MAX_PACKET_SIZE];
+    let inst_id = (*(*args).context).instID[0];
+    for i in 0..n {
+        let prim_hit = &prim_hits[i];
+        let tnear = *soa_field::<f32>(rayhit, SOA_TNEAR, n, i);
+        let tfar = soa_field::<f32>(rayhit, SOA_TFAR, n, i);
+        // The UserPrimitive intersect function should only return distances within the ray's
+        //  range, but a hit outside of it would replace a closer one so treat it as a miss
+        if valid[i] == 0 || !(prim_hit.t > tnear && prim_hit.t < *tfar) {
+            continue;
+        }
+        let hit = Hit {
+            Ng: prim_hit.Ng,
+            uv: prim_hit.uv,
+            prim_id: prim_hit.prim_id.unwrap_or((*args).primID).into(),
+            geom_id: geom_id.into(),
+            inst_id: inst_id.into(),
+        };
+        write_soa_hit(potential_hits.0.as_mut_ptr(), n, i, &hit);
+        filter_valid.0[i] = -1;
+        offered[i] = true;
+        // Filter functions see the distance of the potential hit in tfar
+        old_tfar[i] = *tfar;
+        *tfar = prim_hit.t;
+    }
 
-    let geometry: &UserGeometry<T> = ((*args).geometryUserPtr as *const UserGeometry<T>).as_ref().unwrap();
+    if !offered[..n].iter().any(|&o| o) {
+        return;
+    }
 
-    let prim: &T = &geometry.prims[(*args).primID as usize];
+    let filter_args = RTCFilterFunctionNArguments {
+        valid: filter_valid.0.as_mut_ptr(),
+        geometryUserPtr: (*args).geometryUserPtr,
+        context: (*args).context,
+        ray: rayhit as *mut RTCRayN,
+        hit: potential_hits.0.as_mut_ptr() as *mut RTCHitN,
+        N: n as u32,
+    };
+    rtcFilterIntersection(args, &filter_args);
 
-    ptr::write((*args).bounds_o as *mut Bounds, prim.bounds());
+    let hits = rayhit.add(SOA_RAY_FIELDS * n);
+    for i in 0..n {
+        if !offered[i] {
+            continue;
+        }
+        if filter_valid.0[i] != 0 {
+            // Filters may have modified the hit so commit what they left
+            let hit = read_soa_hit(potential_hits.0.as_mut_ptr(), n, i);
+            write_soa_hit(hits, n, i, &hit);
+        } else {
+            // Rejected by a filter so restore the ray
+            *soa_field::<f32>(rayhit, SOA_TFAR, n, i) = old_tfar[i];
+        }
+    }
 }
 
-unsafe extern "C" fn intersect_func<T: UserPrimitive>(args: *const RTCIntersectFunctionNArguments) {
-    let geometry: &UserGeometry<T> = ((*args).geometryUserPtr as *const UserGeometry<T>).as_ref().unwrap();
+unsafe fn occluded_primitive<T: UserPrimitive>(args: *const RTCOccludedFunctionNArguments, geom_id: u32, has_occluded_filter: bool, prim: &T) {
+    let n = (*args).N as usize;
+    debug_assert!(n <= MAX_PACKET_SIZE);
+    let rays = (*args).ray as *mut f32;
+
+    let mut occluded = [false; MAX_PACKET_SIZE];
+    {
+        let packet = RayPacket::from_raw((*args).valid, rays, n);
+        if n == 1 {
+            occluded[0] = packet.is_valid(0) && prim.occluded(&packet.get(0));
+        } else {
+            prim.occluded_n(&packet, &mut occluded[..n]);
+        }
+    }
 
-    let prim: &T = &geometry.prims[(*args).primID as usize];
+    let valid = slice::from_raw_parts((*args).valid, n);
+    let mut filter_valid = ValidMask([0; MAX_PACKET_SIZE]);
+    for i in 0..n {
+        if valid[i] != 0 && occluded[i] {
+            filter_valid.0[i] = -1;
+        }
+    }
+    if filter_valid.0[..n].iter().all(|&v| v == 0) {
+        return;
+    }
 
-    debug_assert!((*args).N == 1);
-    if *(*args).valid == 0 { return; }
+    // Filter functions need the hit, which occlusion tests don't produce, so only
+    //  find it when there is a filter which could reject it
+    let has_filter = has_occluded_filter || (*(*args).context).filter.is_some();
+    if has_filter {
+        let mut prim_hits = [UserPrimHit::miss(); MAX_PACKET_SIZE];
+        {
+            let packet = RayPacket::from_raw(filter_valid.0.as_ptr(), rays, n);
+            if n == 1 {
+                prim_hits[0] = prim.intersect(&packet.get(0));
+            } else {
+                prim.intersect_n(&packet, &mut prim_hits[..n]);
+            }
+        }
 
-    let rayhit = (*args).rayhit as *mut RTCRayHit as *mut RayHit;
-    let rayhit = &mut *rayhit;
+        let mut potential_hits = SoaHits([0.0; 8 * MAX_PACKET_SIZE]);
+        let mut old_tfar = [0.0; MAX_PACKET_SIZE];
+        let inst_id = (*(*args).context).instID[0];
+        for i in 0..n {
+            if filter_valid.0[i] == 0 {
+                continue;
+            }
+            let tfar = soa_field::<f32>(rays, SOA_TFAR, n, i);
+            old_tfar[i] = *tfar;
+            let prim_hit = &prim_hits[i];
+            let hit = Hit {
+                Ng: prim_hit.Ng,
+                uv: prim_hit.uv,
+                prim_id: prim_hit.prim_id.unwrap_or((*args).primID).into(),
+                geom_id: geom_id.into(),
+                inst_id: inst_id.into(),
+            };
+            write_soa_hit(potential_hits.0.as_mut_ptr(), n, i, &hit);
+            // If occluded disagrees with intersect there's no distance to report so leave tfar
+            if prim_hit.t >= *soa_field::<f32>(rays, SOA_TNEAR, n, i) {
+                *tfar = prim_hit.t;
+            }
+        }
 
-    let ray = &mut rayhit.ray;
-    let hit = &mut rayhit.hit;
+        let filter_args = RTCFilterFunctionNArguments {
+            valid: filter_valid.0.as_mut_ptr(),
+            geometryUserPtr: (*args).geometryUserPtr,
+            context: (*args).context,
+            ray: rays as *mut RTCRayN,
+            hit: potential_hits.0.as_mut_ptr() as *mut RTCHitN,
+            N: n as u32,
+        };
+        rtcFilterOcclusion(args, &filter_args);
 
-    // TODO: need to expose a way to call rtcFilterIntersection (possibly by passing a closure in)
-    let prim_hit = prim.intersect(ray);
+        for i in 0..n {
+            if valid[i] != 0 && occluded[i] && filter_valid.0[i] == 0 {
+                // Rejected by a filter so restore the ray
+                *soa_field::<f32>(rays, SOA_TFAR, n, i) = old_tfar[i];
+            }
+        }
+    }
 
-    if prim_hit.t >= ray.tnear {
-        // The UserPrimitive intersect function should make sure the below invariant holds
-        //  but check it anyways. This could be turned into a runtime check instead of an assert
-        debug_assert!(ray.in_range(prim_hit.t), "Intersect function returning distance out of ray bounds");
-        ray.tfar = prim_hit.t;
-        hit.Ng = prim_hit.Ng;
-        hit.uv = prim_hit.uv;
-        hit.prim_id = (*args).primID.into();
-        hit.geom_id = geometry.id.into();
-        hit.inst_id = (*(*args).context).instID[0].into();
+    for i in 0..n {
+        if filter_valid.0[i] != 0 {
+            // Embree signals an occluded ray by setting tfar to -inf
+            *soa_field::<f32>(rays, SOA_TFAR, n, i) = f32::NEG_INFINITY;
+        }
     }
 }
 
-unsafe extern "C" fn occluded_func<T: UserPrimitive>(_args: *const RTCOccludedFunctionNArguments) {
-    unimplemented!();
-}
\ No newline at end of file
+/// The square [-1, 1] x [-1, 1] at height `z`, facing up
+#[cfg(test)]
+struct TestSquare {
+    z: f32,
+}
+
+#[cfg(test)]
+impl UserPrimitive for TestSquare {
+    fn intersect(&self, ray: &Ray) -> UserPrimHit {
+        let t = (self.z - ray.origin.z) / ray.dir.z;
+        let p = ray.point_at_dist(t);
+        if ray.in_range(t) && p.x.abs() <= 1.0 && p.y.abs() <= 1.0 {
+            UserPrimHit::new(t, Vector3::unit_z(), Vector2::new(p.x, p.y))
+        } else {
+            UserPrimHit::miss()
+        }
+    }
+
+    fn bounds(&self) -> Bounds {
+        Bounds::new(Point3::new(-1.0, -1.0, self.z - 0.001), Point3::new(1.0, 1.0, self.z + 0.001))
+    }
+}
+
+#[test]
+fn test_user_geometry_occluded() {
+    use scene::SceneBuilder;
+
+    let device = Device::new();
+    let mut builder = SceneBuilder::new(&device);
+    builder.attach(UserGeometry::new(&device, vec![TestSquare { z: 0.0 }]));
+    let scene = builder.build();
+
+    let down = -Vector3::unit_z();
+    let hit = Ray::new(Point3::new(0.5, 0.5, 2.0), down, 0.0, f32::INFINITY);
+    let beside = Ray::new(Point3::new(1.5, 0.5, 2.0), down, 0.0, f32::INFINITY);
+    let short = Ray::new(Point3::new(0.5, 0.5, 2.0), down, 0.0, 1.0);
+    assert!(scene.occluded(&mut hit.clone()));
+    assert!(!scene.occluded(&mut beside.clone()));
+    assert!(!scene.occluded(&mut short.clone()));
+
+    // The invalid lane would be occluded
+    let mut packet = Ray4::from_rays(&[hit, beside, hit, short]);
+    assert_eq!(scene.occluded4(&[true, true, false, true], &mut packet), [true, false, false, false]);
+}
+
+#[test]
+fn test_user_geometry_filters() {
+    use scene::SceneBuilder;
+
+    let device = Device::new();
+    let mut geometry = UserGeometry::new(&device, vec![TestSquare { z: 0.0 }, TestSquare { z: -1.0 }]);
+    // Both filters see through the upper square
+    geometry.handle_mut().set_intersect_filter(|_, hit| hit.prim_id.id != 0);
+    geometry.handle_mut().set_occluded_filter(|_, hit| hit.prim_id.id != 0);
+    let mut builder = SceneBuilder::new(&device);
+    builder.attach(geometry);
+    let scene = builder.build();
+
+    let ray = Ray::new(Point3::new(0.5, 0.5, 2.0), -Vector3::unit_z(), 0.0, f32::INFINITY);
+    let mut rayhit = RayHit { ray, hit: Hit::empty() };
+    scene.intersect(&mut rayhit);
+    assert_eq!(rayhit.hit.prim_id.id, 1);
+    assert_eq!(rayhit.ray.tfar, 3.0);
+
+    assert!(scene.occluded(&mut ray.clone()));
+    // Only reaches the upper square
+    assert!(!scene.occluded(&mut Ray::new(ray.origin, ray.dir, 0.0, 2.5)));
+}
+
+#[test]
+fn test_user_geometry_out_of_range_hits() {
+    use scene::SceneBuilder;
+
+    /// Reports the plane z = 0 without checking the ray's range
+    struct Unbounded;
+
+    impl UserPrimitive for Unbounded {
+        fn intersect(&self, ray: &Ray) -> UserPrimHit {
+            UserPrimHit::new(-ray.origin.z / ray.dir.z, Vector3::unit_z(), Vector2::zero())
+        }
+
+        fn bounds(&self) -> Bounds {
+            Bounds::new(Point3::new(-1.0, -1.0, -0.001), Point3::new(1.0, 1.0, 0.001))
+        }
+    }
+
+    let device = Device::new();
+    let mut builder = SceneBuilder::new(&device);
+    builder.attach(UserGeometry::new(&device, vec![Unbounded]));
+    builder.attach(UserGeometry::new(&device, vec![TestSquare { z: 0.5 }]));
+    let scene = builder.build();
+
+    let trace = |tfar: f32| {
+        let mut rayhit = RayHit { ray: Ray::new(Point3::new(0.0, 0.0, 2.0), -Vector3::unit_z(), 0.0, tfar), hit: Hit::empty() };
+        scene.intersect(&mut rayhit);
+        rayhit
+    };
+    // The closer square is kept whichever primitive Embree tries first
+    assert_eq!(trace(f32::INFINITY).ray.tfar, 1.5);
+    assert!(!trace(1.0).hit.is_hit());
+}
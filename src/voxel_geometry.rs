@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::f32;
+
+use cgmath::*;
+
+use common::*;
+use csg_geometry::box_span;
+use device::Device;
+use ray::*;
+use user_geometry::*;
+
+/// Voxels along each side of a brick
+pub const VOXEL_BRICK_SIZE: i32 = 8;
+const VOXELS_PER_BRICK: usize = (VOXEL_BRICK_SIZE * VOXEL_BRICK_SIZE * VOXEL_BRICK_SIZE) as usize;
+
+/// A voxel grid split into bricks, which are the primitives placed in the BVH.
+/// Only bricks containing occupied voxels are stored
+pub type VoxelGeometry<V> = UserGeometry<VoxelBrick<V>>;
+
+/// A cube of `VOXEL_BRICK_SIZE`^3 voxels traced with a 3D-DDA. Hits report the face's
+/// normal and the position on the face of the voxel as uv
+pub struct VoxelBrick<V> {
+    /// Position of voxel (0, 0, 0) of the whole grid
+    pub grid_origin: Point3<f32>,
+    pub voxel_size: f32,
+    /// Grid coordinate of this brick's first voxel
+    pub first_voxel: [i32; 3],
+    voxels: Vec<Option<V>>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct VoxelHit<V> {
+    /// Grid coordinate of the voxel hit
+    pub voxel: [i32; 3],
+    pub normal: Vector3<f32>,
+    pub value: V,
+}
+
+fn voxel_index(local: [i32; 3]) -> usize {
+    (local[0] + VOXEL_BRICK_SIZE * (local[1] + VOXEL_BRICK_SIZE * local[2])) as usize
+}
+
+fn brick_coord(voxel: [i32; 3]) -> [i32; 3] {
+    [voxel[0].div_euclid(VOXEL_BRICK_SIZE), voxel[1].div_euclid(VOXEL_BRICK_SIZE), voxel[2].div_euclid(VOXEL_BRICK_SIZE)]
+}
+
+impl<V: Copy> VoxelBrick<V> {
+    pub fn new(grid_origin: Point3<f32>, voxel_size: f32, first_voxel: [i32; 3]) -> Self {
+        VoxelBrick {
+            grid_origin,
+            voxel_size,
+            first_voxel,
+            voxels: vec![None; VOXELS_PER_BRICK],
+        }
+    }
+
+    fn local(&self, voxel: [i32; 3]) -> Option<[i32; 3]> {
+        let local = [voxel[0] - self.first_voxel[0], voxel[1] - self.first_voxel[1], voxel[2] - self.first_voxel[2]];
+        if local.iter().all(|c| (0..VOXEL_BRICK_SIZE).contains(c)) { Some(local) } else { None }
+    }
+
+    /// The value of a voxel by its grid coordinate, `None` when empty or outside the brick
+    pub fn get(&self, voxel: [i32; 3]) -> Option<V> {
+        self.local(voxel).and_then(|local| self.voxels[voxel_index(local)])
+    }
+
+    pub fn set(&mut self, voxel: [i32; 3], value: Option<V>) {
+        let local = self.local(voxel).expect("Voxel outside of brick");
+        self.voxels[voxel_index(local)] = value;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.voxels.iter().all(|v| v.is_none())
+    }
+
+    fn lower(&self) -> Point3<f32> {
+        let v = self.first_voxel;
+        self.grid_origin + Vector3::new(v[0] as f32, v[1] as f32, v[2] as f32) * self.voxel_size
+    }
+
+    /// The voxel behind a point on one of its faces with the face's outward `normal`
+    pub fn voxel_at(&self, p: Point3<f32>, normal: Vector3<f32>) -> Option<VoxelHit<V>> {
+        let grid = (p - self.grid_origin) / self.voxel_size - normal * 0.5;
+        let voxel = [grid.x.floor() as i32, grid.y.floor() as i32, grid.z.floor() as i32];
+        self.get(voxel).map(|value| VoxelHit { voxel, normal, value })
+    }
+}
+
+impl<V: Copy + Send + Sync + 'static> UserPrimitive for VoxelBrick<V> {
+    fn intersect(&self, ray: &Ray) -> UserPrimHit {
+        if ray.dir.is_zero() {
+            return UserPrimHit::miss();
+        }
+        let lower = self.lower();
+        let extent = VOXEL_BRICK_SIZE as f32 * self.voxel_size;
+        let span = match box_span(lower, lower + Vector3::new(extent, extent, extent), ray) {
+            Some(span) => span,
+            None => return UserPrimHit::miss(),
+        };
+        let t_exit = span.exit.t.min(ray.tfar);
+        if t_exit <= ray.tnear {
+            return UserPrimHit::miss();
+        }
+
+        let face_uv = |t: f32, axis: usize| {
+            let p = (ray.point_at_dist(t) - lower) / self.voxel_size;
+            let (a, b) = (p[(axis + 1) % 3], p[(axis + 2) % 3]);
+            Vector2::new(a - a.floor(), b - b.floor())
+        };
+
+        // Rays entering through the side of the brick can hit a voxel on its boundary.
+        //  Rays starting within the brick only hit the faces of voxels they move into
+        let mut t = span.enter.t.max(ray.tnear);
+        let start = (ray.point_at_dist(t) - lower) / self.voxel_size;
+        let mut cell = [0; 3];
+        for axis in 0..3 {
+            cell[axis] = (start[axis].floor() as i32).clamp(0, VOXEL_BRICK_SIZE - 1);
+        }
+        if ray.in_range(span.enter.t) && self.voxels[voxel_index(cell)].is_some() {
+            let axis = (0..3).find(|&a| span.enter.normal[a] != 0.0).unwrap_or(0);
+            return UserPrimHit::new(t, span.enter.normal, face_uv(t, axis));
+        }
+
+        let mut step = [0; 3];
+        let mut t_max = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+        for axis in 0..3 {
+            let d = ray.dir[axis];
+            if d == 0.0 {
+                continue;
+            }
+            step[axis] = if d > 0.0 { 1 } else { -1 };
+            let next = cell[axis] + if d > 0.0 { 1 } else { 0 };
+            let boundary = lower[axis] + next as f32 * self.voxel_size;
+            t_max[axis] = (boundary - ray.origin[axis]) / d;
+            t_delta[axis] = self.voxel_size / d.abs();
+        }
+
+        // Every step moves into a neighbouring cell, so no ray crosses more cells than this
+        for _ in 0..3 * VOXEL_BRICK_SIZE {
+            let axis = if t_max[0] < t_max[1] {
+                if t_max[0] < t_max[2] { 0 } else { 2 }
+            } else if t_max[1] < t_max[2] { 1 } else { 2 };
+            t = t_max[axis];
+            if t > t_exit {
+                return UserPrimHit::miss();
+            }
+            cell[axis] += step[axis];
+            if cell[axis] < 0 || cell[axis] >= VOXEL_BRICK_SIZE {
+                return UserPrimHit::miss();
+            }
+            t_max[axis] += t_delta[axis];
+            if self.voxels[voxel_index(cell)].is_some() && ray.in_range(t) {
+                let mut normal = Vector3::zero();
+                normal[axis] = -step[axis] as f32;
+                return UserPrimHit::new(t, normal, face_uv(t, axis));
+            }
+        }
+        UserPrimHit::miss()
+    }
+
+    fn bounds(&self) -> Bounds {
+        let lower = self.lower();
+        let extent = VOXEL_BRICK_SIZE as f32 * self.voxel_size;
+        Bounds::new(lower, lower + Vector3::new(extent, extent, extent))
+    }
+}
+
+/// Splits voxels given by grid coordinate into bricks, skipping empty ones
+pub fn voxel_bricks<V, I>(grid_origin: Point3<f32>, voxel_size: f32, voxels: I) -> Vec<VoxelBrick<V>>
+    where V: Copy, I: IntoIterator<Item = ([i32; 3], V)> {
+    let mut bricks = Vec::new();
+    let mut lookup: HashMap<[i32; 3], usize> = HashMap::new();
+    for (voxel, value) in voxels {
+        let coord = brick_coord(voxel);
+        let index = *lookup.entry(coord).or_insert_with(|| {
+            let first = [coord[0] * VOXEL_BRICK_SIZE, coord[1] * VOXEL_BRICK_SIZE, coord[2] * VOXEL_BRICK_SIZE];
+            bricks.push(VoxelBrick::new(grid_origin, voxel_size, first));
+            bricks.len() - 1
+        });
+        bricks[index].set(voxel, Some(value));
+    }
+    bricks
+}
+
+impl<V: Copy + Send + Sync + 'static> UserGeometry<VoxelBrick<V>> {
+    /// `values` is a dense `dims[0] * dims[1] * dims[2]` grid in x then y then z order
+    pub fn from_dense(device: &Device, grid_origin: Point3<f32>, voxel_size: f32, dims: [usize; 3], values: &[Option<V>]) -> Self {
+        assert_eq!(values.len(), dims[0] * dims[1] * dims[2], "Voxel values don't match dimensions");
+        let voxels = values.iter().enumerate().filter_map(|(i, value)| {
+            let coord = [(i % dims[0]) as i32, (i / dims[0] % dims[1]) as i32, (i / (dims[0] * dims[1])) as i32];
+            value.map(|v| (coord, v))
+        });
+        UserGeometry::new(device, voxel_bricks(grid_origin, voxel_size, voxels))
+    }
+
+    pub fn from_sparse<I>(device: &Device, grid_origin: Point3<f32>, voxel_size: f32, voxels: I) -> Self
+        where I: IntoIterator<Item = ([i32; 3], V)> {
+        UserGeometry::new(device, voxel_bricks(grid_origin, voxel_size, voxels))
+    }
+
+    /// The voxel behind a hit on this geometry
+    pub fn voxel_hit(&self, rayhit: &RayHit) -> Option<VoxelHit<V>> {
+        let brick = self.prims.get(rayhit.hit.prim_id.id as usize)?;
+        brick.voxel_at(rayhit.ray.point_at_dist(rayhit.ray.tfar), rayhit.hit.Ng)
+    }
+}
+
+#[test]
+fn test_voxel_brick_dda() {
+    let origin = Point3::new(-1.0, 0.0, 0.0);
+    let bricks = voxel_bricks(origin, 0.5, vec![([1, 2, 3], 7u8), ([5, 2, 3], 9u8), ([9, 0, 0], 1u8)]);
+    assert_eq!(bricks.len(), 2);
+    let brick = &bricks[0];
+
+    // Along +x through the centre of row (y = 2, z = 3), starting inside the brick
+    let ray = Ray::new(Point3::new(-0.75, 1.25, 1.75), Vector3::unit_x(), 0.0, f32::INFINITY);
+    let hit = brick.intersect(&ray);
+    assert_eq!(hit.t, 0.25);
+    assert_eq!(hit.Ng, -Vector3::unit_x());
+    let voxel = brick.voxel_at(ray.point_at_dist(hit.t), hit.Ng).unwrap();
+    assert_eq!((voxel.voxel, voxel.value), ([1, 2, 3], 7));
+
+    // Continuing past the first voxel finds the second
+    let hit = brick.intersect(&Ray::new(Point3::new(0.5, 1.25, 1.75), Vector3::unit_x(), 0.0, f32::INFINITY));
+    assert_eq!(hit.t, 1.0);
+
+    // Entering the brick through its side
+    let ray = Ray::new(Point3::new(-0.25, 1.25, -2.0), Vector3::unit_z(), 0.0, f32::INFINITY);
+    let hit = brick.intersect(&ray);
+    assert_eq!((hit.t, hit.Ng), (3.5, -Vector3::unit_z()));
+    assert!(brick.intersect(&Ray::new(ray.origin, ray.dir, 0.0, 3.0)).t < 0.0);
+
+    // A zero direction never leaves its cell
+    assert!(brick.intersect(&Ray::new(Point3::new(-0.25, 1.25, 1.25), Vector3::zero(), 0.0, f32::INFINITY)).t < 0.0);
+
+    // A voxel on the side of the brick the ray enters through, beyond the end of the ray
+    let bricks = voxel_bricks(Point3::new(0.0, 0.0, 0.0), 1.0, vec![([0, 0, 0], 1u8)]);
+    let ray = Ray::new(Point3::new(-10.0, 0.5, 0.5), Vector3::unit_x(), 0.0, 5.0);
+    assert!(bricks[0].intersect(&ray).t < 0.0);
+    assert_eq!(bricks[0].intersect(&Ray::new(ray.origin, ray.dir, 0.0, f32::INFINITY)).t, 10.0);
+}